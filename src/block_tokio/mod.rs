@@ -3,11 +3,15 @@
 // -----------------
 
 use log::info;
-use neutron_fs::driver::block::{make_block, Block, BlockDriver};
+use neutron_fs::driver::block::{
+    make_block, read_block, write_block, Block, BlockDriver, Completion, Completions, IoError,
+    RequestId,
+};
+use std::collections::HashMap;
 use tokio::{
     sync::{
-        mpsc::{self},
-        oneshot,
+        mpsc::{self, error::TrySendError},
+        oneshot::{self, error::TryRecvError},
     },
     task::JoinHandle,
 };
@@ -22,12 +26,12 @@ type Responder<T> = oneshot::Sender<T>;
 pub enum DiskRequest {
     Read {
         block_id: u64,
-        resp: Responder<Block>,
+        resp: Responder<Result<Block, IoError>>,
     },
     Write {
         block_id: u64,
         block: Block,
-        resp: Responder<()>,
+        resp: Responder<Result<(), IoError>>,
     },
 }
 
@@ -35,9 +39,7 @@ pub enum DiskRequest {
 // TOKIO DRIVER
 // -----------------
 
-// store tx, rx??
-
-/// Expose to the actual higher driver
+/// Owns the partition and serves DiskRequests sent through handles
 pub struct BlockDriverTokio {
     vpartition: VPartition,
     tx_channel: mpsc::Sender<DiskRequest>,
@@ -57,110 +59,535 @@ impl BlockDriverTokio {
         }
     }
 
-    pub fn init_manager(mut self) -> JoinHandle<BlockDriverTokio> {
+    /// A partition behind a channel of `capacity` requests
+    pub fn with_capacity(vpartition: VPartition, capacity: usize) -> Self {
+        let (tx_channel, rx_channel) = mpsc::channel(capacity);
+        Self::new(vpartition, tx_channel, rx_channel)
+    }
+
+    /// Something the fs can use as a BlockDriver. Get these before starting the manager
+    pub fn handle(&self) -> BlockDriverTokioHandle {
+        BlockDriverTokioHandle::new(self.tx_channel.clone())
+    }
+
+    /// Serve requests until every handle is dropped, then give the partition back
+    pub fn init_manager(self) -> JoinHandle<VPartition> {
+        let Self {
+            mut vpartition,
+            tx_channel,
+            mut rx_channel,
+        } = self;
+        // only handles should keep the channel open
+        drop(tx_channel);
+
         let manager = tokio::spawn(async move {
-            // Start receiving and handling requests. Maybe give it away after? IDK
-            while let Some(cmd) = self.rx_channel.recv().await {
+            while let Some(cmd) = rx_channel.recv().await {
                 match cmd {
                     DiskRequest::Read { block_id, resp } => {
-                        // handle the read request by searching the block and wrapping it in a DiskResponse
-                        let block = self.vpartition.get_block(block_id);
-                        let _ = resp.send(block);
+                        let _ = resp.send(read_block(&mut vpartition, block_id));
                     }
                     DiskRequest::Write {
                         block_id,
                         block,
                         resp,
                     } => {
-                        let block = self.vpartition.write_block(block_id, block);
-                        let _ = resp.send(block);
+                        let _ = resp.send(write_block(&mut vpartition, block_id, block));
                     }
                 }
             }
-            self
+            info!("All handles dropped, stopping the disk manager");
+
+            vpartition
         });
 
         manager
     }
 }
 
-// USE ARC! core::arc??
-// uhhh
+enum PendingRequest {
+    Read(oneshot::Receiver<Result<Block, IoError>>),
+    Write(oneshot::Receiver<Result<(), IoError>>),
+}
+
+/// Sends requests to the manager task. Each clone has its own request ids
+///
+/// `wait` blocks the thread, so run the fs on a blocking thread (spawn_blocking) and not on the runtime itself.
+/// Async code can use `completion` or `read`/`write` instead
+pub struct BlockDriverTokioHandle {
+    tx_channel: mpsc::Sender<DiskRequest>,
+    next_id: RequestId,
+    pending: HashMap<RequestId, PendingRequest>,
+}
+
+impl BlockDriverTokioHandle {
+    pub fn new(tx_channel: mpsc::Sender<DiskRequest>) -> Self {
+        Self {
+            tx_channel,
+            next_id: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn send(
+        &mut self,
+        request: DiskRequest,
+        pending: PendingRequest,
+    ) -> Result<RequestId, IoError> {
+        self.tx_channel.try_send(request).map_err(|e| match e {
+            TrySendError::Full(_) => IoError::QueueFull,
+            TrySendError::Closed(_) => IoError::Device,
+        })?;
+
+        self.next_id += 1;
+        self.pending.insert(self.next_id, pending);
+        Ok(self.next_id)
+    }
+
+    /// Read a block, waiting for room in the channel instead of failing with QueueFull
+    pub async fn read(&self, block_id: u64) -> Result<Block, IoError> {
+        let (resp, rx) = oneshot::channel();
+        self.tx_channel
+            .send(DiskRequest::Read { block_id, resp })
+            .await
+            .map_err(|_| IoError::Device)?;
+
+        rx.await.map_err(|_| IoError::Device)?
+    }
+
+    /// Write a block, waiting for room in the channel instead of failing with QueueFull
+    pub async fn write(&self, block_id: u64, block: Block) -> Result<(), IoError> {
+        let (resp, rx) = oneshot::channel();
+        self.tx_channel
+            .send(DiskRequest::Write {
+                block_id,
+                block,
+                resp,
+            })
+            .await
+            .map_err(|_| IoError::Device)?;
+
+        rx.await.map_err(|_| IoError::Device)?
+    }
+}
 
-// impl BlockDriver for BlockDriverTokio {
-//     fn push_read_request(&mut self, buf: &mut [u8], cluster_number: u64) {
-//         let req_read = tokio::spawn(async move {
-//             info!("Sending read request on separate thread...");
+impl Clone for BlockDriverTokioHandle {
+    fn clone(&self) -> Self {
+        Self::new(self.tx_channel.clone())
+    }
+}
 
-//             let (resp_tx, resp_rx) = oneshot::channel();
+impl BlockDriver for BlockDriverTokioHandle {
+    fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+        let (resp, rx) = oneshot::channel();
+        self.send(
+            DiskRequest::Read {
+                block_id: cluster_number,
+                resp,
+            },
+            PendingRequest::Read(rx),
+        )
+    }
 
-//             // send a disk request to read
-//             self.tx_channel
-//                 .send(DiskRequest::Read {
-//                     block_id: 0,
-//                     resp: resp_tx,
-//                 })
-//                 .await
-//                 .unwrap();
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<RequestId, IoError> {
+        let (resp, rx) = oneshot::channel();
+        self.send(
+            DiskRequest::Write {
+                block_id: cluster_number,
+                block,
+                resp,
+            },
+            PendingRequest::Write(rx),
+        )
+    }
 
-//             let res = resp_rx.await;
+    fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+        // the manager dropping a responder means it went away mid request
+        let completion = match self.pending.get_mut(&id)? {
+            PendingRequest::Read(rx) => match rx.try_recv() {
+                Ok(res) => Completion::Read(res.map(Box::new)),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Closed) => Completion::Read(Err(IoError::Device)),
+            },
+            PendingRequest::Write(rx) => match rx.try_recv() {
+                Ok(res) => Completion::Write(res),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Closed) => Completion::Write(Err(IoError::Device)),
+            },
+        };
 
-//             info!("Read request complete!\nResult = {:?}", res.unwrap());
-//         });
+        self.pending.remove(&id);
+        Some(completion)
+    }
 
-//     }
+    fn is_pending(&self, id: RequestId) -> bool {
+        self.pending.contains_key(&id)
+    }
 
-//     fn push_write_request(mut self, cluster_number: u64, block: Block) {
-//         todo!()
-//     }
-// }
+    fn wait(&mut self, id: RequestId) -> Completion {
+        // park the thread on the oneshot instead of spinning
+        match self.pending.remove(&id) {
+            Some(PendingRequest::Read(rx)) => {
+                let res = rx.blocking_recv().unwrap_or(Err(IoError::Device));
+                Completion::Read(res.map(Box::new))
+            }
+            Some(PendingRequest::Write(rx)) => {
+                Completion::Write(rx.blocking_recv().unwrap_or(Err(IoError::Device)))
+            }
+            // unknown, or already handed out by poll_completion
+            None => Completion::Read(Err(IoError::WrongCompletion(id))),
+        }
+    }
+}
 
 // -----------------
 // VIRTUAL PARTITION
 // -----------------
 
-#[derive(Debug)]
+/// Blocks in memory. Can be made to crash after a number of writes, to test what a power cut leaves on disk
+#[derive(Debug, Clone)]
 pub struct VPartition {
     n_blocks: u64,
     blocks: Vec<Block>,
+    completions: Completions,
+    // writes that still land before the crash, None for no crash
+    writes_left: Option<u64>,
+    n_writes: u64,
 }
 
 impl VPartition {
     pub fn new(n_blocks: u64, blocks: Vec<Block>) -> Self {
-        Self { n_blocks, blocks }
+        Self {
+            n_blocks,
+            blocks,
+            completions: Completions::new(),
+            writes_left: None,
+            n_writes: 0,
+        }
     }
 
     pub fn new_empty(n_blocks: u64) -> Self {
-        let mut blocks = Vec::<Block>::new();
-        blocks.reserve(n_blocks as usize);
+        let blocks = Vec::<Block>::with_capacity(n_blocks as usize);
 
         Self {
             n_blocks,
-            blocks: blocks,
+            blocks,
+            completions: Completions::new(),
+            writes_left: None,
+            n_writes: 0,
         }
     }
 
     pub fn new_zeroed(n_blocks: u64) -> Self {
-        let mut blocks_zeroed = Vec::<Block>::new();
-        blocks_zeroed.iter_mut().for_each(|b| {
-            *b = make_block();
-        });
+        let blocks_zeroed = vec![make_block(); n_blocks as usize];
 
         Self {
             n_blocks,
             blocks: blocks_zeroed,
+            completions: Completions::new(),
+            writes_left: None,
+            n_writes: 0,
         }
     }
 
+    /// Crash after the next `n` writes. Every write after that fails with IoError::Device and never lands
+    pub fn crash_after(&mut self, n: u64) {
+        self.writes_left = Some(n);
+    }
+
+    /// Power back on, writes land again
+    pub fn recover(&mut self) {
+        self.writes_left = None;
+    }
+
+    /// Writes that landed so far
+    pub fn n_writes(&self) -> u64 {
+        self.n_writes
+    }
+
     pub fn max_size(&self) -> u64 {
         self.n_blocks
     }
 
     pub fn get_block(&mut self, block_id: u64) -> Block {
-        self.blocks.get(block_id as usize).unwrap().clone()
+        *self.blocks.get(block_id as usize).unwrap()
     }
 
     pub fn write_block(&mut self, block_id: u64, block: Block) {
         self.blocks[block_id as usize] = block;
     }
 }
+
+impl BlockDriver for VPartition {
+    fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+        if cluster_number >= self.blocks.len() as u64 {
+            let res = Err(IoError::OutOfRange(cluster_number));
+            return Ok(self.completions.complete_now(Completion::Read(res)));
+        }
+
+        let block = self.get_block(cluster_number);
+        Ok(self
+            .completions
+            .complete_now(Completion::Read(Ok(Box::new(block)))))
+    }
+
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<RequestId, IoError> {
+        if cluster_number >= self.blocks.len() as u64 {
+            let res = Err(IoError::OutOfRange(cluster_number));
+            return Ok(self.completions.complete_now(Completion::Write(res)));
+        }
+
+        match &mut self.writes_left {
+            Some(0) => {
+                return Ok(self
+                    .completions
+                    .complete_now(Completion::Write(Err(IoError::Device))))
+            }
+            Some(n) => *n -= 1,
+            None => {}
+        }
+
+        self.write_block(cluster_number, block);
+        self.n_writes += 1;
+        Ok(self.completions.complete_now(Completion::Write(Ok(()))))
+    }
+
+    fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+        self.completions.take(id)
+    }
+
+    fn is_pending(&self, id: RequestId) -> bool {
+        self.completions.is_pending(id)
+    }
+
+    fn device_clusters(&self) -> Option<u64> {
+        Some(self.blocks.len() as u64)
+    }
+}
+
+// -----------------
+// TESTS
+// -----------------
+
+#[test]
+fn test_mkfs_vpartition() {
+    use neutron_fs::driver::neutronfs::{mkfs, NeFS};
+
+    let mut vpartition = VPartition::new_zeroed(128);
+    let n_clusters = vpartition.max_size();
+    let superblock = mkfs(&mut vpartition, n_clusters, "vpartition").unwrap();
+    assert_eq!(superblock.n_sectors_total(), 128);
+
+    // superblock went to cluster 0
+    assert_ne!(vpartition.get_block(0), make_block());
+
+    let fs = NeFS::mount(vpartition).unwrap();
+    assert_eq!(fs.superblock().fs_uuid(), superblock.fs_uuid());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tokio_handle_requests() {
+    let driver = BlockDriverTokio::with_capacity(VPartition::new_zeroed(16), 8);
+    let handle = driver.handle();
+    let manager = driver.init_manager();
+
+    // async side
+    handle.write(3, [7; 4096]).await.unwrap();
+    assert_eq!(handle.read(3).await.unwrap(), [7; 4096]);
+    assert_eq!(handle.read(16).await, Err(IoError::OutOfRange(16)));
+
+    // polling side, through the trait
+    let mut polled = handle.clone();
+    let id = polled.push_read_request(3).unwrap();
+    assert_eq!(polled.completion(id).await.into_read(id), Ok([7; 4096]));
+
+    // each completion only once, waiting again is an error and not a panic
+    let waited = tokio::task::spawn_blocking(move || {
+        let id = polled.push_write_request(4, [8; 4096]).unwrap();
+        assert_eq!(polled.wait(id), Completion::Write(Ok(())));
+        assert_eq!(
+            polled.wait(id),
+            Completion::Read(Err(IoError::WrongCompletion(id)))
+        );
+        assert_eq!(
+            polled.wait(1000),
+            Completion::Read(Err(IoError::WrongCompletion(1000)))
+        );
+        polled
+    });
+    let polled = waited.await.unwrap();
+
+    drop(polled);
+    drop(handle);
+    let vpartition = manager.await.unwrap();
+    assert_eq!(vpartition.blocks[3], [7; 4096]);
+    assert_eq!(vpartition.blocks[4], [8; 4096]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mkfs_tokio_handle() {
+    use neutron_fs::driver::neutronfs::{mkfs, NeFS};
+
+    let driver = BlockDriverTokio::with_capacity(VPartition::new_zeroed(128), 64);
+    let mut handle = driver.handle();
+    let manager = driver.init_manager();
+
+    // the fs blocks on its requests, keep it off the runtime threads
+    let uuid = tokio::task::spawn_blocking(move || {
+        let superblock = mkfs(&mut handle, 128, "tokio").unwrap();
+        let fs = NeFS::mount(handle).unwrap();
+        assert_eq!(fs.superblock().fs_uuid(), superblock.fs_uuid());
+
+        superblock.fs_uuid()
+    })
+    .await
+    .unwrap();
+
+    // fs dropped its handle, manager stops
+    let vpartition = manager.await.unwrap();
+    let fs = NeFS::mount(vpartition).unwrap();
+    assert_eq!(fs.superblock().fs_uuid(), uuid);
+}
+
+#[test]
+fn test_journal_crash_every_write() {
+    use neutron_fs::driver::neutronfs::{
+        mkfs_with, FileType, FsError, MkfsOptions, NeFS, OpenFlags, PAGE_SIZE,
+    };
+
+    // what a commit leaves: each name in / with its contents, none for a directory
+    type State = Vec<(String, Option<Vec<u8>>)>;
+
+    fn state<D: BlockDriver>(fs: &mut NeFS<D>) -> State {
+        let mut names = Vec::new();
+        for entry in fs.readdir("/").unwrap() {
+            if entry.name() != "." && entry.name() != ".." {
+                let path = format!("/{}", entry.name());
+                let record = fs.stat(&path).unwrap().record;
+                let contents = (record.file_type == FileType::File).then(|| {
+                    let mut buf = vec![0; record.size as usize];
+                    let n = fs
+                        .open(&path, OpenFlags::NONE)
+                        .unwrap()
+                        .read_bytes(&mut buf, 0)
+                        .unwrap();
+                    assert_eq!(n, buf.len());
+                    buf
+                });
+                names.push((path, contents));
+            }
+        }
+        names.sort();
+
+        names
+    }
+
+    // small rewrites of a big file go in place through the journal, the rest is copied on write
+    fn workload<D: BlockDriver>(fs: &mut NeFS<D>, states: &mut Vec<State>) -> Result<(), FsError> {
+        fs.open("/big", OpenFlags::NONE)?
+            .write_bytes(&[2; 100], 3 * PAGE_SIZE)?;
+        fs.open("/big", OpenFlags::NONE)?
+            .write_bytes(&[2; 100], 15 * PAGE_SIZE + 4000)?;
+        fs.commit()?;
+        states.push(state(fs));
+
+        fs.create("/a")?;
+        fs.open("/a", OpenFlags::NONE)?.set_contents(b"alpha")?;
+        fs.commit()?;
+        states.push(state(fs));
+
+        fs.rename("/a", "/b")?;
+        fs.mkdir("/d")?;
+        fs.open("/big", OpenFlags::NONE)?
+            .write_bytes(&[3; 10], 20 * PAGE_SIZE)?;
+        fs.commit()?;
+        states.push(state(fs));
+
+        fs.unlink("/b")?;
+        fs.open("/big", OpenFlags::NONE)?.write_bytes(&[4; 10], 0)?;
+        fs.commit()?;
+        states.push(state(fs));
+
+        Ok(())
+    }
+
+    let mut vpartition = VPartition::new_zeroed(256);
+    let options = MkfsOptions {
+        journal_clusters: 16,
+    };
+    mkfs_with(&mut vpartition, 256, "crash", options).unwrap();
+    let mut fs = NeFS::mount(vpartition).unwrap();
+    fs.create("/big").unwrap();
+    fs.open("/big", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(&vec![1; 16 * PAGE_SIZE as usize])
+        .unwrap();
+    fs.commit().unwrap();
+    let mut states = vec![state(&mut fs)];
+    let baseline = fs.unmount();
+
+    // how many writes it takes without a crash
+    let mut fs = NeFS::mount(baseline.clone()).unwrap();
+    workload(&mut fs, &mut states).unwrap();
+    let n_writes = fs.unmount().n_writes() - baseline.n_writes();
+
+    // crash at every write. What is left has to mount, as one of the commits, replaying the journal if it has to
+    for k in 0..=n_writes {
+        let mut vpartition = baseline.clone();
+        vpartition.crash_after(k);
+        let mut fs = NeFS::mount(vpartition).unwrap();
+        let _ = workload(&mut fs, &mut Vec::new());
+
+        let mut vpartition = fs.unmount();
+        vpartition.recover();
+        let mut fs = NeFS::mount(vpartition).unwrap();
+        let now = state(&mut fs);
+        // file data is written in place, so a crash during a commit can leave any byte /big has in it from either
+        // commit. Everything else has to be one of the commits exactly
+        let at = states
+            .iter()
+            .position(|state| {
+                state.len() == now.len()
+                    && state
+                        .iter()
+                        .zip(&now)
+                        .all(|((path, old), (_, new))| match (old, new) {
+                            (Some(old), Some(new)) if path == "/big" => old.len() == new.len(),
+                            _ => old == new,
+                        })
+            })
+            .unwrap_or_else(|| {
+                panic!(
+                    "crash after {} writes left {:?}",
+                    k,
+                    now.iter().map(|(path, _)| path).collect::<Vec<_>>()
+                )
+            });
+        let big = |state: &State| -> Vec<u8> {
+            let (_, contents) = state.iter().find(|(path, _)| path == "/big").unwrap();
+            contents.clone().unwrap()
+        };
+        let now_big = big(&now);
+        let committed = big(&states[at]);
+        let next = states.get(at + 1).map(big);
+        for (i, byte) in now_big.iter().enumerate() {
+            assert!(
+                *byte == committed[i] || next.as_ref().and_then(|next| next.get(i)) == Some(byte),
+                "crash after {} writes left byte {} of /big as {}",
+                k,
+                i,
+                byte
+            );
+        }
+        if k == n_writes {
+            assert_eq!(&now, states.last().unwrap());
+        }
+    }
+}
@@ -1,52 +1,37 @@
-/*
-    let (tx, mut rx) = mpsc::channel(64);
-    let req_read = tokio::spawn(async move {
-    let (resp_tx, resp_rx) = oneshot::channel();
+// Read, write and read back block 0 through a tokio disk handle. Start the manager first
+// Only awaits, so this can run on the runtime, unlike the fs which blocks on every request
 
-    // send a disk request to read
-    tx.send(DiskRequest::Read {
-            block_id: 0,
-            resp: resp_tx,
-        })
-        .await
-        .unwrap();
+use crate::block_tokio::BlockDriverTokioHandle;
+use neutron_fs::driver::block::IoError;
 
-        let res = resp_rx.await;
+pub async fn read_write_read(handle: &BlockDriverTokioHandle) -> Result<(), IoError> {
+    handle.read(0).await?;
 
-        println!("read res = {:?}", res.unwrap());
+    let new_block = [1; 4096];
+    handle.write(0, new_block).await?;
 
-        // WRITE REQ
-        let (resp_tx, resp_rx) = oneshot::channel();
+    // the device took the write but doesnt give it back
+    if handle.read(0).await? != new_block {
+        return Err(IoError::Device);
+    }
 
-        let new_block = [1; 4096];
+    Ok(())
+}
 
-        // send a write req through tx..? why doesnt it work
-        tx.send(DiskRequest::Write {
-            block_id: 0,
-            block: new_block,
-            resp: resp_tx,
-        })
-        .await
-        .unwrap();
+// -----------------
+// TESTS
+// -----------------
 
-        let res = resp_rx.await;
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_write_read() {
+    use crate::block_tokio::{BlockDriverTokio, VPartition};
 
-        println!("write res = {:?}", res.unwrap());
+    let driver = BlockDriverTokio::with_capacity(VPartition::new_zeroed(4), 4);
+    let handle = driver.handle();
+    let manager = driver.init_manager();
 
-        let (resp_tx, resp_rx) = oneshot::channel();
+    assert_eq!(read_write_read(&handle).await, Ok(()));
 
-        // send a disk request to read
-        tx.send(DiskRequest::Read {
-            block_id: 0,
-            resp: resp_tx,
-        })
-        .await
-        .unwrap();
-
-        let res = resp_rx.await;
-
-        println!("read res = {:?}", res.unwrap());
-    });
-
-    req_read.await.unwrap();
-*/
\ No newline at end of file
+    drop(handle);
+    assert_eq!(manager.await.unwrap().get_block(0), [1; 4096]);
+}
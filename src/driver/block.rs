@@ -3,8 +3,16 @@
 use core::sync::atomic::AtomicBool;
 
 use super::neutronfs::{ClusterData, ClusterNumber, PAGE_SIZE};
-use alloc::{borrow::ToOwned, sync::Arc, vec::Vec};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    vec,
+    vec::Vec,
+};
 use bytes::Bytes;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 
 pub type Block = [u8; 4096];
 
@@ -25,108 +33,261 @@ pub fn make_block() -> Block {
 // -------------
 
 /// Do allocate this somewhere handy, like the stack. Cache if possible!
+/// Bounded FIFO. `try_push` hands the element back when full, `push` overwrites the oldest instead
 #[derive(Debug)]
 pub struct RingBuffer<T, const SIZE: usize> {
+    // index of the oldest element
     curr_head: usize,
     n_elements: usize,
     max_size: usize,
-    buffer: [T; SIZE],
+    buffer: [Option<T>; SIZE],
 }
 
-impl<T: Clone, const SIZE: usize> RingBuffer<T, SIZE> {
-    pub fn new(curr_head: usize, n_elements: usize, max_size: usize, buffer: [T; SIZE]) -> Self {
-        Self {
+impl<T, const SIZE: usize> RingBuffer<T, SIZE> {
+    // a ring with no slots is a build error, not a panic
+    const HAS_SLOTS: () = assert!(SIZE > 0, "ring buffer needs at least one slot");
+
+    /// Ring over `buffer` where the `n_elements` from `curr_head` on are already queued. Only the first `max_size` slots are used
+    /// None if `max_size` isnt in 1..=SIZE, or the queued elements dont fit in it
+    pub fn new(
+        curr_head: usize,
+        n_elements: usize,
+        max_size: usize,
+        buffer: [T; SIZE],
+    ) -> Option<Self> {
+        if max_size == 0 || max_size > SIZE || curr_head >= max_size || n_elements > max_size {
+            return None;
+        }
+
+        let mut index = 0;
+        let buffer = buffer.map(|t| {
+            // slots outside the live range are just storage
+            let live = (index + max_size - curr_head) % max_size < n_elements && index < max_size;
+            index += 1;
+            if live {
+                Some(t)
+            } else {
+                None
+            }
+        });
+
+        Some(Self {
             curr_head,
             n_elements,
             max_size,
             buffer,
-        }
+        })
     }
 
-    pub fn new_cluster_queue(buf: [T; SIZE]) -> Self {
-        // let  = [(0, [0 as u8; 4096]); SIZE];
-        // for each elem, increment the cluster number? Nah its a request queue
+    pub fn new_empty() -> Self {
+        let () = Self::HAS_SLOTS;
 
         Self {
             curr_head: 0,
             n_elements: 0,
             max_size: SIZE,
-            buffer: buf,
+            buffer: core::array::from_fn(|_| None),
         }
     }
 
-    /// Push a new element to the back of the queue. Or if full, to the head, and increment the curr_head
-    pub fn push(&mut self, t: T) {
-        // if full (n_elements = max_size), replace the head and increment (% size if needed)
+    pub fn len(&self) -> usize {
+        self.n_elements
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_elements == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.n_elements == self.max_size
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.max_size
+    }
+
+    /// Push to the back of the queue. If full, hands `t` back untouched
+    pub fn try_push(&mut self, t: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(t);
+        }
+
         let ind = (self.curr_head + self.n_elements) % self.max_size;
-        self.buffer[ind] = t;
-        // move head to next
-        let next = (ind + 1) % self.max_size;
-        self.curr_head = next;
+        self.buffer[ind] = Some(t);
+        self.n_elements += 1;
+
+        Ok(())
+    }
+
+    /// Push to the back of the queue. If full, the oldest element (the head) is dropped to make room and returned
+    pub fn push(&mut self, t: T) -> Option<T> {
+        let evicted = if self.is_full() { self.pop() } else { None };
+        // cant fail, there is room now
+        let _ = self.try_push(t);
+
+        evicted
     }
 
-    /// Get the head
-    pub fn pop(&mut self) -> T {
-        let ind = self.curr_head;
+    /// Take the head (oldest element)
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let t = self.buffer[self.curr_head].take();
         self.curr_head = (self.curr_head + 1) % self.max_size;
-        self.buffer[ind].clone()
+        self.n_elements -= 1;
+
+        t
+    }
+
+    /// Look at the head without taking it
+    pub fn peek(&self) -> Option<&T> {
+        self.iter().next()
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+        self.curr_head = 0;
+    }
+
+    /// Oldest to newest
+    pub fn iter(&self) -> RingBufferIter<'_, T, SIZE> {
+        RingBufferIter {
+            ring: self,
+            index: 0,
+        }
+    }
+}
+
+pub struct RingBufferIter<'a, T, const SIZE: usize> {
+    ring: &'a RingBuffer<T, SIZE>,
+    // how many we have yielded so far
+    index: usize,
+}
+
+impl<'a, T, const SIZE: usize> Iterator for RingBufferIter<'a, T, SIZE> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.ring.n_elements {
+            return None;
+        }
+
+        let ind = (self.ring.curr_head + self.index) % self.ring.max_size;
+        self.index += 1;
+
+        self.ring.buffer[ind].as_ref()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.ring.n_elements - self.index;
+        (left, Some(left))
+    }
+}
+
+impl<'a, T, const SIZE: usize> ExactSizeIterator for RingBufferIter<'a, T, SIZE> {}
+
+impl<'a, T, const SIZE: usize> IntoIterator for &'a RingBuffer<T, SIZE> {
+    type Item = &'a T;
+    type IntoIter = RingBufferIter<'a, T, SIZE>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
 
 // USE A RING BUFFER, basically a pointer to the current head and a max size
-// Then "push" or append to the buffer at head - 1/curr_size. If would overflow, just replace the head
+// Then "push" or append to the buffer at head + curr_size. If it would overflow, the caller decides: try_push() or overwrite the head with push()
 
 pub const MAX_QUEUE_SIZE: usize = 64;
 
+/// Reads waiting to go to the device
 #[derive(Debug)]
 pub struct ReadQueue {
-    queue: RingBuffer<(ClusterNumber, Block), MAX_QUEUE_SIZE>,
+    queue: RingBuffer<(RequestId, ClusterNumber), MAX_QUEUE_SIZE>,
 }
 
+/// Writes waiting to go to the device, with the block to write
 #[derive(Debug)]
 pub struct WriteQueue {
-    queue: RingBuffer<(ClusterNumber, Block), MAX_QUEUE_SIZE>,
+    queue: RingBuffer<(RequestId, ClusterNumber, Block), MAX_QUEUE_SIZE>,
 }
 
 impl WriteQueue {
-    pub fn new(queue: RingBuffer<(ClusterNumber, Block), MAX_QUEUE_SIZE>) -> Self {
+    pub fn new(queue: RingBuffer<(RequestId, ClusterNumber, Block), MAX_QUEUE_SIZE>) -> Self {
         Self { queue }
     }
 
     pub fn new_empty() -> Self {
-        // uhh ok
-        let ringbuffer = RingBuffer::new_cluster_queue([(0, [0 as u8; 4096]); MAX_QUEUE_SIZE]);
-        Self { queue: ringbuffer }
+        Self {
+            queue: RingBuffer::new_empty(),
+        }
+    }
+
+    /// Queue a write. A full queue hands the request back, dropping writes would lose data
+    pub fn push(
+        &mut self,
+        id: RequestId,
+        cluster_number: ClusterNumber,
+        block: Block,
+    ) -> Result<(), (RequestId, ClusterNumber, Box<Block>)> {
+        self.queue
+            .try_push((id, cluster_number, block))
+            .map_err(|(id, cluster_number, block)| (id, cluster_number, Box::new(block)))
+    }
+
+    pub fn pop(&mut self) -> Option<(RequestId, ClusterNumber, Block)> {
+        self.queue.pop()
     }
 
-    pub fn push(&mut self, cluster_number: ClusterNumber, block: Block) {
-        self.queue.push((cluster_number, block));
+    pub fn len(&self) -> usize {
+        self.queue.len()
     }
 
-    pub fn pop(&mut self) -> Option<(ClusterNumber, Block)> {
-        Some(self.queue.pop())
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.is_full()
     }
 }
 
 impl ReadQueue {
-    pub fn new(queue: RingBuffer<(ClusterNumber, Block), MAX_QUEUE_SIZE>) -> Self {
+    pub fn new(queue: RingBuffer<(RequestId, ClusterNumber), MAX_QUEUE_SIZE>) -> Self {
         Self { queue }
     }
 
     pub fn new_empty() -> Self {
-        // uhh ok
-        let ringbuffer = RingBuffer::new_cluster_queue([(0, [0 as u8; 4096]); MAX_QUEUE_SIZE]);
-        Self { queue: ringbuffer }
+        Self {
+            queue: RingBuffer::new_empty(),
+        }
     }
 
-    pub fn push(&mut self, buf: &mut [u8], cluster_number: ClusterNumber) {
-        let mut new_buf: Block = make_block();
-        new_buf.copy_from_slice(&buf[..4095]);
-        self.queue.push((cluster_number, new_buf));
+    /// Queue a read. A full queue hands the request back
+    pub fn push(
+        &mut self,
+        id: RequestId,
+        cluster_number: ClusterNumber,
+    ) -> Result<(), (RequestId, ClusterNumber)> {
+        self.queue.try_push((id, cluster_number))
     }
 
-    pub fn pop(&mut self) -> Option<(ClusterNumber, Block)> {
-        Some(self.queue.pop())
+    pub fn pop(&mut self) -> Option<(RequestId, ClusterNumber)> {
+        self.queue.pop()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.is_full()
     }
 }
 
@@ -134,27 +295,840 @@ impl ReadQueue {
 // &mut is not threadsafe
 // must use ARC with mutex
 
+/// Handed out when a request is pushed, and used to collect its completion
+pub type RequestId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoError {
+    /// Cluster past the end of the device
+    OutOfRange(ClusterNumber),
+    /// Too many requests in flight, collect some completions first
+    QueueFull,
+    /// The device failed the request
+    Device,
+    /// Got a write completion for a read or the other way round, or waited on an id that isnt pending
+    WrongCompletion(RequestId),
+}
+
+/// What a finished request gives back. A read's block is boxed, so a write's completion doesnt take up a cluster too
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Completion {
+    Read(Result<Box<Block>, IoError>),
+    Write(Result<(), IoError>),
+}
+
+impl Completion {
+    pub fn into_read(self, id: RequestId) -> Result<Block, IoError> {
+        match self {
+            Completion::Read(res) => res.map(|block| *block),
+            Completion::Write(_) => Err(IoError::WrongCompletion(id)),
+        }
+    }
+
+    pub fn into_write(self, id: RequestId) -> Result<(), IoError> {
+        match self {
+            Completion::Write(res) => res,
+            Completion::Read(_) => Err(IoError::WrongCompletion(id)),
+        }
+    }
+}
+
 /// Interface for block drivers to implement
+/// Requests are queued and complete later, in any order. The kernel interrupt handler (or the tokio manager) finishes them,
+/// and whoever pushed the request picks up the result with its id
+/// A push only fails if the request cant be queued, e.g. QueueFull. Anything wrong with the request itself, like a cluster
+/// past the end of the device (OutOfRange), comes back in its completion
 pub trait BlockDriver {
-    fn push_read_request(&mut self, buf: &mut [u8], cluster_number: u64);
-    fn push_write_request(&mut self, cluster_number: u64, block: Block);
+    fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError>;
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<RequestId, IoError>;
+
+    /// Doesnt block. The completion for `id` if the device is done with it. Each completion is only handed out once
+    fn poll_completion(&mut self, id: RequestId) -> Option<Completion>;
+
+    /// Whether `id` was handed out and nobody collected its completion yet. Drivers that cant tell say yes
+    fn is_pending(&self, id: RequestId) -> bool {
+        let _ = id;
+        true
+    }
+
+    /// Block until `id` is done. Spins on poll_completion unless the driver knows better
+    /// An id that isnt pending, never handed out or already collected, gives WrongCompletion instead of spinning forever
+    fn wait(&mut self, id: RequestId) -> Completion {
+        loop {
+            if let Some(completion) = self.poll_completion(id) {
+                return completion;
+            }
+            if !self.is_pending(id) {
+                return Completion::Read(Err(IoError::WrongCompletion(id)));
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Make finished writes durable. For drivers that cache or buffer, a no-op for the rest
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+
+    /// Byte address of cluster 0 on the physical disk. Only a partition view isnt at 0
+    fn physical_offset(&self) -> u64 {
+        0
+    }
+
+    /// How many clusters the device has, if the driver knows
+    fn device_clusters(&self) -> Option<u64> {
+        None
+    }
+
+    /// Await a request instead of blocking on it
+    fn completion(&mut self, id: RequestId) -> RequestFuture<'_, Self>
+    where
+        Self: Sized,
+    {
+        RequestFuture { driver: self, id }
+    }
+}
+
+/// Lend a driver out without giving it up, e.g. to a PartitionView
+impl<D: BlockDriver + ?Sized> BlockDriver for &mut D {
+    fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+        (**self).push_read_request(cluster_number)
+    }
+
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<RequestId, IoError> {
+        (**self).push_write_request(cluster_number, block)
+    }
+
+    fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+        (**self).poll_completion(id)
+    }
+
+    fn is_pending(&self, id: RequestId) -> bool {
+        (**self).is_pending(id)
+    }
+
+    fn wait(&mut self, id: RequestId) -> Completion {
+        (**self).wait(id)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        (**self).flush()
+    }
+
+    fn physical_offset(&self) -> u64 {
+        (**self).physical_offset()
+    }
+
+    fn device_clusters(&self) -> Option<u64> {
+        (**self).device_clusters()
+    }
+}
+
+/// Future adapter over poll_completion. Without a way to hook the device's interrupt, it just asks to be polled again
+pub struct RequestFuture<'d, D: BlockDriver> {
+    driver: &'d mut D,
+    id: RequestId,
+}
+
+impl<'d, D: BlockDriver> Future for RequestFuture<'d, D> {
+    type Output = Completion;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.driver.poll_completion(this.id) {
+            Some(completion) => Poll::Ready(completion),
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Read a block and wait for it
+pub fn read_block<D: BlockDriver + ?Sized>(
+    driver: &mut D,
+    cluster_number: ClusterNumber,
+) -> Result<Block, IoError> {
+    let id = driver.push_read_request(cluster_number)?;
+    driver.wait(id).into_read(id)
+}
+
+/// Write a block and wait for it
+pub fn write_block<D: BlockDriver + ?Sized>(
+    driver: &mut D,
+    cluster_number: ClusterNumber,
+    block: Block,
+) -> Result<(), IoError> {
+    let id = driver.push_write_request(cluster_number, block)?;
+    driver.wait(id).into_write(id)
+}
+
+/// Finished requests waiting to be collected. For drivers that finish a request as soon as its pushed, and for interrupt handlers to drop results into
+#[derive(Debug, Default, Clone)]
+pub struct Completions {
+    next_id: RequestId,
+    // handed to the device, not done yet
+    in_flight: BTreeSet<RequestId>,
+    done: Vec<(RequestId, Completion)>,
+}
+
+impl Completions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A fresh id for a request
+    pub fn next_id(&mut self) -> RequestId {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// A fresh id for a request the device finishes later. It stays pending until its completion is taken
+    pub fn begin(&mut self) -> RequestId {
+        let id = self.next_id();
+        self.in_flight.insert(id);
+        id
+    }
+
+    /// Mark `id` as done
+    pub fn complete(&mut self, id: RequestId, completion: Completion) {
+        self.in_flight.remove(&id);
+        self.done.push((id, completion));
+    }
+
+    /// For drivers that do the work right away. Gives the request an id that is already done
+    pub fn complete_now(&mut self, completion: Completion) -> RequestId {
+        let id = self.next_id();
+        self.complete(id, completion);
+        id
+    }
+
+    pub fn take(&mut self, id: RequestId) -> Option<Completion> {
+        let index = self.done.iter().position(|(i, _)| *i == id)?;
+        Some(self.done.swap_remove(index).1)
+    }
+
+    /// Begun and not done, or done and not taken
+    pub fn is_pending(&self, id: RequestId) -> bool {
+        self.in_flight.contains(&id) || self.done.iter().any(|(i, _)| *i == id)
+    }
+
+    /// Number of completions nobody has collected yet
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.done.is_empty()
+    }
 }
 
 // -------------
 // NEUTRON-LIKE
 // -------------
 
+/// Queues requests for the device. The kernel pops them off to DMA to the SSD, and calls complete() from the IO_fin interrupt
 pub struct NeutronDriver {
     read_queue: ReadQueue,
     write_queue: WriteQueue,
+    completions: Completions,
+}
+
+impl NeutronDriver {
+    pub fn new() -> Self {
+        Self {
+            read_queue: ReadQueue::new_empty(),
+            write_queue: WriteQueue::new_empty(),
+            completions: Completions::new(),
+        }
+    }
+
+    /// Next read to hand to the device
+    pub fn next_read(&mut self) -> Option<(RequestId, ClusterNumber)> {
+        self.read_queue.pop()
+    }
+
+    /// Next write to hand to the device
+    pub fn next_write(&mut self) -> Option<(RequestId, ClusterNumber, Block)> {
+        self.write_queue.pop()
+    }
+
+    /// The device finished `id`. Can be called in any order
+    pub fn complete(&mut self, id: RequestId, completion: Completion) {
+        self.completions.complete(id, completion);
+    }
+}
+
+impl Default for NeutronDriver {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BlockDriver for NeutronDriver {
-    fn push_read_request(&mut self, buf: &mut [u8], cluster_number: u64) {
-        self.read_queue.push(buf, cluster_number);
+    fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+        if self.read_queue.is_full() {
+            return Err(IoError::QueueFull);
+        }
+        let id = self.completions.begin();
+        self.read_queue
+            .push(id, cluster_number)
+            .map_err(|_| IoError::QueueFull)?;
+
+        Ok(id)
+    }
+
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<RequestId, IoError> {
+        if self.write_queue.is_full() {
+            return Err(IoError::QueueFull);
+        }
+        let id = self.completions.begin();
+        self.write_queue
+            .push(id, cluster_number, block)
+            .map_err(|_| IoError::QueueFull)?;
+
+        Ok(id)
+    }
+
+    fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+        self.completions.take(id)
+    }
+
+    fn is_pending(&self, id: RequestId) -> bool {
+        self.completions.is_pending(id)
+    }
+}
+
+// -------------
+// RAM DISK
+// -------------
+
+/// Plain vector of blocks. Good enough as a ramdisk, and what the fs tests format
+#[derive(Debug, Clone)]
+pub struct RamDisk {
+    blocks: Vec<Block>,
+    completions: Completions,
+}
+
+impl RamDisk {
+    pub fn new(n_blocks: usize) -> Self {
+        Self {
+            blocks: vec![make_block(); n_blocks],
+            completions: Completions::new(),
+        }
+    }
+
+    pub fn n_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+impl BlockDriver for RamDisk {
+    fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+        let res = self
+            .blocks
+            .get(cluster_number as usize)
+            .map(|block| Box::new(*block))
+            .ok_or(IoError::OutOfRange(cluster_number));
+
+        Ok(self.completions.complete_now(Completion::Read(res)))
+    }
+
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<RequestId, IoError> {
+        let res = match self.blocks.get_mut(cluster_number as usize) {
+            Some(b) => {
+                *b = block;
+                Ok(())
+            }
+            None => Err(IoError::OutOfRange(cluster_number)),
+        };
+
+        Ok(self.completions.complete_now(Completion::Write(res)))
+    }
+
+    fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+        self.completions.take(id)
+    }
+
+    fn is_pending(&self, id: RequestId) -> bool {
+        self.completions.is_pending(id)
+    }
+
+    fn device_clusters(&self) -> Option<u64> {
+        Some(self.blocks.len() as u64)
+    }
+}
+
+// -------------
+// PARTITIONS
+// -------------
+
+/// A slice of a bigger device, e.g. the NeFS partition in a GPT. Cluster 0 is the partition's first cluster
+/// physical addr = physical_offset_of_partition + cluster_area_offset + cluster_number * cluster_size, the cluster area starts right at the partition
+#[derive(Debug)]
+pub struct PartitionView<D: BlockDriver> {
+    driver: D,
+    first_cluster: ClusterNumber,
+    n_clusters: u64,
+    // requests handed to the driver: our id -> its id
+    pending: BTreeMap<RequestId, RequestId>,
+    // requests past the end of the partition, they never reach the driver
+    completions: Completions,
+}
+
+impl<D: BlockDriver> PartitionView<D> {
+    /// `n_clusters` starting at `first_cluster` (the partition's first LBA) of `driver`
+    pub fn new(driver: D, first_cluster: ClusterNumber, n_clusters: u64) -> Self {
+        Self {
+            driver,
+            first_cluster,
+            n_clusters,
+            pending: BTreeMap::new(),
+            completions: Completions::new(),
+        }
+    }
+
+    pub fn first_cluster(&self) -> ClusterNumber {
+        self.first_cluster
+    }
+
+    pub fn n_clusters(&self) -> u64 {
+        self.n_clusters
+    }
+
+    pub fn into_inner(self) -> D {
+        self.driver
+    }
+
+    fn translate(&self, cluster_number: ClusterNumber) -> Option<ClusterNumber> {
+        (cluster_number < self.n_clusters).then(|| self.first_cluster + cluster_number)
+    }
+
+    /// The device speaks in its own cluster numbers, give them back in ours
+    fn untranslate_err(&self, e: IoError) -> IoError {
+        match e {
+            IoError::OutOfRange(c) => IoError::OutOfRange(c.wrapping_sub(self.first_cluster)),
+            e => e,
+        }
+    }
+
+    fn untranslate(&self, completion: Completion) -> Completion {
+        let fix = |e| self.untranslate_err(e);
+
+        match completion {
+            Completion::Read(res) => Completion::Read(res.map_err(fix)),
+            Completion::Write(res) => Completion::Write(res.map_err(fix)),
+        }
+    }
+
+    /// Give the driver's request one of our ids
+    fn track(&mut self, inner: RequestId) -> RequestId {
+        let id = self.completions.next_id();
+        self.pending.insert(id, inner);
+        id
+    }
+}
+
+impl<D: BlockDriver> BlockDriver for PartitionView<D> {
+    fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+        let Some(absolute) = self.translate(cluster_number) else {
+            let res = Err(IoError::OutOfRange(cluster_number));
+            return Ok(self.completions.complete_now(Completion::Read(res)));
+        };
+
+        let inner = self
+            .driver
+            .push_read_request(absolute)
+            .map_err(|e| self.untranslate_err(e))?;
+        Ok(self.track(inner))
+    }
+
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<RequestId, IoError> {
+        let Some(absolute) = self.translate(cluster_number) else {
+            let res = Err(IoError::OutOfRange(cluster_number));
+            return Ok(self.completions.complete_now(Completion::Write(res)));
+        };
+
+        let inner = self
+            .driver
+            .push_write_request(absolute, block)
+            .map_err(|e| self.untranslate_err(e))?;
+        Ok(self.track(inner))
+    }
+
+    fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+        let Some(&inner) = self.pending.get(&id) else {
+            return self.completions.take(id);
+        };
+
+        let completion = self.driver.poll_completion(inner)?;
+        self.pending.remove(&id);
+        Some(self.untranslate(completion))
     }
 
-    fn push_write_request(&mut self, cluster_number: u64, block: Block) {
-        self.write_queue.push(cluster_number, block);
+    fn is_pending(&self, id: RequestId) -> bool {
+        match self.pending.get(&id) {
+            Some(&inner) => self.driver.is_pending(inner),
+            None => self.completions.is_pending(id),
+        }
+    }
+
+    fn wait(&mut self, id: RequestId) -> Completion {
+        let Some(inner) = self.pending.remove(&id) else {
+            return self
+                .completions
+                .take(id)
+                .unwrap_or(Completion::Read(Err(IoError::WrongCompletion(id))));
+        };
+
+        let completion = self.driver.wait(inner);
+        self.untranslate(completion)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.driver.flush()
+    }
+
+    fn physical_offset(&self) -> u64 {
+        self.driver.physical_offset() + self.first_cluster * PAGE_SIZE
+    }
+
+    fn device_clusters(&self) -> Option<u64> {
+        Some(self.n_clusters)
     }
 }
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_ring_buffer_basics() {
+    let mut ring = RingBuffer::<u32, 3>::new_empty();
+    assert!(ring.is_empty());
+    assert_eq!(ring.pop(), None);
+
+    ring.try_push(1).unwrap();
+    ring.try_push(2).unwrap();
+    ring.try_push(3).unwrap();
+    assert!(ring.is_full());
+    assert_eq!(ring.try_push(4), Err(4));
+
+    // FIFO
+    assert_eq!(ring.pop(), Some(1));
+    ring.try_push(4).unwrap();
+    assert_eq!(ring.iter().copied().collect::<Vec<_>>(), [2, 3, 4]);
+
+    // overwrite mode drops the oldest
+    assert_eq!(ring.push(5), Some(2));
+    assert_eq!(ring.peek(), Some(&3));
+    assert_eq!(ring.len(), 3);
+
+    // prefilled, wrapping around the end
+    let ring = RingBuffer::new(2, 2, 3, [7, 8, 9]).unwrap();
+    assert_eq!(ring.iter().copied().collect::<Vec<_>>(), [9, 7]);
+
+    // args that dont describe a ring are refused, not a panic
+    assert!(RingBuffer::new(0, 0, 0, [7, 8, 9]).is_none());
+    assert!(RingBuffer::new(0, 0, 4, [7, 8, 9]).is_none());
+    assert!(RingBuffer::new(2, 0, 2, [7, 8, 9]).is_none());
+    assert!(RingBuffer::new(0, 3, 2, [7, 8, 9]).is_none());
+}
+
+#[test]
+fn test_queues_report_empty_and_full() {
+    extern crate std;
+
+    // 64 queued blocks are 256K, debug builds copy that around more than a test thread's stack can take
+    std::thread::Builder::new()
+        .stack_size(16 * 1024 * 1024)
+        .spawn(|| {
+            let mut queue = WriteQueue::new_empty();
+            assert_eq!(queue.pop(), None);
+
+            for i in 0..MAX_QUEUE_SIZE as u64 {
+                queue.push(i, i, make_block()).unwrap();
+            }
+            assert!(queue.is_full());
+            assert!(queue.push(99, 99, make_block()).is_err());
+            assert_eq!(queue.pop().map(|(id, _, _)| id), Some(0));
+
+            let mut queue = ReadQueue::new_empty();
+            assert_eq!(queue.pop(), None);
+            queue.push(1, 5).unwrap();
+            assert_eq!(queue.len(), 1);
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn test_out_of_order_completion() {
+    extern crate std;
+
+    std::thread::Builder::new()
+        .stack_size(16 * 1024 * 1024)
+        .spawn(|| {
+            let mut driver = NeutronDriver::new();
+            let write = driver.push_write_request(3, [7; 4096]).unwrap();
+            let read = driver.push_read_request(3).unwrap();
+            assert_eq!(driver.poll_completion(read), None);
+
+            // the "device" finishes the read first
+            let (id, cluster_number) = driver.next_read().unwrap();
+            assert_eq!((id, cluster_number), (read, 3));
+            driver.complete(read, Completion::Read(Ok(Box::new([7; 4096]))));
+            assert_eq!(driver.wait(read).into_read(read), Ok([7; 4096]));
+
+            let (id, _, _) = driver.next_write().unwrap();
+            driver.complete(id, Completion::Write(Ok(())));
+            assert_eq!(driver.wait(write).into_write(write), Ok(()));
+
+            // only handed out once
+            assert_eq!(driver.poll_completion(write), None);
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn test_wait_on_unknown_id() {
+    extern crate std;
+
+    std::thread::Builder::new()
+        .stack_size(16 * 1024 * 1024)
+        .spawn(|| {
+            let wrong = |id| Completion::Read(Err(IoError::WrongCompletion(id)));
+
+            // never handed out, and already collected
+            let mut disk = RamDisk::new(4);
+            assert_eq!(disk.wait(42), wrong(42));
+            let id = disk.push_write_request(1, [1; 4096]).unwrap();
+            assert!(disk.is_pending(id));
+            disk.wait(id).into_write(id).unwrap();
+            assert!(!disk.is_pending(id));
+            assert_eq!(disk.wait(id), wrong(id));
+
+            // still with the device is pending, done and collected isnt
+            let mut driver = NeutronDriver::new();
+            let id = driver.push_read_request(3).unwrap();
+            assert!(driver.is_pending(id));
+            assert!(!driver.is_pending(id + 1));
+            assert_eq!(driver.wait(id + 1), wrong(id + 1));
+            driver.next_read().unwrap();
+            assert!(driver.is_pending(id));
+            driver.complete(id, Completion::Read(Ok(Box::new([3; 4096]))));
+            assert_eq!(driver.wait(id).into_read(id), Ok([3; 4096]));
+            assert_eq!(driver.wait(id), wrong(id));
+
+            // a partition asks the device underneath
+            let mut view = PartitionView::new(RamDisk::new(8), 2, 4);
+            let id = view.push_read_request(1).unwrap();
+            assert!(view.is_pending(id));
+            view.wait(id).into_read(id).unwrap();
+            assert!(!view.is_pending(id));
+            assert_eq!(view.wait(id), wrong(id));
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn test_ram_disk_completions() {
+    let mut disk = RamDisk::new(4);
+    write_block(&mut disk, 1, [1; 4096]).unwrap();
+    assert_eq!(read_block(&mut disk, 1), Ok([1; 4096]));
+    assert_eq!(read_block(&mut disk, 4), Err(IoError::OutOfRange(4)));
+
+    // past the end still queues, the completion says what went wrong
+    let id = disk.push_write_request(4, [1; 4096]).unwrap();
+    assert_eq!(
+        disk.wait(id),
+        Completion::Write(Err(IoError::OutOfRange(4)))
+    );
+
+    // nothing left lying around
+    assert!(disk.completions.is_empty());
+
+    // a write collected through the future adapter
+    let id = disk.push_write_request(2, [2; 4096]).unwrap();
+    let mut future = disk.completion(id);
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    assert_eq!(
+        Pin::new(&mut future).poll(&mut cx),
+        Poll::Ready(Completion::Write(Ok(())))
+    );
+}
+
+#[cfg(test)]
+fn noop_waker() -> core::task::Waker {
+    use core::task::{RawWaker, RawWakerVTable, Waker};
+
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+    unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) }
+}
+
+#[cfg(test)]
+mod proptests {
+    use super::RingBuffer;
+    use alloc::{collections::VecDeque, vec::Vec};
+    use proptest::prelude::*;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        TryPush(u16),
+        Push(u16),
+        Pop,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            any::<u16>().prop_map(Op::TryPush),
+            any::<u16>().prop_map(Op::Push),
+            Just(Op::Pop),
+        ]
+    }
+
+    proptest! {
+        // behaves like a VecDeque capped at the capacity
+        #[test]
+        fn matches_bounded_deque(ops in proptest::collection::vec(op(), 0..200)) {
+            let mut ring = RingBuffer::<u16, 8>::new_empty();
+            let mut model = VecDeque::new();
+
+            for op in ops {
+                match op {
+                    Op::TryPush(v) => {
+                        let res = ring.try_push(v);
+                        if model.len() == 8 {
+                            prop_assert_eq!(res, Err(v));
+                        } else {
+                            prop_assert_eq!(res, Ok(()));
+                            model.push_back(v);
+                        }
+                    }
+                    Op::Push(v) => {
+                        let evicted = if model.len() == 8 { model.pop_front() } else { None };
+                        model.push_back(v);
+                        prop_assert_eq!(ring.push(v), evicted);
+                    }
+                    Op::Pop => prop_assert_eq!(ring.pop(), model.pop_front()),
+                }
+
+                prop_assert_eq!(ring.len(), model.len());
+                prop_assert_eq!(ring.is_empty(), model.is_empty());
+                prop_assert_eq!(ring.is_full(), model.len() == 8);
+                prop_assert_eq!(ring.iter().copied().collect::<Vec<_>>(), model.iter().copied().collect::<Vec<_>>());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_partition_view() {
+    let mut disk = RamDisk::new(64);
+    {
+        let mut view = PartitionView::new(&mut disk, 10, 20);
+        write_block(&mut view, 0, [1; 4096]).unwrap();
+        write_block(&mut view, 19, [2; 4096]).unwrap();
+        assert_eq!(read_block(&mut view, 19), Ok([2; 4096]));
+
+        // past the end of the partition, even though the disk goes on
+        assert_eq!(read_block(&mut view, 20), Err(IoError::OutOfRange(20)));
+        assert_eq!(
+            write_block(&mut view, 25, [3; 4096]),
+            Err(IoError::OutOfRange(25))
+        );
+        assert_eq!(view.physical_offset(), 10 * 4096);
+    }
+
+    assert_eq!(read_block(&mut disk, 10), Ok([1; 4096]));
+    assert_eq!(read_block(&mut disk, 29), Ok([2; 4096]));
+    assert_eq!(read_block(&mut disk, 30), Ok(make_block()));
+
+    // a partition hanging off the end of the disk reports in partition clusters
+    let mut view = PartitionView::new(disk, 60, 8);
+    assert_eq!(read_block(&mut view, 5), Err(IoError::OutOfRange(5)));
+
+    // past the end of the partition completes like any other request, in any order
+    let outside = view.push_read_request(8).unwrap();
+    let inside = view.push_write_request(1, [4; 4096]).unwrap();
+    assert_eq!(
+        view.poll_completion(inside),
+        Some(Completion::Write(Ok(())))
+    );
+    assert_eq!(
+        view.poll_completion(outside),
+        Some(Completion::Read(Err(IoError::OutOfRange(8))))
+    );
+    assert_eq!(view.poll_completion(inside), None);
+}
+
+#[test]
+fn test_partition_view_push_errors() {
+    // a driver that refuses past its end at push time, instead of in the completion
+    struct Strict(RamDisk);
+
+    impl BlockDriver for Strict {
+        fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+            if cluster_number >= self.0.n_blocks() as u64 {
+                return Err(IoError::OutOfRange(cluster_number));
+            }
+            self.0.push_read_request(cluster_number)
+        }
+
+        fn push_write_request(
+            &mut self,
+            cluster_number: u64,
+            block: Block,
+        ) -> Result<RequestId, IoError> {
+            if cluster_number >= self.0.n_blocks() as u64 {
+                return Err(IoError::OutOfRange(cluster_number));
+            }
+            self.0.push_write_request(cluster_number, block)
+        }
+
+        fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+            self.0.poll_completion(id)
+        }
+    }
+
+    // still in partition clusters
+    let mut view = PartitionView::new(Strict(RamDisk::new(64)), 60, 8);
+    assert_eq!(view.push_read_request(5), Err(IoError::OutOfRange(5)));
+    assert_eq!(
+        view.push_write_request(6, make_block()),
+        Err(IoError::OutOfRange(6))
+    );
+    assert_eq!(read_block(&mut view, 3), Ok(make_block()));
+}
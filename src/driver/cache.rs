@@ -0,0 +1,522 @@
+// -------------
+// CACHES
+// -------------
+
+// docs/NOTES.md: check if its already in memory before asking the device. Both caches here are bounded LRUs
+// BlockCache sits between the fs and any BlockDriver and keeps recently used clusters. Writes stay in it until the
+// block is evicted or the driver is flushed, so a commit's flush is still what makes things durable
+// A dirty block whose write back on eviction fails is kept aside, still dirty, and the next flush tries it again and
+// reports the error. The request that pushed it out went fine and isnt told
+// The inode cache is an Lru in NeFS, from inode number to leaf node, so a path walk doesnt search the skiplist
+// once per part for directories it has seen recently
+
+use super::{
+    block::{write_block, Block, BlockDriver, Completion, Completions, IoError, RequestId},
+    neutronfs::ClusterNumber,
+};
+use alloc::{boxed::Box, collections::BTreeMap};
+
+/// What a cache has been doing, to tune its capacity by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to make room
+    pub evictions: u64,
+    /// Dirty blocks written to the device, on eviction or flush
+    pub write_backs: u64,
+}
+
+/// A map of at most `capacity` entries. Inserting into a full one drops the entry used longest ago
+#[derive(Debug, Clone)]
+pub struct Lru<K, V> {
+    capacity: usize,
+    // value and the tick it was last used at
+    entries: BTreeMap<K, (V, u64)>,
+    // tick -> key, oldest first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl<K: Ord + Copy, V> Lru<K, V> {
+    /// A capacity of 0 keeps nothing
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    /// Look `key` up, counting a hit or a miss. Makes it the most recently used
+    pub fn get(&mut self, key: &K) -> Option<&mut V> {
+        self.tick += 1;
+        let Some((value, used)) = self.entries.get_mut(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+
+        self.stats.hits += 1;
+        self.order.remove(used);
+        self.order.insert(self.tick, *key);
+        *used = self.tick;
+
+        Some(value)
+    }
+
+    /// Look `key` up without counting it or moving it up
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    /// Add or replace `key`, as the most recently used. Hands back the entry evicted to make room, if any
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if self.capacity == 0 {
+            return Some((key, value));
+        }
+
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.insert(key, (value, self.tick)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(self.tick, key);
+
+        if self.entries.len() <= self.capacity {
+            return None;
+        }
+        let (_, oldest) = self.order.pop_first()?;
+        let (value, _) = self.entries.remove(&oldest)?;
+        self.stats.evictions += 1;
+
+        Some((oldest, value))
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, used) = self.entries.remove(key)?;
+        self.order.remove(&used);
+
+        Some(value)
+    }
+
+    /// Drop everything. The stats stay
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    /// Every entry by key, without moving any up
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.entries
+            .iter_mut()
+            .map(|(key, (value, _))| (key, value))
+    }
+}
+
+/// A cluster held by BlockCache
+#[derive(Debug, Clone)]
+struct CachedBlock {
+    block: Block,
+    // newer than what the device has
+    dirty: bool,
+}
+
+/// Write-back cache over another driver. Reads of cached clusters and all writes finish right away
+/// A write past the end of the device fails right away if the driver knows its size, otherwise once it is written back
+pub struct BlockCache<D: BlockDriver> {
+    driver: D,
+    blocks: Lru<ClusterNumber, CachedBlock>,
+    // dirty blocks evicted but not written back because the device failed, until a flush gets them out
+    held: BTreeMap<ClusterNumber, Block>,
+    // misses handed to the driver: our id -> its id and the cluster
+    reading: BTreeMap<RequestId, (RequestId, ClusterNumber)>,
+    completions: Completions,
+    write_backs: u64,
+}
+
+impl<D: BlockDriver> BlockCache<D> {
+    /// Keep up to `capacity` clusters of `driver` in memory
+    pub fn new(driver: D, capacity: usize) -> Self {
+        Self {
+            driver,
+            blocks: Lru::new(capacity),
+            held: BTreeMap::new(),
+            reading: BTreeMap::new(),
+            completions: Completions::new(),
+            write_backs: 0,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            write_backs: self.write_backs,
+            ..self.blocks.stats()
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.blocks.reset_stats();
+        self.write_backs = 0;
+    }
+
+    /// Clusters held right now
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Clusters written to the cache that the device doesnt have yet
+    pub fn n_dirty(&self) -> usize {
+        self.blocks
+            .entries
+            .values()
+            .filter(|(b, _)| b.dirty)
+            .count()
+            + self.held.len()
+    }
+
+    /// Write back everything dirty and give the driver back
+    pub fn into_inner(mut self) -> Result<D, IoError> {
+        self.write_back()?;
+        Ok(self.driver)
+    }
+
+    /// Cache `block`, writing back whatever it pushes out. One that cant be written is held for the next flush
+    fn insert(&mut self, cluster_number: ClusterNumber, block: CachedBlock) {
+        self.held.remove(&cluster_number);
+        let Some((evicted, old)) = self.blocks.insert(cluster_number, block) else {
+            return;
+        };
+        if !old.dirty {
+            return;
+        }
+
+        match write_block(&mut self.driver, evicted, old.block) {
+            Ok(()) => self.write_backs += 1,
+            Err(e) => {
+                log::warn!("write back of cluster {} failed: {:?}", evicted, e);
+                self.held.insert(evicted, old.block);
+            }
+        }
+    }
+
+    /// Keep what a read that missed brought back. A write that came in while it was out is newer, that stays
+    fn finish_read(&mut self, cluster_number: ClusterNumber, completion: Completion) -> Completion {
+        let Completion::Read(Ok(block)) = completion else {
+            return completion;
+        };
+        if self.blocks.peek(&cluster_number).is_some() {
+            return Completion::Read(Ok(block));
+        }
+
+        let cached = CachedBlock {
+            block: *block,
+            dirty: false,
+        };
+        self.insert(cluster_number, cached);
+
+        Completion::Read(Ok(block))
+    }
+
+    /// Write every dirty block to the device, the ones held after a failed write back first
+    fn write_back(&mut self) -> Result<(), IoError> {
+        while let Some((cluster_number, block)) = self.held.pop_first() {
+            if let Err(e) = write_block(&mut self.driver, cluster_number, block) {
+                self.held.insert(cluster_number, block);
+                return Err(e);
+            }
+            self.write_backs += 1;
+        }
+
+        for (cluster_number, cached) in self.blocks.iter_mut() {
+            if cached.dirty {
+                write_block(&mut self.driver, *cluster_number, cached.block)?;
+                cached.dirty = false;
+                self.write_backs += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: BlockDriver> BlockDriver for BlockCache<D> {
+    fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+        let cached = self.blocks.get(&cluster_number).map(|cached| cached.block);
+        if let Some(block) = cached.or_else(|| self.held.get(&cluster_number).copied()) {
+            return Ok(self
+                .completions
+                .complete_now(Completion::Read(Ok(Box::new(block)))));
+        }
+
+        let inner = self.driver.push_read_request(cluster_number)?;
+        let id = self.completions.next_id();
+        self.reading.insert(id, (inner, cluster_number));
+
+        Ok(id)
+    }
+
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<RequestId, IoError> {
+        let res = match self.driver.device_clusters() {
+            Some(n_clusters) if cluster_number >= n_clusters => {
+                Err(IoError::OutOfRange(cluster_number))
+            }
+            _ => {
+                self.insert(cluster_number, CachedBlock { block, dirty: true });
+                Ok(())
+            }
+        };
+        Ok(self.completions.complete_now(Completion::Write(res)))
+    }
+
+    fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+        let Some((inner, cluster_number)) = self.reading.get(&id).copied() else {
+            return self.completions.take(id);
+        };
+
+        let completion = self.driver.poll_completion(inner)?;
+        self.reading.remove(&id);
+        Some(self.finish_read(cluster_number, completion))
+    }
+
+    fn is_pending(&self, id: RequestId) -> bool {
+        match self.reading.get(&id) {
+            Some(&(inner, _)) => self.driver.is_pending(inner),
+            None => self.completions.is_pending(id),
+        }
+    }
+
+    fn wait(&mut self, id: RequestId) -> Completion {
+        let Some((inner, cluster_number)) = self.reading.remove(&id) else {
+            return self
+                .completions
+                .take(id)
+                .unwrap_or(Completion::Read(Err(IoError::WrongCompletion(id))));
+        };
+
+        let completion = self.driver.wait(inner);
+        self.finish_read(cluster_number, completion)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.write_back()?;
+        self.driver.flush()
+    }
+
+    fn physical_offset(&self) -> u64 {
+        self.driver.physical_offset()
+    }
+
+    fn device_clusters(&self) -> Option<u64> {
+        self.driver.device_clusters()
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_lru_evicts_oldest() {
+    let mut lru = Lru::new(2);
+    assert_eq!(lru.insert(1, "a"), None);
+    assert_eq!(lru.insert(2, "b"), None);
+
+    // 1 was used last, so 2 goes
+    assert_eq!(lru.get(&1), Some(&mut "a"));
+    assert_eq!(lru.insert(3, "c"), Some((2, "b")));
+    assert_eq!(lru.get(&2), None);
+    assert_eq!(lru.peek(&3), Some(&"c"));
+
+    // replacing isnt evicting
+    assert_eq!(lru.insert(3, "d"), None);
+    assert_eq!(lru.remove(&1), Some("a"));
+    assert_eq!(lru.len(), 1);
+    assert_eq!(
+        lru.stats(),
+        CacheStats {
+            hits: 1,
+            misses: 1,
+            evictions: 1,
+            write_backs: 0,
+        }
+    );
+
+    let mut none = Lru::new(0);
+    assert_eq!(none.insert(1, ()), Some((1, ())));
+    assert!(none.is_empty());
+}
+
+#[test]
+fn test_block_cache_write_back() {
+    use super::block::{make_block, read_block, RamDisk};
+
+    let block = |b: u8| {
+        let mut block = make_block();
+        block[0] = b;
+        block
+    };
+    let mut cache = BlockCache::new(RamDisk::new(16), 2);
+
+    // writes wait in the cache
+    write_block(&mut cache, 1, block(1)).unwrap();
+    write_block(&mut cache, 2, block(2)).unwrap();
+    assert_eq!(cache.n_dirty(), 2);
+    assert_eq!(read_block(&mut cache, 1).unwrap()[0], 1);
+    assert_eq!(cache.stats().hits, 1);
+
+    // 2 is the oldest, so it is written back to make room
+    assert_eq!(read_block(&mut cache, 5).unwrap()[0], 0);
+    assert_eq!(cache.stats().misses, 1);
+    assert_eq!(cache.stats().write_backs, 1);
+    assert_eq!(cache.n_dirty(), 1);
+
+    // the rest go on flush
+    cache.flush().unwrap();
+    assert_eq!(cache.n_dirty(), 0);
+    let mut disk = cache.into_inner().unwrap();
+    assert_eq!(read_block(&mut disk, 1).unwrap()[0], 1);
+    assert_eq!(read_block(&mut disk, 2).unwrap()[0], 2);
+
+    // past the end fails in the completion, and nothing is cached
+    let mut cache = BlockCache::new(disk, 1);
+    let id = cache.push_write_request(100, block(1)).unwrap();
+    assert_eq!(
+        cache.wait(id),
+        Completion::Write(Err(IoError::OutOfRange(100)))
+    );
+    assert_eq!(read_block(&mut cache, 100), Err(IoError::OutOfRange(100)));
+    assert!(cache.is_empty());
+    write_block(&mut cache, 3, block(3)).unwrap();
+}
+
+#[test]
+fn test_block_cache_write_back_fails() {
+    use super::block::{make_block, read_block, RamDisk};
+
+    // a device whose writes fail while `failing` is set
+    struct Flaky {
+        disk: RamDisk,
+        failing: bool,
+    }
+
+    impl BlockDriver for Flaky {
+        fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+            self.disk.push_read_request(cluster_number)
+        }
+
+        fn push_write_request(
+            &mut self,
+            cluster_number: u64,
+            block: Block,
+        ) -> Result<RequestId, IoError> {
+            if self.failing {
+                return Err(IoError::Device);
+            }
+            self.disk.push_write_request(cluster_number, block)
+        }
+
+        fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+            self.disk.poll_completion(id)
+        }
+    }
+
+    let block = |b: u8| {
+        let mut block = make_block();
+        block[0] = b;
+        block
+    };
+    let driver = Flaky {
+        disk: RamDisk::new(16),
+        failing: true,
+    };
+    let mut cache = BlockCache::new(driver, 1);
+
+    // pushing 1 out fails to write it, but the write of 2 and the read of 3 went fine
+    write_block(&mut cache, 1, block(1)).unwrap();
+    write_block(&mut cache, 2, block(2)).unwrap();
+    assert_eq!(read_block(&mut cache, 3).unwrap()[0], 0);
+    assert_eq!(cache.n_dirty(), 2);
+    assert_eq!(cache.stats().write_backs, 0);
+
+    // nothing is lost, and the flush is where it shows
+    assert_eq!(read_block(&mut cache, 1).unwrap()[0], 1);
+    assert_eq!(read_block(&mut cache, 2).unwrap()[0], 2);
+    assert_eq!(cache.flush(), Err(IoError::Device));
+    assert_eq!(cache.n_dirty(), 2);
+
+    cache.driver.failing = false;
+    cache.flush().unwrap();
+    assert_eq!(cache.n_dirty(), 0);
+    let mut disk = cache.into_inner().unwrap().disk;
+    assert_eq!(read_block(&mut disk, 1).unwrap()[0], 1);
+    assert_eq!(read_block(&mut disk, 2).unwrap()[0], 2);
+}
+
+#[test]
+fn test_block_cache_under_nefs() {
+    use super::{
+        block::RamDisk,
+        neutronfs::{mkfs, NeFS, OpenFlags},
+    };
+
+    let mut disk = RamDisk::new(128);
+    mkfs(&mut disk, 128, "cached").unwrap();
+    let mut fs = NeFS::mount(BlockCache::new(disk, 8)).unwrap();
+    fs.mkdir("/a").unwrap();
+    fs.mkdir("/a/b").unwrap();
+    fs.create("/a/b/file").unwrap();
+    fs.open("/a/b/file", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(&[7; 20_000])
+        .unwrap();
+
+    // walking the same path again finds the directories' leaves cached
+    let before = fs.inode_cache_stats();
+    fs.resolve("/a/b/file", true).unwrap();
+    let after = fs.inode_cache_stats();
+    assert_eq!(after.misses, before.misses);
+    assert!(after.hits > before.hits);
+
+    let cache = fs.unmount();
+    assert_eq!(cache.n_dirty(), 0);
+    assert!(cache.stats().hits > 0);
+
+    // everything made it through to the disk
+    let mut fs = NeFS::mount(cache.into_inner().unwrap()).unwrap();
+    let mut buf = [0; 20_000];
+    fs.open("/a/b/file", OpenFlags::NONE)
+        .unwrap()
+        .read_bytes(&mut buf, 0)
+        .unwrap();
+    assert!(buf.iter().all(|b| *b == 7));
+}
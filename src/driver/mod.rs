@@ -13,5 +13,6 @@ If "multiuser", other homes are in /home/guest/<name>
 // -------------
 
 pub mod block;
+pub mod cache;
 pub mod ram;
 pub mod neutronfs;
@@ -0,0 +1,132 @@
+// -------------
+// NODE CHECKSUMS
+// -------------
+
+// Every node starts with its NodeHeader, and the header starts with the checksum, so it is always the first
+// NODE_CHECKSUM_LEN bytes of the cluster. It covers the rest of the cluster, padding included
+// The superblock has its own CRC32C, see SuperBlock::compute_checksum
+
+use super::{ChecksumSHA1, ClusterNumber, FsError};
+use crate::driver::block::Block;
+
+pub const NODE_CHECKSUM_LEN: usize = 20;
+
+/// SHA-1 of `data`
+pub fn sha1(data: &[u8]) -> ChecksumSHA1 {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    // pad with a 1 bit, zeros, then the length in bits, to a multiple of 64 bytes
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+    let n_chunks = (data.len() + 8) / 64 + 1;
+
+    for chunk_index in 0..n_chunks {
+        let mut chunk = [0u8; 64];
+        let start = chunk_index * 64;
+        for (i, byte) in chunk.iter_mut().enumerate() {
+            let at = start + i;
+            *byte = match at.cmp(&data.len()) {
+                core::cmp::Ordering::Less => data[at],
+                core::cmp::Ordering::Equal => 0x80,
+                core::cmp::Ordering::Greater => 0,
+            };
+        }
+        if chunk_index == n_chunks - 1 {
+            chunk[56..].copy_from_slice(&bit_len.to_be_bytes());
+        }
+
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(chunk[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; NODE_CHECKSUM_LEN];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+
+    digest
+}
+
+/// Stamp an encoded node with the checksum of everything after it
+pub fn seal_node(block: &mut Block) {
+    let checksum = sha1(&block[NODE_CHECKSUM_LEN..]);
+    block[..NODE_CHECKSUM_LEN].copy_from_slice(&checksum);
+}
+
+/// Check a node read off disk before decoding it
+pub fn verify_node(block: &Block, cluster_number: ClusterNumber) -> Result<(), FsError> {
+    if block[..NODE_CHECKSUM_LEN] != sha1(&block[NODE_CHECKSUM_LEN..]) {
+        return Err(FsError::BadChecksum(cluster_number));
+    }
+
+    Ok(())
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_sha1_known_values() {
+    let hex = |digest: ChecksumSHA1| {
+        digest
+            .iter()
+            .map(|b| alloc::format!("{:02x}", b))
+            .collect::<alloc::string::String>()
+    };
+
+    assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(
+        hex(sha1(b"abc")),
+        "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    // 56 bytes, so the length spills into a second chunk
+    assert_eq!(
+        hex(sha1(
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+        )),
+        "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+    );
+}
+
+#[test]
+fn test_node_seal_verify() {
+    let mut block = crate::driver::block::make_block();
+    block[100] = 7;
+    seal_node(&mut block);
+    assert_eq!(verify_node(&block, 4), Ok(()));
+
+    // a flipped bit anywhere, padding included, is caught
+    block[4000] ^= 1;
+    assert_eq!(verify_node(&block, 4), Err(FsError::BadChecksum(4)));
+}
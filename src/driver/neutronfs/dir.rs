@@ -0,0 +1,485 @@
+// -------------
+// DIRECTORIES
+// -------------
+
+// A directory is a hash table of names to inode numbers, like docs/NOTES.md's "list of inode numbers" but with names
+// Each bucket is a chain of DirEntryNode nodes. Buckets double (up to a limit) as the directory fills up,
+// past that the chains just get longer
+// `.` and `..` are answered from the directory's own inode and parent, so every directory has them
+
+use super::{
+    crc32c, encode_cluster, DirEntry, DirEntryNode, Directory, FsError, InodeNumber, InodeRecord,
+    ItemType, LeafNode, NodeHeader, NodeNumber, Volume,
+};
+use crate::driver::block::BlockDriver;
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+/// Longest name in bytes
+pub const MAX_NAME_LEN: usize = 255;
+/// Average entries per bucket before the buckets double
+pub const DIR_ENTRIES_PER_BUCKET: u64 = 32;
+/// Most buckets a directory gets. Keeps the bucket list well inside the leaf
+pub const MAX_DIR_BUCKETS: usize = 256;
+
+/// Can `name` be stored in a directory
+pub fn validate_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(FsError::InvalidName);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+
+    Ok(())
+}
+
+/// (the node before in the chain if any, node_number of the node, the node)
+type Found = (Option<(NodeNumber, DirEntryNode)>, NodeNumber, DirEntryNode);
+
+fn empty_node() -> DirEntryNode {
+    DirEntryNode {
+        header: NodeHeader::new(0),
+        next: 0,
+        entries: Vec::new(),
+    }
+}
+
+/// An open directory. Holds the record and bucket list in memory and writes the leaf back when they change
+pub struct Dir<'fs, D: BlockDriver> {
+    volume: &'fs mut Volume<D>,
+    number: InodeNumber,
+    leaf: NodeNumber,
+    record: InodeRecord,
+    dir: Directory,
+}
+
+impl<'fs, D: BlockDriver> Dir<'fs, D> {
+    pub(super) fn new(
+        volume: &'fs mut Volume<D>,
+        number: InodeNumber,
+        leaf: NodeNumber,
+        record: InodeRecord,
+        dir: Directory,
+    ) -> Self {
+        Self {
+            volume,
+            number,
+            leaf,
+            record,
+            dir,
+        }
+    }
+
+    pub fn number(&self) -> InodeNumber {
+        self.number
+    }
+
+    pub fn record(&self) -> &InodeRecord {
+        &self.record
+    }
+
+    /// What `..` points at
+    pub fn parent(&self) -> InodeNumber {
+        self.dir.parent
+    }
+
+    /// Entries not counting `.` and `..`
+    pub fn n_entries(&self) -> u64 {
+        self.dir.n_entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dir.n_entries == 0
+    }
+
+    /// Point `..` somewhere else, for when the directory moves
+    pub fn set_parent(&mut self, parent: InodeNumber) -> Result<(), FsError> {
+        self.dir.parent = parent;
+        self.touch_modified();
+        self.write_leaf()
+    }
+
+    fn write_leaf(&mut self) -> Result<(), FsError> {
+        self.record.size = self.dir.n_entries;
+        let leaf = LeafNode::new(
+            self.number,
+            &self.record,
+            ItemType::Directory(self.dir.clone()),
+        );
+
+        self.volume.write_node(self.leaf, &leaf)
+    }
+
+    /// Entries changed, which changes the record too
+    fn touch_modified(&mut self) {
+        let now = self.volume.now();
+        self.record.modified = now;
+        self.record.changed = now;
+    }
+
+    fn bucket_of(&self, name: &str) -> NodeNumber {
+        let index = crc32c(name.as_bytes()) as usize % self.dir.buckets.len();
+        self.dir.buckets[index]
+    }
+
+    /// The node holding `name`, see Found
+    fn find(&mut self, name: &str) -> Result<Option<Found>, FsError> {
+        if self.dir.buckets.is_empty() {
+            return Ok(None);
+        }
+
+        let mut prev = None;
+        let mut node_number = self.bucket_of(name);
+        loop {
+            let node = self.volume.read_dir_node(node_number)?;
+            if node.entries.iter().any(|e| e.name == name) {
+                return Ok(Some((prev, node_number, node)));
+            }
+
+            let next = node.next;
+            if next == 0 {
+                return Ok(None);
+            }
+            prev = Some((node_number, node));
+            node_number = next;
+        }
+    }
+
+    /// Inode `name` points at
+    pub fn lookup(&mut self, name: &str) -> Result<InodeNumber, FsError> {
+        match name {
+            "." => return Ok(self.number),
+            ".." => return Ok(self.dir.parent),
+            _ => {}
+        }
+
+        let (_, _, node) = self.find(name)?.ok_or(FsError::NotFound)?;
+        let entry = node.entries.iter().find(|e| e.name == name).unwrap();
+
+        Ok(entry.inode)
+    }
+
+    /// Link `inode` in as `name`
+    pub fn insert(&mut self, name: &str, inode: InodeNumber) -> Result<(), FsError> {
+        validate_name(name)?;
+        if self.find(name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let n_buckets = self.dir.buckets.len();
+        if n_buckets == 0 {
+            self.rehash(1)?;
+        } else if self.dir.n_entries >= n_buckets as u64 * DIR_ENTRIES_PER_BUCKET
+            && n_buckets < MAX_DIR_BUCKETS
+        {
+            self.rehash(n_buckets * 2)?;
+        }
+
+        self.insert_into_bucket(DirEntry::new(name.to_string(), inode))?;
+        self.dir.n_entries += 1;
+        self.touch_modified();
+        self.write_leaf()
+    }
+
+    /// Put an entry in the first node of its chain with room, adding a node if they are all full
+    fn insert_into_bucket(&mut self, entry: DirEntry) -> Result<(), FsError> {
+        let mut entry = entry;
+        let mut node_number = self.bucket_of(&entry.name);
+
+        loop {
+            let mut node = self.volume.read_dir_node(node_number)?;
+            node.entries.push(entry);
+            match encode_cluster(&node) {
+                Ok(block) => return self.volume.write_node_block(node_number, block),
+                Err(FsError::NodeTooLarge) => entry = node.entries.pop().unwrap(),
+                Err(e) => return Err(e),
+            }
+
+            if node.next == 0 {
+                let overflow = self.volume.alloc_node()?;
+                let mut new_node = empty_node();
+                new_node.entries.push(entry);
+                self.volume.write_node(overflow, &new_node)?;
+
+                node.next = overflow;
+                return self.volume.write_node(node_number, &node);
+            }
+            node_number = node.next;
+        }
+    }
+
+    /// Every entry in `buckets` and every node_number their chains use
+    fn collect(
+        &mut self,
+        buckets: &[NodeNumber],
+    ) -> Result<(Vec<DirEntry>, Vec<NodeNumber>), FsError> {
+        let mut entries = Vec::new();
+        let mut nodes = Vec::new();
+
+        for bucket in buckets {
+            let mut node_number = *bucket;
+            while node_number != 0 {
+                let node = self.volume.read_dir_node(node_number)?;
+                nodes.push(node_number);
+                entries.extend(node.entries);
+                node_number = node.next;
+            }
+        }
+
+        Ok((entries, nodes))
+    }
+
+    /// Move every entry into `n_buckets` new buckets. The old ones are only freed once the leaf points at the new ones
+    fn rehash(&mut self, n_buckets: usize) -> Result<(), FsError> {
+        let old_buckets = self.dir.buckets.clone();
+        let (entries, old_nodes) = self.collect(&old_buckets)?;
+
+        let mut buckets = Vec::with_capacity(n_buckets);
+        for _ in 0..n_buckets {
+            match self.volume.alloc_node() {
+                Ok(c) => buckets.push(c),
+                Err(e) => {
+                    for c in buckets {
+                        self.volume.free_node(c)?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        for node_number in &buckets {
+            self.volume.write_node(*node_number, &empty_node())?;
+        }
+
+        self.dir.buckets = buckets;
+        for entry in entries {
+            if let Err(e) = self.insert_into_bucket(entry) {
+                // still have the old table, go back to it
+                let new_buckets = core::mem::replace(&mut self.dir.buckets, old_buckets);
+                let (_, new_nodes) = self.collect(&new_buckets)?;
+                for c in new_nodes {
+                    self.volume.free_node(c)?;
+                }
+                return Err(e);
+            }
+        }
+        self.write_leaf()?;
+
+        for node_number in old_nodes {
+            self.volume.free_node(node_number)?;
+        }
+
+        Ok(())
+    }
+
+    /// Unlink `name`. Returns the inode it pointed at
+    pub fn remove(&mut self, name: &str) -> Result<InodeNumber, FsError> {
+        validate_name(name)?;
+        let (prev, node_number, mut node) = self.find(name)?.ok_or(FsError::NotFound)?;
+
+        let index = node.entries.iter().position(|e| e.name == name).unwrap();
+        let entry = node.entries.remove(index);
+
+        match prev {
+            // an emptied overflow node comes out of the chain
+            Some((prev_number, mut prev_node)) if node.entries.is_empty() => {
+                prev_node.next = node.next;
+                self.volume.write_node(prev_number, &prev_node)?;
+                self.volume.free_node(node_number)?;
+            }
+            _ => self.volume.write_node(node_number, &node)?,
+        }
+
+        self.dir.n_entries -= 1;
+        self.touch_modified();
+        if self.dir.n_entries == 0 {
+            // an empty directory doesnt hold on to any nodes
+            self.free_buckets()?;
+        } else {
+            self.write_leaf()?;
+        }
+
+        Ok(entry.inode)
+    }
+
+    /// Give `old` the name `new`. Fails if `new` is taken
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), FsError> {
+        validate_name(old)?;
+        validate_name(new)?;
+        let inode = self.lookup(old)?;
+        if old == new {
+            return Ok(());
+        }
+
+        // new name first, so a failure leaves the old one in place
+        self.insert(new, inode)?;
+        self.remove(old)?;
+
+        Ok(())
+    }
+
+    /// Free every bucket, dropping all entries
+    pub(super) fn free_buckets(&mut self) -> Result<(), FsError> {
+        let buckets = core::mem::take(&mut self.dir.buckets);
+        let (_, nodes) = self.collect(&buckets)?;
+
+        self.dir.n_entries = 0;
+        self.write_leaf()?;
+        for node_number in nodes {
+            self.volume.free_node(node_number)?;
+        }
+
+        Ok(())
+    }
+
+    /// `.`, `..`, then every entry in no particular order
+    pub fn entries(&mut self) -> DirIter<'_, D> {
+        DirIter {
+            volume: self.volume,
+            buckets: self.dir.buckets.clone(),
+            bucket: 0,
+            next: 0,
+            pending: vec![
+                DirEntry::new(String::from(".."), self.dir.parent),
+                DirEntry::new(String::from("."), self.number),
+            ],
+        }
+    }
+}
+
+/// Walks a directory's buckets one node at a time
+pub struct DirIter<'d, D: BlockDriver> {
+    volume: &'d mut Volume<D>,
+    buckets: Vec<NodeNumber>,
+    // next bucket to start on
+    bucket: usize,
+    // next node in the current chain, 0 to move on to the next bucket
+    next: NodeNumber,
+    // entries of the last node read, popped off the back
+    pending: Vec<DirEntry>,
+}
+
+impl<'d, D: BlockDriver> Iterator for DirIter<'d, D> {
+    type Item = Result<DirEntry, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.pending.pop() {
+                return Some(Ok(entry));
+            }
+
+            if self.next == 0 {
+                self.next = *self.buckets.get(self.bucket)?;
+                self.bucket += 1;
+            }
+
+            match self.volume.read_dir_node(self.next) {
+                Ok(node) => {
+                    self.next = node.next;
+                    self.pending = node.entries;
+                }
+                Err(e) => {
+                    // dont keep walking a broken directory
+                    self.bucket = self.buckets.len();
+                    self.next = 0;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_dir_insert_lookup_remove() {
+    let mut fs = super::test_fs(256);
+    let root = fs.create_dir(1).unwrap();
+    let file = fs.create_inode().unwrap();
+
+    let mut dir = fs.dir(root).unwrap();
+    assert_eq!(dir.lookup("."), Ok(root));
+    assert_eq!(dir.lookup(".."), Ok(1));
+    assert_eq!(dir.lookup("a"), Err(FsError::NotFound));
+
+    dir.insert("a", file).unwrap();
+    assert_eq!(dir.lookup("a"), Ok(file));
+    assert_eq!(dir.insert("a", file), Err(FsError::AlreadyExists));
+    assert_eq!(dir.n_entries(), 1);
+
+    assert_eq!(dir.remove("a"), Ok(file));
+    assert_eq!(dir.remove("a"), Err(FsError::NotFound));
+    assert!(dir.is_empty());
+
+    // wrong kind of inode either way
+    assert_eq!(fs.inode(root).err(), Some(FsError::IsADirectory));
+    assert_eq!(fs.dir(file).err(), Some(FsError::NotADirectory));
+}
+
+#[test]
+fn test_dir_names() {
+    let mut fs = super::test_fs(64);
+    let root = fs.create_dir(1).unwrap();
+    let mut dir = fs.dir(root).unwrap();
+
+    for name in ["", ".", "..", "a/b", "nul\0"] {
+        assert_eq!(dir.insert(name, 5), Err(FsError::InvalidName));
+    }
+    assert_eq!(dir.remove("."), Err(FsError::InvalidName));
+
+    let long = String::from_utf8(vec![b'x'; MAX_NAME_LEN + 1]).unwrap();
+    assert_eq!(dir.insert(&long, 5), Err(FsError::NameTooLong));
+    dir.insert(&long[1..], 5).unwrap();
+
+    dir.rename(&long[1..], "short").unwrap();
+    assert_eq!(dir.lookup("short"), Ok(5));
+    assert_eq!(dir.lookup(&long[1..]), Err(FsError::NotFound));
+
+    dir.insert("other", 6).unwrap();
+    assert_eq!(dir.rename("short", "other"), Err(FsError::AlreadyExists));
+    assert_eq!(dir.rename("gone", "new"), Err(FsError::NotFound));
+}
+
+#[test]
+fn test_dir_large() {
+    use alloc::format;
+
+    let mut fs = super::test_fs(2048);
+    let root = fs.create_dir(1).unwrap();
+    fs.commit().unwrap();
+    let used_before = fs.superblock().n_sectors_used();
+
+    // long names so buckets overflow into chains as well as doubling
+    let name = |i: u64| format!("{:0>200}", i);
+    let mut dir = fs.dir(root).unwrap();
+    for i in 0..3000 {
+        dir.insert(&name(i), i + 100).unwrap();
+    }
+    assert_eq!(dir.n_entries(), 3000);
+
+    // still all there after a remount
+    let mut fs = super::NeFS::mount(fs.unmount()).unwrap();
+    let mut dir = fs.dir(root).unwrap();
+    for i in (0..3000).step_by(7) {
+        assert_eq!(dir.lookup(&name(i)), Ok(i + 100));
+    }
+
+    let mut seen: Vec<InodeNumber> = dir.entries().map(|e| e.unwrap().inode()).collect();
+    assert_eq!(seen.len(), 3002);
+    seen.sort();
+    assert_eq!(seen[..2], [1, root]);
+    assert_eq!(seen[2..], (100..3100).collect::<Vec<_>>());
+
+    for i in 0..3000 {
+        assert_eq!(dir.remove(&name(i)), Ok(i + 100));
+    }
+    assert!(dir.is_empty());
+    // every bucket and overflow node went back
+    fs.commit().unwrap();
+    assert_eq!(fs.superblock().n_sectors_used(), used_before);
+}
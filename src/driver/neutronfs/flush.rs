@@ -0,0 +1,412 @@
+// -------------
+// FLUSH POLICY
+// -------------
+
+// Changes only survive a crash once they are committed. Committing after every change is the safest and the slowest,
+// so the fs asks its FlushPolicy after each one whether it is time. Triggers combine with |, whichever fires first wins:
+// creating, deleting or moving a file, the oldest change waiting long enough, or too many changes waiting
+// The clock is only looked at when something changes or the caller ticks the fs, nothing commits by itself while it
+// sits idle
+// A commit the policy asks for doesnt fail the change that set it off, which is already made. The change waits for the
+// next commit like any other, and the error is kept until a commit works, see NeFS::commit_error
+// A read that only moves an access time isnt a change unless the policy keeps access times, it goes with the next commit
+
+use super::{FsError, InodeNumber, LeafNode, NeFS, NodeNumber, Volume};
+use crate::driver::block::BlockDriver;
+
+/// What kind of change was just made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// File contents
+    Data,
+    /// Names, links and attributes. A file was created, removed, moved or chmodded
+    Metadata,
+}
+
+/// When the fs commits on its own. The default is never, only on sync, fsync and unmount
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlushPolicy {
+    immediate: bool,
+    on_metadata: bool,
+    // commit when the oldest change waiting is this many seconds old
+    interval_secs: Option<u64>,
+    // commit when this many changes are waiting
+    max_pending: Option<u64>,
+    // an access time update counts as a metadata change
+    access_times: bool,
+}
+
+impl FlushPolicy {
+    /// Only commit when asked to
+    pub const MANUAL: FlushPolicy = FlushPolicy {
+        immediate: false,
+        on_metadata: false,
+        interval_secs: None,
+        max_pending: None,
+        access_times: false,
+    };
+    /// Commit after every change
+    pub const IMMEDIATE: FlushPolicy = FlushPolicy {
+        immediate: true,
+        ..FlushPolicy::MANUAL
+    };
+    /// Commit after a metadata change, file contents wait for the next one
+    pub const ON_METADATA: FlushPolicy = FlushPolicy {
+        on_metadata: true,
+        ..FlushPolicy::MANUAL
+    };
+    /// Keep access times: a read that moves one is a metadata change. Without it they only go with other changes
+    pub const ACCESS_TIMES: FlushPolicy = FlushPolicy {
+        access_times: true,
+        ..FlushPolicy::MANUAL
+    };
+
+    /// Commit once the oldest change waiting is `secs` old, checked when the next one is made
+    pub const fn timed(secs: u64) -> FlushPolicy {
+        FlushPolicy {
+            interval_secs: Some(secs),
+            ..FlushPolicy::MANUAL
+        }
+    }
+
+    /// Commit once `n` changes are waiting
+    pub const fn queue_depth(n: u64) -> FlushPolicy {
+        FlushPolicy {
+            max_pending: Some(n),
+            ..FlushPolicy::MANUAL
+        }
+    }
+
+    /// Whether `change` should be committed now, with `n_pending` changes waiting (it included) and the oldest of
+    /// them made `elapsed_secs` ago
+    pub fn is_due(&self, change: Change, n_pending: u64, elapsed_secs: u64) -> bool {
+        self.immediate
+            || (self.on_metadata && change == Change::Metadata)
+            || self.is_overdue(elapsed_secs)
+            || self.max_pending.is_some_and(|n| n_pending >= n)
+    }
+
+    /// Whether changes waiting since `elapsed_secs` ago should be committed, with or without a new one
+    pub fn is_overdue(&self, elapsed_secs: u64) -> bool {
+        self.interval_secs.is_some_and(|s| elapsed_secs >= s)
+    }
+}
+
+/// Fires when either would. Two intervals or depths keep the smaller one
+impl core::ops::BitOr for FlushPolicy {
+    type Output = FlushPolicy;
+
+    fn bitor(self, rhs: FlushPolicy) -> FlushPolicy {
+        let min = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            _ => a.or(b),
+        };
+
+        FlushPolicy {
+            immediate: self.immediate || rhs.immediate,
+            on_metadata: self.on_metadata || rhs.on_metadata,
+            interval_secs: min(self.interval_secs, rhs.interval_secs),
+            max_pending: min(self.max_pending, rhs.max_pending),
+            access_times: self.access_times || rhs.access_times,
+        }
+    }
+}
+
+impl<D: BlockDriver> Volume<D> {
+    /// Count a change and commit if the policy says so. Called once the change is fully made, so a commit that fails
+    /// leaves it waiting and goes in commit_error
+    pub fn changed(&mut self, change: Change) {
+        self.atime_only = false;
+        let now = self.now();
+        if self.n_pending == 0 {
+            self.pending_since = now;
+        }
+        self.n_pending += 1;
+
+        if self
+            .flush_policy
+            .is_due(change, self.n_pending, self.pending_secs())
+        {
+            if let Err(e) = self.commit() {
+                log::error!("commit for the flush policy failed: {}", e.as_str());
+                self.commit_error = Some(e);
+            }
+        }
+    }
+
+    /// How long the oldest change waiting has been
+    fn pending_secs(&self) -> u64 {
+        self.now()
+            .secs
+            .saturating_sub(self.pending_since.secs)
+            .max(0) as u64
+    }
+
+    /// Write a leaf whose only change is an access time. Unless the policy keeps those, it isnt a change the policy
+    /// counts, but the next commit takes it all the same
+    pub fn write_access_time(
+        &mut self,
+        number: NodeNumber,
+        leaf: &LeafNode,
+    ) -> Result<(), FsError> {
+        let atime_only = !self.dirty || self.atime_only;
+        self.write_node(number, leaf)?;
+        if !self.flush_policy.access_times {
+            self.atime_only = atime_only;
+            return Ok(());
+        }
+
+        self.changed(Change::Metadata);
+
+        Ok(())
+    }
+}
+
+impl<D: BlockDriver> NeFS<D> {
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.volume.flush_policy
+    }
+
+    /// When to commit from now on. Doesnt commit what is already waiting, see sync
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.volume.flush_policy = policy;
+    }
+
+    /// Changes since the last commit
+    pub fn n_pending(&self) -> u64 {
+        self.volume.n_pending
+    }
+
+    /// Why the last commit the policy asked for failed, until a commit works. The changes are still waiting
+    pub fn commit_error(&self) -> Option<FsError> {
+        self.volume.commit_error
+    }
+
+    /// Commit if the changes waiting are older than the policy's interval. A timed policy only notices when the next
+    /// change is made, so call this now and then to have it commit while the fs is idle. True if it committed
+    pub fn tick(&mut self) -> Result<bool, FsError> {
+        let volume = &mut self.volume;
+        if volume.atime_only
+            || volume.n_pending == 0
+            || !volume.flush_policy.is_overdue(volume.pending_secs())
+        {
+            return Ok(false);
+        }
+        volume.commit()?;
+
+        Ok(true)
+    }
+
+    /// Commit everything waiting and make sure the device has it
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        self.volume.commit()?;
+        self.volume.driver.flush()?;
+
+        Ok(())
+    }
+
+    /// Make inode `number` survive a crash. A commit always covers the whole tree, so this is a sync
+    pub fn fsync(&mut self, number: InodeNumber) -> Result<(), FsError> {
+        self.record(number)?;
+        self.sync()
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_flush_policy_due() {
+    let policy = FlushPolicy::ON_METADATA | FlushPolicy::timed(30) | FlushPolicy::queue_depth(8);
+    assert!(!FlushPolicy::MANUAL.is_due(Change::Metadata, 1000, 1000));
+    assert!(FlushPolicy::IMMEDIATE.is_due(Change::Data, 1, 0));
+    assert!(policy.is_due(Change::Metadata, 1, 0));
+    assert!(!policy.is_due(Change::Data, 7, 29));
+    assert!(policy.is_due(Change::Data, 8, 0));
+    assert!(policy.is_due(Change::Data, 1, 30));
+
+    // the tighter bound wins
+    let tight = FlushPolicy::queue_depth(8) | FlushPolicy::queue_depth(2);
+    assert!(tight.is_due(Change::Data, 2, 0));
+    assert_eq!(FlushPolicy::default(), FlushPolicy::MANUAL);
+}
+
+#[test]
+fn test_flush_policy_commits() {
+    use super::{test_fs, OpenFlags, Timestamp};
+    use core::sync::atomic::{AtomicI64, Ordering};
+
+    static NOW: AtomicI64 = AtomicI64::new(100);
+    let mut fs = test_fs(128);
+    fs.set_clock(|| Timestamp::new(NOW.load(Ordering::Relaxed), 0));
+    let generation = |fs: &NeFS<_>| fs.superblock().generation();
+
+    // nothing by default
+    let start = generation(&fs);
+    fs.create("/a").unwrap();
+    assert_eq!(generation(&fs), start);
+    assert_eq!(fs.n_pending(), 1);
+    fs.sync().unwrap();
+    assert_eq!(generation(&fs), start + 1);
+    assert_eq!(fs.n_pending(), 0);
+
+    // data waits for the next metadata change
+    fs.set_flush_policy(FlushPolicy::ON_METADATA);
+    fs.open("/a", OpenFlags::NONE)
+        .unwrap()
+        .write_bytes(b"data", 0)
+        .unwrap();
+    assert_eq!(generation(&fs), start + 1);
+    fs.rename("/a", "/b").unwrap();
+    assert_eq!(generation(&fs), start + 2);
+
+    fs.set_flush_policy(FlushPolicy::IMMEDIATE);
+    fs.chmod("/b", 0o600).unwrap();
+    assert_eq!(generation(&fs), start + 3);
+
+    // every third change
+    fs.set_flush_policy(FlushPolicy::queue_depth(3));
+    fs.mkdir("/d").unwrap();
+    fs.symlink("/b", "/d/link").unwrap();
+    assert_eq!(generation(&fs), start + 3);
+    fs.unlink("/d/link").unwrap();
+    assert_eq!(generation(&fs), start + 4);
+
+    // a change once the oldest waiting one is old enough commits them all
+    fs.set_flush_policy(FlushPolicy::timed(30));
+    NOW.store(110, Ordering::Relaxed);
+    fs.rmdir("/d").unwrap();
+    assert_eq!(generation(&fs), start + 4);
+    NOW.store(140, Ordering::Relaxed);
+    fs.create("/c").unwrap();
+    assert_eq!(generation(&fs), start + 5);
+    assert_eq!(fs.resolve("/d", true), Err(FsError::NotFound));
+
+    // fsync needs an inode that is there
+    fs.set_flush_policy(FlushPolicy::MANUAL);
+    let c = fs.resolve("/c", true).unwrap();
+    fs.unlink("/c").unwrap();
+    assert_eq!(fs.fsync(c), Err(FsError::NotFound));
+    let b = fs.resolve("/b", true).unwrap();
+    fs.fsync(b).unwrap();
+    assert_eq!(generation(&fs), start + 6);
+
+    let mut fs = NeFS::mount(fs.unmount()).unwrap();
+    let mut buf = [0; 4];
+    fs.open("/b", OpenFlags::NONE)
+        .unwrap()
+        .read_bytes(&mut buf, 0)
+        .unwrap();
+    assert_eq!(&buf, b"data");
+}
+
+#[test]
+fn test_flush_policy_tick() {
+    use super::{test_fs, Timestamp};
+    use core::sync::atomic::{AtomicI64, Ordering};
+
+    static NOW: AtomicI64 = AtomicI64::new(100);
+    let mut fs = test_fs(128);
+    fs.set_clock(|| Timestamp::new(NOW.load(Ordering::Relaxed), 0));
+    fs.set_flush_policy(FlushPolicy::timed(30));
+    let start = fs.superblock().generation();
+
+    // nothing waiting, nothing to do
+    assert_eq!(fs.tick(), Ok(false));
+    fs.create("/a").unwrap();
+    NOW.store(129, Ordering::Relaxed);
+    assert_eq!(fs.tick(), Ok(false));
+    assert_eq!(fs.superblock().generation(), start);
+
+    // old enough without another change
+    NOW.store(130, Ordering::Relaxed);
+    assert_eq!(fs.tick(), Ok(true));
+    assert_eq!(fs.superblock().generation(), start + 1);
+    assert_eq!(fs.n_pending(), 0);
+    assert_eq!(fs.tick(), Ok(false));
+
+    // only a timed policy ticks
+    fs.set_flush_policy(FlushPolicy::queue_depth(8));
+    fs.create("/b").unwrap();
+    NOW.store(1_000, Ordering::Relaxed);
+    assert_eq!(fs.tick(), Ok(false));
+    assert_eq!(fs.superblock().generation(), start + 1);
+}
+
+/// Fails every flush while `fail` is set
+#[cfg(test)]
+struct FailFlush {
+    disk: crate::driver::block::RamDisk,
+    fail: bool,
+}
+
+#[cfg(test)]
+impl BlockDriver for FailFlush {
+    fn push_read_request(
+        &mut self,
+        cluster_number: u64,
+    ) -> Result<crate::driver::block::RequestId, crate::driver::block::IoError> {
+        self.disk.push_read_request(cluster_number)
+    }
+
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: crate::driver::block::Block,
+    ) -> Result<crate::driver::block::RequestId, crate::driver::block::IoError> {
+        self.disk.push_write_request(cluster_number, block)
+    }
+
+    fn poll_completion(
+        &mut self,
+        id: crate::driver::block::RequestId,
+    ) -> Option<crate::driver::block::Completion> {
+        self.disk.poll_completion(id)
+    }
+
+    fn flush(&mut self) -> Result<(), crate::driver::block::IoError> {
+        if self.fail {
+            return Err(crate::driver::block::IoError::Device);
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_flush_policy_commit_fails() {
+    use super::{mkfs, OpenFlags};
+    use crate::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(128);
+    mkfs(&mut disk, 128, "rootfs").unwrap();
+    let driver = FailFlush { disk, fail: true };
+    let mut fs = NeFS::mount(driver).unwrap();
+    fs.set_flush_policy(FlushPolicy::IMMEDIATE);
+    let start = fs.superblock().generation();
+
+    // the writes are made, only the commits after them fail
+    fs.create("/a").unwrap();
+    assert_eq!(
+        fs.open("/a", OpenFlags::NONE)
+            .unwrap()
+            .write_bytes(b"data", 0),
+        Ok(4)
+    );
+    assert!(fs.commit_error().is_some());
+    assert_eq!(fs.superblock().generation(), start);
+    assert_eq!(fs.n_pending(), 2);
+
+    // the next commit that works takes them
+    fs.volume.driver.fail = false;
+    fs.sync().unwrap();
+    assert_eq!(fs.commit_error(), None);
+    let mut fs = NeFS::mount(fs.unmount().disk).unwrap();
+    let mut buf = [0; 4];
+    fs.open("/a", OpenFlags::NONE)
+        .unwrap()
+        .read_bytes(&mut buf, 0)
+        .unwrap();
+    assert_eq!(&buf, b"data");
+}
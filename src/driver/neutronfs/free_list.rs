@@ -0,0 +1,297 @@
+// -------------
+// FREE LIST
+// -------------
+
+// Free clusters as runs (DataNodes), most recently freed at the front. Allocation takes from the front, so it is LIFO
+// On disk it is a chain of FreeClusterNodes starting at the superblock's free_cluster_list_addr
+// The chain's own clusters come out of the free runs, so they count as used
+
+use super::{
+    checksum::verify_node, encode_cluster, ClusterNumber, DataNode, FreeClusterNode, FsError,
+    MountError, NodeHeader, BINCODE_CONFIG,
+};
+use crate::driver::block::{read_block, write_block, BlockDriver};
+use alloc::{vec, vec::Vec};
+
+/// How many runs go in one cluster of the list. A run is at most ~20 bytes encoded, so this leaves plenty of room
+pub const FREE_RUNS_PER_NODE: usize = 160;
+
+/// Nodes it takes to store `n_runs`
+fn needed(n_runs: usize) -> usize {
+    core::cmp::max(1, n_runs.div_ceil(FREE_RUNS_PER_NODE))
+}
+
+/// The free list, loaded into memory
+#[derive(Debug, Clone)]
+pub struct FreeList {
+    // clusters the list itself is stored in, first is the superblock's free_cluster_list_addr
+    chain: Vec<ClusterNumber>,
+    runs: Vec<DataNode>,
+}
+
+impl FreeList {
+    /// A list where every cluster from `first_free` to `n_clusters` is free, stored at `addr`
+    pub fn new(addr: ClusterNumber, first_free: ClusterNumber, n_clusters: u64) -> Self {
+        let runs = if first_free < n_clusters {
+            vec![DataNode::new(n_clusters - first_free, first_free)]
+        } else {
+            Vec::new()
+        };
+
+        Self {
+            chain: vec![addr],
+            runs,
+        }
+    }
+
+    /// Follow the chain from `addr`
+    pub fn load(driver: &mut impl BlockDriver, addr: ClusterNumber) -> Result<Self, MountError> {
+        let mut chain = Vec::new();
+        let mut runs = Vec::new();
+
+        let mut next = addr;
+        while next != 0 {
+            // a loop in the chain would never end
+            if chain.contains(&next) {
+                return Err(MountError::BadStructure(next));
+            }
+
+            let block = read_block(driver, next)?;
+            verify_node(&block, next).map_err(|_| MountError::BadNodeChecksum(next))?;
+            let (node, _): (FreeClusterNode, usize) =
+                bincode::decode_from_slice(&block, BINCODE_CONFIG)
+                    .map_err(|_| MountError::BadStructure(next))?;
+
+            chain.push(next);
+            runs.extend(node.free_runs);
+            next = node.next;
+        }
+
+        Ok(Self { chain, runs })
+    }
+
+    /// Total free clusters
+    pub fn n_free(&self) -> u64 {
+        self.runs.iter().map(|r| r.clusters_used).sum()
+    }
+
+    pub fn runs(&self) -> &[DataNode] {
+        &self.runs
+    }
+
+    /// Number of clusters the list takes up on disk
+    pub fn n_chain_clusters(&self) -> u64 {
+        self.chain.len() as u64
+    }
+
+    /// Where the list starts on disk
+    pub fn addr(&self) -> ClusterNumber {
+        self.chain[0]
+    }
+
+    /// Up to `n` contiguous clusters. Takes the first run (most recently freed) that fits all `n`,
+    /// otherwise the largest run there is, so the caller has to come back for the rest
+    pub fn alloc(&mut self, n: u64) -> Option<DataNode> {
+        if n == 0 {
+            return None;
+        }
+
+        let index = match self.runs.iter().position(|r| r.clusters_used >= n) {
+            Some(i) => i,
+            None => {
+                let (i, _) = self
+                    .runs
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, r)| r.clusters_used)?;
+                i
+            }
+        };
+
+        let run = &mut self.runs[index];
+        let taken = DataNode::new(
+            core::cmp::min(n, run.clusters_used),
+            run.cluster_start_number,
+        );
+        run.cluster_start_number += taken.clusters_used;
+        run.clusters_used -= taken.clusters_used;
+        if run.clusters_used == 0 {
+            self.runs.remove(index);
+        }
+
+        Some(taken)
+    }
+
+    /// Push a run to the front. Merges with a run it touches so the list doesnt fragment forever
+    pub fn free(&mut self, data_node: DataNode) {
+        let mut freed = data_node;
+
+        while let Some(i) = self.runs.iter().position(|r| {
+            r.cluster_start_number + r.clusters_used == freed.cluster_start_number
+                || freed.cluster_start_number + freed.clusters_used == r.cluster_start_number
+        }) {
+            let neighbour = self.runs.remove(i);
+            freed = DataNode::new(
+                freed.clusters_used + neighbour.clusters_used,
+                core::cmp::min(freed.cluster_start_number, neighbour.cluster_start_number),
+            );
+        }
+
+        self.runs.insert(0, freed);
+    }
+
+    /// A copy of the list with `released` freed, written to a new chain. Nothing the current chain or `released` is in
+    /// gets overwritten, so the list on disk as of the last commit stays intact until the superblock moves on
+    pub fn relocated(
+        &self,
+        driver: &mut impl BlockDriver,
+        released: &[DataNode],
+    ) -> Result<FreeList, FsError> {
+        let mut next = self.clone();
+        let old_chain = core::mem::take(&mut next.chain);
+
+        // freeing adds at most a run each, so this many nodes is enough for the list after it
+        let n_runs = next.runs.len() + released.len() + old_chain.len();
+        for _ in 0..needed(n_runs) {
+            let cluster = next.alloc(1).ok_or(FsError::NoSpace)?;
+            next.chain.push(cluster.cluster_start_number);
+        }
+
+        for run in released {
+            next.free(*run);
+        }
+        for cluster in old_chain {
+            next.free(DataNode::new(1, cluster));
+        }
+        // merging usually leaves far fewer runs than that. The extra nodes are new, they can go straight back
+        while next.chain.len() > 1 && next.chain.len() > needed(next.runs.len() + 1) {
+            let spare = next.chain.pop().unwrap();
+            next.free(DataNode::new(1, spare));
+        }
+
+        let mut chunks = next.runs.chunks(FREE_RUNS_PER_NODE);
+        for (i, cluster_number) in next.chain.iter().enumerate() {
+            let node = FreeClusterNode {
+                header: NodeHeader::new(0),
+                next: next.chain.get(i + 1).copied().unwrap_or(0),
+                free_runs: chunks.next().map(|c| c.to_vec()).unwrap_or_default(),
+            };
+            write_block(driver, *cluster_number, encode_cluster(&node)?)?;
+        }
+
+        Ok(next)
+    }
+
+    /// Clusters `relocated` takes at most for the new chain
+    pub fn n_relocate_clusters(&self, n_released: usize) -> u64 {
+        needed(self.runs.len() + n_released + self.chain.len()) as u64
+    }
+
+    /// Resize the chain to fit the runs and write every node of it. Clusters for the chain come from the list itself
+    pub fn store(&mut self, driver: &mut impl BlockDriver) -> Result<(), FsError> {
+        while self.chain.len() < needed(self.runs.len()) {
+            let extra = self.alloc(1).ok_or(FsError::NoSpace)?;
+            self.chain.push(extra.cluster_start_number);
+        }
+        // keep a spare node around so a list that hovers on a boundary doesnt keep resizing
+        while self.chain.len() > needed(self.runs.len()) + 1 {
+            let spare = self.chain.pop().unwrap();
+            self.free(DataNode::new(1, spare));
+        }
+
+        let mut chunks = self.runs.chunks(FREE_RUNS_PER_NODE);
+        for (i, cluster_number) in self.chain.iter().enumerate() {
+            let node = FreeClusterNode {
+                header: NodeHeader::new(0),
+                next: self.chain.get(i + 1).copied().unwrap_or(0),
+                free_runs: chunks.next().map(|c| c.to_vec()).unwrap_or_default(),
+            };
+            write_block(driver, *cluster_number, encode_cluster(&node)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_free_list_lifo() {
+    let mut list = FreeList::new(2, 3, 100);
+    assert_eq!(list.n_free(), 97);
+
+    // contiguous when it can
+    assert_eq!(list.alloc(10), Some(DataNode::new(10, 3)));
+    assert_eq!(list.alloc(5), Some(DataNode::new(5, 13)));
+
+    // most recently freed goes first
+    list.free(DataNode::new(2, 3));
+    assert_eq!(list.alloc(1), Some(DataNode::new(1, 3)));
+    // doesnt fit in the freed run anymore, falls through to the tail
+    assert_eq!(list.alloc(3), Some(DataNode::new(3, 18)));
+}
+
+#[test]
+fn test_free_list_partial_and_merge() {
+    let mut list = FreeList::new(2, 3, 13);
+    let a = list.alloc(4).unwrap();
+    let b = list.alloc(4).unwrap();
+    assert_eq!(list.n_free(), 2);
+
+    // only 2 left together, get what there is
+    assert_eq!(list.alloc(5), Some(DataNode::new(2, 11)));
+    assert_eq!(list.alloc(1), None);
+
+    // neighbours merge back into one run
+    list.free(a);
+    list.free(b);
+    assert_eq!(list.runs(), &[DataNode::new(8, 3)]);
+}
+
+#[test]
+fn test_free_list_store_load() {
+    use crate::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(2048);
+    let mut list = FreeList::new(2, 3, 2048);
+
+    // fragment it past one node's worth of runs
+    let runs: Vec<DataNode> = (0..400).map(|_| list.alloc(2).unwrap()).collect();
+    for run in runs.iter().step_by(2) {
+        list.free(*run);
+    }
+    list.store(&mut disk).unwrap();
+    assert!(list.n_chain_clusters() > 1);
+
+    let loaded = FreeList::load(&mut disk, 2).unwrap();
+    assert_eq!(loaded.runs(), list.runs());
+    assert_eq!(loaded.n_chain_clusters(), list.n_chain_clusters());
+}
+
+#[test]
+fn test_free_list_relocated() {
+    use crate::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(64);
+    let mut list = FreeList::new(2, 3, 64);
+    list.store(&mut disk).unwrap();
+    let taken = list.alloc(4).unwrap();
+
+    let next = list.relocated(&mut disk, &[taken]).unwrap();
+    // new chain came out of what was free, not the old chain or what was released
+    assert_ne!(next.addr(), 2);
+    assert_ne!(next.addr(), taken.cluster_start_number());
+    assert_eq!(next.n_free(), list.n_free() + 4 + 1 - 1);
+
+    // the old list is still on disk as it was
+    assert_eq!(
+        FreeList::load(&mut disk, 2).unwrap().runs(),
+        FreeList::new(2, 3, 64).runs()
+    );
+    assert_eq!(
+        FreeList::load(&mut disk, next.addr()).unwrap().runs(),
+        next.runs()
+    );
+}
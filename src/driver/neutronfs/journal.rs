@@ -0,0 +1,576 @@
+// -------------
+// JOURNAL
+// -------------
+
+// Copy on write moves a node, its node map chunk and the map root for every change, which adds up for workloads that
+// keep rewriting a few nodes, like small writes to a big file. A fs made with a journal (MkfsOptions) writes a node the
+// last commit has over itself instead, unless a snapshot or a clone shares it
+// Those writes wait in memory until the commit. It stores the node map and other tables copy on write as usual, then logs
+// a transaction to the journal region: a JournalHeader naming each entry's target with its CRC32C, the entries, and a
+// JournalCommit. Only once that is down do the entries go where they belong, the new superblock last
+// A crash before the commit record leaves the last commit. After it, mount finds a whole transaction one generation
+// past the superblock and replays it. Once that superblock is down the transaction is done and never replayed again
+// A transaction has to fit the region, so once it is full more nodes are copied on write until the commit
+// Writing in place costs the older superblock slot as a fallback, the generation it has points at nodes a later commit
+// wrote over. So mount only takes the older slot when the journal shows nothing after it wrote in place: the header is
+// for that generation or the one after, which replay finishes. mkfs logs an empty transaction, so there always is one
+
+use super::{
+    checksum::verify_node, crc32c, encode_cluster, ClusterNumber, FsError, JournalCommit,
+    JournalHeader, MountError, NodeHeader, SuperBlock, BINCODE_CONFIG,
+};
+use crate::driver::block::{read_block, write_block, Block, BlockDriver, IoError};
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// Most entries one transaction has, so its header fits a cluster. A target and its checksum encode to 14 bytes at most
+pub const MAX_JOURNAL_ENTRIES: usize = 200;
+
+/// A header, a node, the superblock and a commit record
+pub const MIN_JOURNAL_CLUSTERS: u64 = 4;
+
+/// The journal region and the node writes waiting for the next commit
+#[derive(Debug, Clone)]
+pub struct Journal {
+    addr: ClusterNumber,
+    n_clusters: u64,
+    // cluster -> what goes in it, once logged
+    staged: BTreeMap<ClusterNumber, Block>,
+}
+
+impl Journal {
+    /// The journal `superblock` was made with, if it has one
+    pub fn of(superblock: &SuperBlock) -> Option<Self> {
+        if superblock.journal_clusters == 0 {
+            return None;
+        }
+
+        Some(Self {
+            addr: superblock.journal_addr,
+            n_clusters: superblock.journal_clusters,
+            staged: BTreeMap::new(),
+        })
+    }
+
+    pub fn addr(&self) -> ClusterNumber {
+        self.addr
+    }
+
+    pub fn n_clusters(&self) -> u64 {
+        self.n_clusters
+    }
+
+    /// Entries a transaction can have, the superblock included
+    pub fn capacity(&self) -> usize {
+        core::cmp::min(self.n_clusters as usize - 2, MAX_JOURNAL_ENTRIES)
+    }
+
+    /// Node writes waiting for the next commit
+    pub fn n_staged(&self) -> usize {
+        self.staged.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.staged.is_empty()
+    }
+
+    /// What the next commit writes to `cluster_number`, if anything
+    pub fn staged(&self, cluster_number: ClusterNumber) -> Option<&Block> {
+        self.staged.get(&cluster_number)
+    }
+
+    /// Write `block` over `cluster_number` at the next commit. False if the transaction is full, copy the node instead
+    pub fn stage(&mut self, cluster_number: ClusterNumber, block: Block) -> bool {
+        // room for the superblock too
+        if !self.staged.contains_key(&cluster_number) && self.staged.len() + 1 >= self.capacity() {
+            return false;
+        }
+
+        self.staged.insert(cluster_number, block);
+        true
+    }
+
+    /// Forget a write to a cluster that is being let go of
+    pub fn unstage(&mut self, cluster_number: ClusterNumber) {
+        self.staged.remove(&cluster_number);
+    }
+
+    /// Log the staged writes and `superblock`, which goes in `slot` as generation `sequence`, then write them all where
+    /// they go. Everything else the commit needs has to be down already
+    pub fn commit(
+        &mut self,
+        driver: &mut impl BlockDriver,
+        sequence: u64,
+        slot: ClusterNumber,
+        superblock: Block,
+    ) -> Result<(), FsError> {
+        let mut entries: Vec<(ClusterNumber, Block)> =
+            self.staged.iter().map(|(c, b)| (*c, *b)).collect();
+        entries.push((slot, superblock));
+
+        self.log(driver, sequence, &entries)?;
+        apply(driver, &entries)?;
+        self.staged.clear();
+
+        Ok(())
+    }
+
+    /// An empty transaction for generation 0, so a new region has a header to go by
+    pub fn format(driver: &mut impl BlockDriver, addr: ClusterNumber) -> Result<(), FsError> {
+        let header = JournalHeader {
+            header: NodeHeader::new(0),
+            sequence: 0,
+            targets: Vec::new(),
+            checksums: Vec::new(),
+        };
+        write_block(driver, addr, encode_cluster(&header)?)?;
+
+        Ok(())
+    }
+
+    /// Write a whole transaction. It counts once the commit record is down
+    fn log(
+        &self,
+        driver: &mut impl BlockDriver,
+        sequence: u64,
+        entries: &[(ClusterNumber, Block)],
+    ) -> Result<(), FsError> {
+        let header = JournalHeader {
+            header: NodeHeader::new(0),
+            sequence,
+            targets: entries.iter().map(|(c, _)| *c).collect(),
+            checksums: entries.iter().map(|(_, b)| crc32c(b)).collect(),
+        };
+        let header_block = encode_cluster(&header)?;
+
+        write_block(driver, self.addr, header_block)?;
+        for (i, (_, block)) in entries.iter().enumerate() {
+            write_block(driver, self.addr + 1 + i as u64, *block)?;
+        }
+        // the commit record cant land before what it vouches for
+        driver.flush()?;
+
+        let commit = JournalCommit {
+            header: NodeHeader::new(0),
+            sequence,
+            header_checksum: crc32c(&header_block),
+        };
+        let commit_cluster = self.addr + 1 + entries.len() as u64;
+        write_block(driver, commit_cluster, encode_cluster(&commit)?)?;
+        driver.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Write logged entries in place. The last one is the superblock, it only goes once the rest are down
+fn apply(driver: &mut impl BlockDriver, entries: &[(ClusterNumber, Block)]) -> Result<(), IoError> {
+    let Some(((slot, superblock), nodes)) = entries.split_last() else {
+        return Ok(());
+    };
+
+    for (cluster_number, block) in nodes {
+        write_block(driver, *cluster_number, *block)?;
+    }
+    driver.flush()?;
+    write_block(driver, *slot, *superblock)?;
+    driver.flush()?;
+
+    Ok(())
+}
+
+/// Finish the commit after `superblock` if a crash cut it off after it was logged. True if it did
+/// Anything in the region that isnt a whole transaction for that generation is left alone
+pub fn replay(driver: &mut impl BlockDriver, superblock: &SuperBlock) -> Result<bool, MountError> {
+    let Some(journal) = Journal::of(superblock) else {
+        return Ok(false);
+    };
+
+    let header_block = read_block(driver, journal.addr)?;
+    if verify_node(&header_block, journal.addr).is_err() {
+        return Ok(false);
+    }
+    let Ok((header, _)): Result<(JournalHeader, usize), _> =
+        bincode::decode_from_slice(&header_block, BINCODE_CONFIG)
+    else {
+        return Ok(false);
+    };
+
+    let n_entries = header.targets.len();
+    if header.sequence != superblock.generation + 1
+        || n_entries == 0
+        || n_entries > journal.capacity()
+        || header.checksums.len() != n_entries
+    {
+        return Ok(false);
+    }
+
+    let commit_cluster = journal.addr + 1 + n_entries as u64;
+    let commit_block = read_block(driver, commit_cluster)?;
+    if verify_node(&commit_block, commit_cluster).is_err() {
+        return Ok(false);
+    }
+    let Ok((commit, _)): Result<(JournalCommit, usize), _> =
+        bincode::decode_from_slice(&commit_block, BINCODE_CONFIG)
+    else {
+        return Ok(false);
+    };
+    if commit.sequence != header.sequence || commit.header_checksum != crc32c(&header_block) {
+        return Ok(false);
+    }
+
+    // committed, so every entry has to check out and go somewhere outside the region
+    let journal_end = journal.addr + journal.n_clusters;
+    let mut entries = Vec::with_capacity(n_entries);
+    for (i, target) in header.targets.iter().enumerate() {
+        let cluster_number = journal.addr + 1 + i as u64;
+        let block = read_block(driver, cluster_number)?;
+        let in_journal = (journal.addr..journal_end).contains(target);
+        if crc32c(&block) != header.checksums[i]
+            || in_journal
+            || *target >= superblock.n_sectors_total
+        {
+            return Err(MountError::BadStructure(cluster_number));
+        }
+        entries.push((*target, block));
+    }
+
+    apply(driver, &entries)?;
+
+    Ok(true)
+}
+
+/// Whether `superblock` can be used with the other slot unusable. Always without a journal, copy on write leaves
+/// everything it points at alone. With one, only if the last transaction logged isnt past the generation after it
+pub fn fallback_is_safe(
+    driver: &mut impl BlockDriver,
+    superblock: &SuperBlock,
+) -> Result<bool, MountError> {
+    let Some(journal) = Journal::of(superblock) else {
+        return Ok(true);
+    };
+
+    let header_block = read_block(driver, journal.addr)?;
+    if verify_node(&header_block, journal.addr).is_err() {
+        return Ok(false);
+    }
+    let Ok((header, _)): Result<(JournalHeader, usize), _> =
+        bincode::decode_from_slice(&header_block, BINCODE_CONFIG)
+    else {
+        return Ok(false);
+    };
+
+    Ok(header.sequence <= superblock.generation + 1)
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_journal_writes_in_place() {
+    use super::{mkfs_with, MkfsOptions, NeFS, OpenFlags, PAGE_SIZE};
+    use crate::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(256);
+    let options = MkfsOptions {
+        journal_clusters: 16,
+    };
+    mkfs_with(&mut disk, 256, "journal", options).unwrap();
+    let mut fs = NeFS::mount(disk).unwrap();
+    assert_eq!(fs.superblock().journal_clusters(), 16);
+
+    let big = alloc::vec![1; 64 * PAGE_SIZE as usize];
+    fs.create("/big").unwrap();
+    fs.open("/big", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(&big)
+        .unwrap();
+    fs.commit().unwrap();
+
+    // a small rewrite only logs the leaf, nothing moves
+    let node_map = fs.volume.superblock.node_map_addr;
+    let used = fs.superblock().n_sectors_used();
+    for i in 0..5 {
+        fs.open("/big", OpenFlags::NONE)
+            .unwrap()
+            .write_bytes(b"small", i * PAGE_SIZE)
+            .unwrap();
+        assert_eq!(fs.volume.journal.as_ref().unwrap().n_staged(), 1);
+        fs.commit().unwrap();
+    }
+    assert_eq!(fs.volume.superblock.node_map_addr, node_map);
+    assert_eq!(fs.superblock().n_sectors_used(), used);
+
+    // a full transaction turns nodes away, but for the ones it has
+    let mut journal = fs.volume.journal.clone().unwrap();
+    assert_eq!(journal.capacity(), 14);
+    for cluster_number in 0..13 {
+        assert!(journal.stage(cluster_number, [0; 4096]));
+    }
+    assert!(!journal.stage(13, [0; 4096]));
+    assert!(journal.stage(0, [1; 4096]));
+    assert_eq!(journal.n_staged(), 13);
+
+    // nodes made since the last commit are copied, only older ones are logged
+    fs.set_flush_policy(super::FlushPolicy::MANUAL);
+    for i in 0..40 {
+        fs.create(&alloc::format!("/file{}", i)).unwrap();
+        assert!(fs.volume.journal.as_ref().unwrap().n_staged() <= 13);
+    }
+    fs.commit().unwrap();
+    assert_eq!(fs.volume.journal.as_ref().unwrap().n_staged(), 0);
+
+    let mut fs = NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(fs.readdir("/").unwrap().len(), 43);
+    let mut buf = [0; 5];
+    fs.open("/big", OpenFlags::NONE)
+        .unwrap()
+        .read_bytes(&mut buf, 4 * PAGE_SIZE)
+        .unwrap();
+    assert_eq!(&buf, b"small");
+}
+
+#[test]
+fn test_journal_min_size() {
+    use super::{mkfs_with, MkfsOptions, NeFS, OpenFlags};
+    use crate::driver::block::RamDisk;
+
+    let options = |journal_clusters| MkfsOptions { journal_clusters };
+    let mut disk = RamDisk::new(128);
+    assert!(mkfs_with(&mut disk, 128, "journal", options(MIN_JOURNAL_CLUSTERS - 1)).is_err());
+    mkfs_with(&mut disk, 128, "journal", options(MIN_JOURNAL_CLUSTERS)).unwrap();
+
+    let mut fs = NeFS::mount(disk).unwrap();
+    assert_eq!(fs.volume.journal.as_ref().unwrap().capacity(), 2);
+    fs.create("/file").unwrap();
+    fs.open("/file", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(b"old")
+        .unwrap();
+    fs.commit().unwrap();
+
+    // room for exactly one node besides the superblock
+    let node_map = fs.volume.superblock.node_map_addr;
+    fs.open("/file", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(b"new")
+        .unwrap();
+    assert_eq!(fs.volume.journal.as_ref().unwrap().n_staged(), 1);
+    fs.commit().unwrap();
+    assert_eq!(fs.volume.superblock.node_map_addr, node_map);
+
+    let mut fs = NeFS::mount(fs.unmount()).unwrap();
+    let mut buf = [0; 3];
+    fs.open("/file", OpenFlags::NONE)
+        .unwrap()
+        .read_bytes(&mut buf, 0)
+        .unwrap();
+    assert_eq!(&buf, b"new");
+}
+
+#[test]
+fn test_journal_bad_region() {
+    use super::{mkfs_with, MkfsOptions, NeFS, SUPERBLOCK_CLUSTERS};
+    use crate::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(128);
+    let options = MkfsOptions {
+        journal_clusters: 8,
+    };
+    let superblock = mkfs_with(&mut disk, 128, "journal", options).unwrap();
+    let addr = superblock.journal_addr;
+
+    // too small to hold a transaction, past the end of the partition, wrapping around
+    for (journal_addr, journal_clusters) in [(addr, 1), (addr, 3), (124, 8), (u64::MAX, 8)] {
+        let mut bad = superblock.clone();
+        bad.journal_addr = journal_addr;
+        bad.journal_clusters = journal_clusters;
+        let mut disk = disk.clone();
+        for slot in SUPERBLOCK_CLUSTERS {
+            write_block(&mut disk, slot, bad.to_disk_format()).unwrap();
+        }
+        assert_eq!(
+            NeFS::mount(disk).err(),
+            Some(MountError::BadStructure(journal_addr))
+        );
+    }
+
+    // no journal at all is fine wherever it points
+    let mut none = superblock.clone();
+    none.journal_clusters = 0;
+    for slot in SUPERBLOCK_CLUSTERS {
+        write_block(&mut disk, slot, none.to_disk_format()).unwrap();
+    }
+    NeFS::mount(disk).unwrap();
+}
+
+#[test]
+fn test_journal_replay() {
+    use super::{mkfs_with, read_superblock, CrashAfter, MkfsOptions, NeFS, OpenFlags};
+    use crate::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(128);
+    let options = MkfsOptions {
+        journal_clusters: 8,
+    };
+    mkfs_with(&mut disk, 128, "journal", options).unwrap();
+    let mut fs = NeFS::mount(disk).unwrap();
+    fs.create("/file").unwrap();
+    fs.open("/file", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(b"old")
+        .unwrap();
+    let disk = fs.unmount();
+
+    // crash after every write of a commit that only writes a leaf in place. The contents go in place too, so only
+    // the size in the leaf tells them apart
+    let mut replayed = 0;
+    for writes_left in 0.. {
+        let mut fs = NeFS::mount(CrashAfter {
+            disk: disk.clone(),
+            writes_left,
+        })
+        .unwrap();
+        let _ = (|| {
+            fs.open("/file", OpenFlags::NONE)?.set_contents(b"newer")?;
+            fs.commit()
+        })();
+
+        let mut disk = fs.unmount().disk;
+        let (before, _) = read_superblock(&mut disk).unwrap();
+        let mut fs = NeFS::mount(disk).unwrap();
+        if fs.superblock().generation() > before.generation() {
+            replayed += 1;
+        }
+
+        let mut inode = fs.open("/file", OpenFlags::NONE).unwrap();
+        let mut buf = alloc::vec![0; inode.size() as usize];
+        inode.read_bytes(&mut buf, 0).unwrap();
+        if buf != b"newer" {
+            assert_eq!(buf.len(), 3);
+        } else if fs.superblock().generation() == before.generation() {
+            break;
+        }
+        assert!(writes_left < 1000);
+    }
+
+    // cut off between the commit record and the last in place write
+    assert!(replayed > 0);
+}
+
+#[test]
+fn test_journal_fallback() {
+    use super::{mkfs_with, MkfsOptions, NeFS, OpenFlags, SUPERBLOCK_CLUSTERS};
+    use crate::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(128);
+    let options = MkfsOptions {
+        journal_clusters: 8,
+    };
+    mkfs_with(&mut disk, 128, "journal", options).unwrap();
+    let mut fs = NeFS::mount(disk).unwrap();
+    fs.create("/file").unwrap();
+    fs.open("/file", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(b"old")
+        .unwrap();
+    fs.commit().unwrap();
+    let older = fs.superblock().generation();
+
+    // only logged, the leaf is overwritten in place
+    fs.open("/file", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(b"newer")
+        .unwrap();
+    assert!(!fs.volume.journal.as_ref().unwrap().is_empty());
+    fs.commit().unwrap();
+    let journal_addr = fs.volume.superblock.journal_addr;
+    let disk = fs.unmount();
+    let newest = SUPERBLOCK_CLUSTERS[((older + 1) % 2) as usize];
+
+    // the older slot points at the leaf that was written over, the journal still has the commit that did it
+    let mut torn = disk.clone();
+    write_block(&mut torn, newest, [0; 4096]).unwrap();
+    let mut fs = NeFS::mount(torn.clone()).unwrap();
+    assert_eq!(fs.superblock().generation(), older + 1);
+    assert_eq!(fs.bad_superblock_slot(), None);
+    let mut buf = [0; 5];
+    fs.open("/file", OpenFlags::NONE)
+        .unwrap()
+        .read_bytes(&mut buf, 0)
+        .unwrap();
+    assert_eq!(&buf, b"newer");
+
+    // without the journal nothing says the older slot is still whole
+    write_block(&mut torn, newest, [0; 4096]).unwrap();
+    write_block(&mut torn, journal_addr, [0; 4096]).unwrap();
+    assert_eq!(NeFS::mount(torn).err(), Some(MountError::BadMagic(0)));
+
+    // a fresh fs has an empty transaction, nothing was written in place yet
+    let mut disk = RamDisk::new(128);
+    let superblock = mkfs_with(&mut disk, 128, "journal", options).unwrap();
+    let newest = SUPERBLOCK_CLUSTERS[(superblock.generation() % 2) as usize];
+    write_block(&mut disk, newest, [0; 4096]).unwrap();
+    let (older, _) = super::read_superblock(&mut disk).unwrap();
+    assert!(fallback_is_safe(&mut disk, &older).unwrap());
+}
+
+#[test]
+fn test_journal_replay_io_error() {
+    use super::{mkfs_with, read_superblock, CrashAfter, MkfsOptions, NeFS, OpenFlags};
+    use crate::driver::block::{Completion, RamDisk, RequestId};
+
+    // a device that went read only
+    struct NoWrites(RamDisk);
+
+    impl BlockDriver for NoWrites {
+        fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+            self.0.push_read_request(cluster_number)
+        }
+
+        fn push_write_request(&mut self, _: u64, _: Block) -> Result<RequestId, IoError> {
+            Err(IoError::Device)
+        }
+
+        fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+            self.0.poll_completion(id)
+        }
+    }
+
+    let mut disk = RamDisk::new(128);
+    let options = MkfsOptions {
+        journal_clusters: 8,
+    };
+    mkfs_with(&mut disk, 128, "journal", options).unwrap();
+    let mut fs = NeFS::mount(disk).unwrap();
+    fs.create("/file").unwrap();
+    let disk = fs.unmount();
+
+    // cut off once the transaction is logged, before it is applied
+    let logged = (0..1000)
+        .find_map(|writes_left| {
+            let mut fs = NeFS::mount(CrashAfter {
+                disk: disk.clone(),
+                writes_left,
+            })
+            .unwrap();
+            let _ = (|| {
+                fs.open("/file", OpenFlags::NONE)?.set_contents(b"new")?;
+                fs.commit()
+            })();
+
+            let mut disk = fs.unmount().disk;
+            let (superblock, _) = read_superblock(&mut disk).unwrap();
+            replay(&mut disk.clone(), &superblock)
+                .unwrap()
+                .then_some(disk)
+        })
+        .unwrap();
+
+    // the transaction is fine, the device isnt
+    assert_eq!(
+        NeFS::mount(NoWrites(logged.clone())).err(),
+        Some(MountError::Io(IoError::Device))
+    );
+    assert!(NeFS::mount(logged).is_ok());
+}
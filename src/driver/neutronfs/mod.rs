@@ -0,0 +1,2614 @@
+// -------------
+// USES
+// -------------
+
+use super::block::{make_block, read_block, write_block, Block, BlockDriver, IoError};
+use super::cache::{CacheStats, Lru};
+use alloc::{collections::BTreeSet, string::String, vec, vec::Vec};
+use bincode::{config::Configuration, Decode, Encode};
+use core::sync::atomic::{AtomicU64, Ordering};
+use neutronapi::fs::{Readable, Writable};
+use rand_mt::Mt19937GenRand64;
+
+// -------------
+// MODULES
+// -------------
+
+pub mod checksum;
+pub mod dir;
+pub mod flush;
+pub mod free_list;
+pub mod journal;
+pub mod node_map;
+pub mod path;
+pub mod refcount;
+pub mod reflink;
+pub mod skiplist;
+pub mod snapshot;
+
+pub use dir::Dir;
+pub use flush::{Change, FlushPolicy};
+pub use free_list::FreeList;
+pub use journal::Journal;
+pub use node_map::NodeMap;
+pub use path::{OpenFlags, Stat};
+pub use refcount::RefCounts;
+pub use skiplist::SkipList;
+pub use snapshot::SnapshotTable;
+
+// ----------------
+// DISK DEFINITIONS
+// ----------------
+
+/// 64K for a single leaf node (at least in the fs)
+pub const DEFAULT_LEAF_NODE_SIZE: u16 = 4096;
+/// Internal nodes are literally 40 bytes or something
+pub const DEFAULT_INTERNAL_NODE_SIZE: u8 = 40;
+
+pub const SECTOR_SIZE: u64 = 4096;
+pub const PAGE_SIZE: u64 = 4096;
+
+pub const MAX_LEVELS: usize = SECTOR_SIZE as usize - 1;
+
+/// Inodes NeFS remembers the leaf node of, see NeFS::set_inode_cache_capacity
+pub const INODE_CACHE_SIZE: usize = 256;
+
+/// Actual driver lookup media number
+pub type ClusterNumber = u64;
+/// What nodes point at each other with. The node map has the cluster each one is in, see node_map.rs
+pub type NodeNumber = u64;
+/// Key numbers to sort lists
+pub type InodeNumber = u64;
+pub type DataNodeNumber = u64;
+
+// node size / entry size, prob like 2 pages
+pub const MAX_DATA_NODES: u64 = 8192;
+
+pub const MAX_INTERNAL_ITEMS_PER_NODE: usize = 20;
+pub const MAX_LEAF_ITEMS_PER_NODE: usize = 20;
+
+/// "NeutrnFS" in little endian
+pub const NEFS_MAGIC: u64 = u64::from_le_bytes(*b"NeutrnFS");
+
+// Clusters laid down by mkfs. Everything after these is handed out by the free list
+// Only the superblocks stay put, the rest is copied somewhere new the first time it changes
+/// Two copies of the superblock. Commits take turns, generation % 2 says which, so a torn write only loses one of them
+pub const SUPERBLOCK_CLUSTERS: [ClusterNumber; 2] = [0, 1];
+pub const SKIPLIST_HEAD_CLUSTER: ClusterNumber = 2;
+pub const FREE_LIST_CLUSTER: ClusterNumber = 3;
+pub const NODE_MAP_CLUSTER: ClusterNumber = 4;
+pub const NODE_MAP_CHUNK_CLUSTER: ClusterNumber = 5;
+pub const N_RESERVED_CLUSTERS: u64 = 6;
+
+/// The skiplist head is the first node mkfs puts in the node map
+pub const SKIPLIST_HEAD_NODE: NodeNumber = 1;
+
+/// The root directory, made by mkfs. Its `..` is itself
+pub const ROOT_INODE: InodeNumber = 1;
+
+/// Room for the root directory's tower and leaf after the reserved clusters, a new copy of the head,
+/// and what the first commit needs for its node map and free list
+pub const MIN_CLUSTERS: u64 = N_RESERVED_CLUSTERS + 7;
+
+/// Every disk structure is encoded with this. Varint encoding, so structs dont have a fixed on disk size
+pub const BINCODE_CONFIG: Configuration = bincode::config::standard();
+
+// ---------------
+// DISK STRUCTURES
+// ---------------
+
+pub type FSUUID = [u8; 16];
+pub type Checksum32 = u32;
+pub type ChecksumSHA1 = [u8; 20];
+
+/// Use this to align the data correctly in memory before writing to disk
+#[repr(align(4096))]
+pub struct Align4096<T>(T);
+
+/// Core metadata of the fs in memory. On disk, uses a subset of these (implemented by method to_disk_format())
+#[repr(C)]
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct SuperBlock {
+    // AUTHENTICITY OF FS
+    magic: u64,
+    fs_uuid: FSUUID,
+
+    // INTEGRITY
+    checksum: Checksum32,
+
+    // LABELS
+    label: [u8; 0x100],
+    generation: u64,
+
+    // OFFSETS
+    physical_addr_of_partition: u64,
+    // node number of the head, not a cluster
+    core_fs_skiplist_addr: u64,
+    free_cluster_list_addr: u64,
+    node_map_addr: u64,
+    // 0 while no cluster is shared
+    refcounts_addr: u64,
+    // 0 while there are no snapshots
+    snapshots_addr: u64,
+    // first cluster of the journal region and its length, both 0 for a fs made without one
+    journal_addr: u64,
+    journal_clusters: u64,
+
+    // TOTAL SIZES
+    n_sectors_total: u64,
+    n_sectors_used: u64,
+
+    // FEATURE SIZE
+    sector_size_bytes: u16,
+    fs_node_size_bytes: u16,
+}
+
+impl SuperBlock {
+    pub fn fs_uuid(&self) -> FSUUID {
+        self.fs_uuid
+    }
+
+    pub fn checksum(&self) -> Checksum32 {
+        self.checksum
+    }
+
+    /// Bumped by every commit
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn n_sectors_total(&self) -> u64 {
+        self.n_sectors_total
+    }
+
+    pub fn n_sectors_used(&self) -> u64 {
+        self.n_sectors_used
+    }
+
+    /// Size of the journal region, 0 without a journal
+    pub fn journal_clusters(&self) -> u64 {
+        self.journal_clusters
+    }
+
+    /// Byte address of the partition on the disk it was made on
+    pub fn physical_addr_of_partition(&self) -> u64 {
+        self.physical_addr_of_partition
+    }
+
+    /// Label up to the first NUL
+    pub fn label(&self) -> &str {
+        let len = self
+            .label
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.label.len());
+        core::str::from_utf8(&self.label[..len]).unwrap_or("")
+    }
+
+    /// CRC32C of the encoded superblock, with the checksum field itself zeroed
+    pub fn compute_checksum(&self) -> Checksum32 {
+        let mut zeroed = self.clone();
+        zeroed.checksum = 0;
+
+        let mut buf = make_block();
+        let len = bincode::encode_into_slice(&zeroed, &mut buf, BINCODE_CONFIG)
+            .expect("superblock always fits in a cluster");
+
+        crc32c(&buf[..len])
+    }
+
+    /// Decode and validate the superblock at the start of a partition
+    pub fn from_disk_format(block: &Block) -> Result<Self, MountError> {
+        let (superblock, _): (SuperBlock, usize) =
+            bincode::decode_from_slice(block, BINCODE_CONFIG)
+                .map_err(|_| MountError::Undecodable)?;
+
+        if superblock.magic != NEFS_MAGIC {
+            return Err(MountError::BadMagic(superblock.magic));
+        }
+
+        let computed = superblock.compute_checksum();
+        if superblock.checksum != computed {
+            return Err(MountError::BadChecksum {
+                stored: superblock.checksum,
+                computed,
+            });
+        }
+
+        if superblock.sector_size_bytes as u64 != SECTOR_SIZE {
+            return Err(MountError::UnsupportedSectorSize(
+                superblock.sector_size_bytes,
+            ));
+        }
+
+        if superblock.fs_node_size_bytes != DEFAULT_LEAF_NODE_SIZE {
+            return Err(MountError::UnsupportedNodeSize(
+                superblock.fs_node_size_bytes,
+            ));
+        }
+
+        // replay and Journal::capacity trust the region to be big enough and on the partition
+        let room = superblock
+            .n_sectors_total
+            .saturating_sub(superblock.journal_addr);
+        if superblock.journal_clusters != 0
+            && (superblock.journal_clusters < journal::MIN_JOURNAL_CLUSTERS
+                || superblock.journal_clusters > room)
+        {
+            return Err(MountError::BadStructure(superblock.journal_addr));
+        }
+
+        Ok(superblock)
+    }
+
+    /// Encode into a cluster, with an up to date checksum
+    pub fn to_disk_format(&self) -> Block {
+        let mut sb = self.clone();
+        sb.checksum = sb.compute_checksum();
+
+        let mut block = make_block();
+        bincode::encode_into_slice(&sb, &mut block, BINCODE_CONFIG)
+            .expect("superblock always fits in a cluster");
+
+        block
+    }
+}
+
+// Each internal node or leaf node should have a header I think. Should they also begin at a start of a cluster?
+// Maybe it doesnt matter as much, just read multiple clusters if you have to, and extract the data with offsets and dont overread
+#[repr(C)]
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct NodeHeader {
+    checksum: ChecksumSHA1,
+    size_bytes: u64,
+    generation_number: u64,
+    n_levels: u64,
+}
+
+impl NodeHeader {
+    pub fn new(n_levels: u64) -> Self {
+        Self {
+            checksum: [0; 20],
+            size_bytes: DEFAULT_LEAF_NODE_SIZE as u64,
+            generation_number: 0,
+            n_levels,
+        }
+    }
+}
+
+/// A representation of an internal node that only stores keys. And at most a pointer to a leaf data structure that is formatted in some way
+/// In the skiplist, each inode gets one of these as its tower. The head is also one, with key 0 and every level
+#[repr(C)]
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct InternalNode {
+    header: NodeHeader,
+    key: InodeNumber,
+    // node of the leaf holding this inode's items. 0 for the head
+    leaf: NodeNumber,
+    // next tower on each level. 0 is the end of the level, node 0 never exists
+    pointers: [NodeNumber; MAX_INTERNAL_ITEMS_PER_NODE],
+}
+
+#[repr(C)]
+#[derive(Debug, Encode, Decode)]
+pub enum ItemType {
+    Payload(Payload),
+    Directory(Directory),
+    /// Path the link points at
+    Symlink(String),
+}
+
+// For a CoW-able fs, we prob should use extent trees
+// otherwise store everything in line, and bloat leaf node really hard?
+
+/// A file's contents. The extents holding them, in order. The size in bytes is in the inode record
+#[repr(C)]
+#[derive(Debug, Default, Encode, Decode)]
+pub struct Payload {
+    data_nodes: Vec<DataNode>,
+}
+
+/// A directory. Names hash into buckets, each a chain of DirEntryNodes
+/// `.` is the directory itself and `..` is `parent`, they arent stored as entries
+#[repr(C)]
+#[derive(Debug, Clone, Default, Encode, Decode)]
+pub struct Directory {
+    parent: InodeNumber,
+    n_entries: u64,
+    // first DirEntryNode of each bucket. Empty until the first entry goes in
+    buckets: Vec<NodeNumber>,
+}
+
+impl Directory {
+    pub fn new(parent: InodeNumber) -> Self {
+        Self {
+            parent,
+            ..Default::default()
+        }
+    }
+
+    pub fn parent(&self) -> InodeNumber {
+        self.parent
+    }
+
+    /// Not counting `.` and `..`
+    pub fn n_entries(&self) -> u64 {
+        self.n_entries
+    }
+}
+
+/// A name in a directory
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct DirEntry {
+    name: String,
+    inode: InodeNumber,
+}
+
+impl DirEntry {
+    pub fn new(name: String, inode: InodeNumber) -> Self {
+        Self { name, inode }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> InodeNumber {
+        self.inode
+    }
+}
+
+/// One cluster of a directory bucket. `next` is the overflow, 0 at the end
+#[repr(C)]
+#[derive(Debug, Encode, Decode)]
+pub struct DirEntryNode {
+    header: NodeHeader,
+    next: NodeNumber,
+    entries: Vec<DirEntry>,
+}
+
+#[repr(C)]
+#[derive(Debug, Encode, Decode)]
+pub struct LeafNode {
+    header: NodeHeader,
+    inode: InodeNumber,
+    // an InodeRecord. Fixed size, so it is always at the same offset in the leaf
+    record: [u8; INODE_RECORD_SIZE],
+    item_type: ItemType,
+}
+
+impl LeafNode {
+    pub fn new(inode: InodeNumber, record: &InodeRecord, item_type: ItemType) -> Self {
+        Self {
+            header: NodeHeader::new(0),
+            inode,
+            record: record.to_bytes(),
+            item_type,
+        }
+    }
+
+    /// None if the record doesnt decode
+    pub fn record(&self) -> Option<InodeRecord> {
+        InodeRecord::from_bytes(&self.record)
+    }
+}
+
+/// Same kinds as NeFSFile in ram.rs
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File = 0,
+    Dir = 1,
+    Device = 2,
+    Symlink = 3,
+    Socket = 4,
+    Pipe = 5,
+}
+
+impl FileType {
+    fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(FileType::File),
+            1 => Some(FileType::Dir),
+            2 => Some(FileType::Device),
+            3 => Some(FileType::Symlink),
+            4 => Some(FileType::Socket),
+            5 => Some(FileType::Pipe),
+            _ => None,
+        }
+    }
+
+    /// rw-r--r-- for files, rwxr-xr-x for directories, rwxrwxrwx for symlinks
+    pub fn default_mode(&self) -> u16 {
+        match self {
+            FileType::Dir => 0o755,
+            FileType::Symlink => 0o777,
+            _ => 0o644,
+        }
+    }
+}
+
+/// Seconds and nanoseconds since the unix epoch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Encode, Decode)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    pub fn new(secs: i64, nanos: u32) -> Self {
+        Self { secs, nanos }
+    }
+}
+
+/// Where the fs gets the time from. no_std has no clock, so the kernel (or a test) hands one in
+pub type Clock = fn() -> Timestamp;
+
+/// Always the epoch, until someone calls NeFS::set_clock
+pub fn epoch_clock() -> Timestamp {
+    Timestamp::default()
+}
+
+pub const INODE_RECORD_SIZE: usize = 72;
+
+/// An inode's attributes. Kept in its leaf as INODE_RECORD_SIZE little endian bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InodeRecord {
+    pub file_type: FileType,
+    /// Permission bits, e.g. 0o644
+    pub mode: u16,
+    /// Directory entries pointing at it. A directory also gets one for its `.` and one for each child's `..`
+    pub n_links: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Bytes for a file, entries for a directory, target length for a symlink
+    pub size: u64,
+    pub created: Timestamp,
+    /// Contents last changed
+    pub modified: Timestamp,
+    pub accessed: Timestamp,
+    /// The record itself last changed
+    pub changed: Timestamp,
+}
+
+impl InodeRecord {
+    /// A fresh record with the default mode for its type, created at `now`
+    pub fn new(file_type: FileType, uid: u32, gid: u32, now: Timestamp) -> Self {
+        Self {
+            file_type,
+            mode: file_type.default_mode(),
+            n_links: if file_type == FileType::Dir { 2 } else { 1 },
+            uid,
+            gid,
+            size: 0,
+            created: now,
+            modified: now,
+            accessed: now,
+            changed: now,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; INODE_RECORD_SIZE] {
+        let mut bytes = [0; INODE_RECORD_SIZE];
+        bytes[0] = self.file_type as u8;
+        // 1 is padding
+        bytes[2..4].copy_from_slice(&self.mode.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.n_links.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.uid.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.gid.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.size.to_le_bytes());
+
+        let times = [self.created, self.modified, self.accessed, self.changed];
+        for (i, time) in times.iter().enumerate() {
+            let at = 24 + i * 12;
+            bytes[at..at + 8].copy_from_slice(&time.secs.to_le_bytes());
+            bytes[at + 8..at + 12].copy_from_slice(&time.nanos.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    /// None if the type byte isnt a FileType
+    pub fn from_bytes(bytes: &[u8; INODE_RECORD_SIZE]) -> Option<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let time_at = |i: usize| {
+            Timestamp::new(
+                i64::from_le_bytes(bytes[i..i + 8].try_into().unwrap()),
+                u32_at(i + 8),
+            )
+        };
+
+        Some(Self {
+            file_type: FileType::from_u8(bytes[0])?,
+            mode: u16::from_le_bytes([bytes[2], bytes[3]]),
+            n_links: u32_at(4),
+            uid: u32_at(8),
+            gid: u32_at(12),
+            size: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            created: time_at(24),
+            modified: time_at(36),
+            accessed: time_at(48),
+            changed: time_at(60),
+        })
+    }
+}
+
+impl Default for InodeRecord {
+    fn default() -> Self {
+        Self::new(FileType::File, 0, 0, Timestamp::default())
+    }
+}
+
+/// Each data node must refer to a cont block of allocated clusters
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct DataNode {
+    clusters_used: u64,
+    cluster_start_number: ClusterNumber,
+}
+
+impl DataNode {
+    pub fn new(clusters_used: u64, cluster_start_number: ClusterNumber) -> Self {
+        Self {
+            clusters_used,
+            cluster_start_number,
+        }
+    }
+
+    pub fn clusters_used(&self) -> u64 {
+        self.clusters_used
+    }
+
+    pub fn cluster_start_number(&self) -> ClusterNumber {
+        self.cluster_start_number
+    }
+}
+
+pub type ClusterData = [u8; PAGE_SIZE as usize];
+
+/// One cluster of the free list. Always adds LIFO (inserts at the front). Could prob be very fragmented
+/// Holds runs of free clusters and the cluster of the next node, 0 at the end
+#[repr(C)]
+#[derive(Debug, Encode, Decode)]
+pub struct FreeClusterNode {
+    header: NodeHeader,
+    next: ClusterNumber,
+    free_runs: Vec<DataNode>,
+}
+
+/// Top of the node map. Lists the clusters its chunks are in
+#[repr(C)]
+#[derive(Debug, Encode, Decode)]
+pub struct NodeMapRoot {
+    header: NodeHeader,
+    // length of the table, including number 0
+    n_numbers: u64,
+    chunks: Vec<ClusterNumber>,
+}
+
+/// A slice of the node map. Entry i is the cluster of node (chunk index * NODE_MAP_ENTRIES_PER_CHUNK + i)
+#[repr(C)]
+#[derive(Debug, Encode, Decode)]
+pub struct NodeMapChunk {
+    header: NodeHeader,
+    clusters: Vec<ClusterNumber>,
+}
+
+/// A run of clusters that all have the same number of owners
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct RefCountRun {
+    cluster_start_number: ClusterNumber,
+    clusters_used: u64,
+    count: u32,
+}
+
+/// One cluster of the refcount table. Holds the runs of shared clusters and the cluster of the next node, 0 at the end
+#[repr(C)]
+#[derive(Debug, Encode, Decode)]
+pub struct RefCountNode {
+    header: NodeHeader,
+    next: ClusterNumber,
+    runs: Vec<RefCountRun>,
+}
+
+/// A frozen commit. Holds on to the node map the commit had, and through it every node and file cluster
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Snapshot {
+    name: String,
+    generation: u64,
+    created: Timestamp,
+    node_map_addr: ClusterNumber,
+}
+
+impl Snapshot {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Generation of the commit it froze
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn created(&self) -> Timestamp {
+        self.created
+    }
+}
+
+/// Every snapshot, in the order they were taken
+#[repr(C)]
+#[derive(Debug, Encode, Decode)]
+pub struct SnapshotTableNode {
+    header: NodeHeader,
+    snapshots: Vec<Snapshot>,
+}
+
+/// Begins a journal transaction, in the first cluster of the journal region. Its entries follow it, one cluster each,
+/// in the order of `targets`
+#[repr(C)]
+#[derive(Debug, Encode, Decode)]
+pub struct JournalHeader {
+    header: NodeHeader,
+    /// Generation of the commit it makes
+    sequence: u64,
+    /// Where each entry goes
+    targets: Vec<ClusterNumber>,
+    /// CRC32C of each entry
+    checksums: Vec<Checksum32>,
+}
+
+/// Ends a journal transaction, in the cluster after its last entry. Without it the transaction never happened
+#[repr(C)]
+#[derive(Debug, Encode, Decode)]
+pub struct JournalCommit {
+    header: NodeHeader,
+    sequence: u64,
+    /// CRC32C of the header's whole cluster, so a commit record left over from another transaction doesnt match
+    header_checksum: Checksum32,
+}
+
+// -----------------
+// ERRORS
+// -----------------
+
+/// Why a partition couldnt be mounted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountError {
+    /// Cluster 0 isnt a bincode superblock at all
+    Undecodable,
+    /// Decodes, but isnt NeFS. Holds the magic that was found
+    BadMagic(u64),
+    /// The superblock was corrupted or partially written
+    BadChecksum {
+        stored: Checksum32,
+        computed: Checksum32,
+    },
+    /// Formatted for a sector size this driver doesnt handle
+    UnsupportedSectorSize(u16),
+    /// Formatted with a node size this driver doesnt handle
+    UnsupportedNodeSize(u16),
+    /// Something the superblock points to doesnt decode
+    BadStructure(ClusterNumber),
+    /// Something the superblock points to decodes, but its checksum is wrong
+    BadNodeChecksum(ClusterNumber),
+    /// The superblock says the partition is bigger than the device it is on
+    DeviceTooSmall {
+        n_clusters: u64,
+        device_clusters: u64,
+    },
+    /// Couldnt read the partition
+    Io(IoError),
+}
+
+impl From<IoError> for MountError {
+    fn from(e: IoError) -> Self {
+        MountError::Io(e)
+    }
+}
+
+/// Errors from operations on a mounted fs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    /// Inode 0 is the skiplist head and cant be stored
+    ReservedInode,
+    /// No free clusters left
+    NoSpace,
+    /// A node didnt decode, or something points at a node that doesnt exist
+    Corrupt(NodeNumber),
+    /// A node on disk doesnt match its checksum. Bad sector or torn write
+    BadChecksum(ClusterNumber),
+    /// A structure grew past a single cluster
+    NodeTooLarge,
+    /// The block driver failed
+    Io(IoError),
+    /// Wanted a directory, got a file
+    NotADirectory,
+    /// Wanted a file, got a directory
+    IsADirectory,
+    /// Empty, `.`, `..`, or has a `/` or NUL in it
+    InvalidName,
+    NameTooLong,
+    /// Wanted a file or directory, got a symlink
+    IsASymlink,
+    /// Directory still has entries
+    NotEmpty,
+    /// Not absolute, or would move a directory into itself, or remove the root
+    InvalidPath,
+    /// Symlinks pointing at each other, or just too many in a row
+    TooManySymlinks,
+    /// The snapshot table is full
+    TooManySnapshots,
+    /// Mounted read only, like a snapshot view
+    ReadOnly,
+    /// A clone range that isnt on cluster boundaries, runs past the end of the source, or overlaps itself
+    InvalidRange,
+    /// An offset and length that end past the largest size a file can have
+    FileTooLarge,
+}
+
+impl From<IoError> for FsError {
+    fn from(e: IoError) -> Self {
+        FsError::Io(e)
+    }
+}
+
+impl FsError {
+    /// For the neutronapi traits, which only take static strings
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FsError::NotFound => "not found",
+            FsError::AlreadyExists => "already exists",
+            FsError::ReservedInode => "inode number is reserved",
+            FsError::NoSpace => "no space left on partition",
+            FsError::Corrupt(_) => "corrupt node on disk",
+            FsError::BadChecksum(_) => "node checksum mismatch",
+            FsError::NodeTooLarge => "structure does not fit in a cluster",
+            FsError::Io(_) => "block device error",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::InvalidName => "invalid file name",
+            FsError::NameTooLong => "file name too long",
+            FsError::IsASymlink => "is a symlink",
+            FsError::NotEmpty => "directory not empty",
+            FsError::InvalidPath => "invalid path",
+            FsError::TooManySymlinks => "too many levels of symlinks",
+            FsError::TooManySnapshots => "too many snapshots",
+            FsError::ReadOnly => "read-only file system",
+            FsError::InvalidRange => "invalid clone range",
+            FsError::FileTooLarge => "file too large",
+        }
+    }
+}
+
+// -----------------
+// INTERNAL API
+// -----------------
+
+/// Encode a node into a single cluster and seal it with its checksum. Fails if it doesnt fit
+pub fn encode_cluster<T: Encode>(val: &T) -> Result<Block, FsError> {
+    let mut block = make_block();
+    bincode::encode_into_slice(val, &mut block, BINCODE_CONFIG)
+        .map_err(|_| FsError::NodeTooLarge)?;
+    checksum::seal_node(&mut block);
+
+    Ok(block)
+}
+
+const fn make_crc32c_table() -> [u32; 256] {
+    // reflected Castagnoli polynomial
+    let poly = 0x82F63B78;
+    let mut table = [0; 256];
+
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = make_crc32c_table();
+
+/// CRC32C (Castagnoli), same as what SSE4.2 and most SSD firmware computes
+pub fn crc32c(data: &[u8]) -> Checksum32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc = CRC32C_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
+
+/// Bumped on every call so two mkfs in the same run dont get the same uuid
+static UUID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Random v4 uuid. No entropy source in no_std, so mix whatever differs between calls: the label, the size, a counter and where the stack happens to be
+pub fn generate_uuid(label: &str, n_clusters: u64) -> FSUUID {
+    let stack_addr = &n_clusters as *const u64 as u64;
+    let count = UUID_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let seed = (crc32c(label.as_bytes()) as u64)
+        ^ n_clusters.rotate_left(32)
+        ^ stack_addr
+        ^ count.rotate_left(48);
+
+    let mut mt = Mt19937GenRand64::new(seed);
+    let mut uuid: FSUUID = [0; 16];
+    uuid[..8].copy_from_slice(&mt.next_u64().to_le_bytes());
+    uuid[8..].copy_from_slice(&mt.next_u64().to_le_bytes());
+
+    // version 4, variant 1
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+
+    uuid
+}
+
+/// What mkfs can set up besides the defaults
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MkfsOptions {
+    /// Clusters for a metadata journal, 0 for none. See Journal
+    pub journal_clusters: u64,
+}
+
+/// Format a fresh NeFS partition of `n_clusters` on the driver. Cluster 0 gets the superblock, followed by an empty skiplist head,
+/// the free list and the node map. Then the root directory goes in as the first commit
+pub fn mkfs(
+    driver: &mut impl BlockDriver,
+    n_clusters: u64,
+    label: &str,
+) -> Result<SuperBlock, &'static str> {
+    mkfs_with(driver, n_clusters, label, MkfsOptions::default())
+}
+
+/// mkfs, with `options`. A journal takes its clusters right after the reserved ones
+pub fn mkfs_with(
+    driver: &mut impl BlockDriver,
+    n_clusters: u64,
+    label: &str,
+    options: MkfsOptions,
+) -> Result<SuperBlock, &'static str> {
+    let journal_clusters = options.journal_clusters;
+    if journal_clusters != 0 && journal_clusters < journal::MIN_JOURNAL_CLUSTERS {
+        return Err("journal too small");
+    }
+    if n_clusters < MIN_CLUSTERS + journal_clusters {
+        return Err("partition too small for NeFS");
+    }
+
+    let label_bytes = label.as_bytes();
+    let mut label_buf = [0u8; 0x100];
+    if label_bytes.len() > label_buf.len() {
+        return Err("label longer than 256 bytes");
+    }
+    label_buf[..label_bytes.len()].copy_from_slice(label_bytes);
+
+    // empty skiplist, the head has every level but nothing to point to yet
+    let skiplist_head = InternalNode {
+        header: NodeHeader::new(MAX_INTERNAL_ITEMS_PER_NODE as u64),
+        key: 0,
+        leaf: 0,
+        pointers: [0; MAX_INTERNAL_ITEMS_PER_NODE],
+    };
+
+    // every cluster after the reserved ones is free, but for the journal
+    let mut free_list = FreeList::new(FREE_LIST_CLUSTER, N_RESERVED_CLUSTERS, n_clusters);
+    let journal_addr = match free_list.alloc(journal_clusters) {
+        Some(region) => region.cluster_start_number,
+        None => 0,
+    };
+    // with the head as the only node
+    let node_map = NodeMap::format(
+        &[SKIPLIST_HEAD_CLUSTER],
+        NODE_MAP_CLUSTER,
+        NODE_MAP_CHUNK_CLUSTER,
+    )
+    .map_err(|e| e.as_str())?;
+
+    let mut superblock = SuperBlock {
+        magic: NEFS_MAGIC,
+        fs_uuid: generate_uuid(label, n_clusters),
+        checksum: 0,
+        label: label_buf,
+        generation: 0,
+        physical_addr_of_partition: driver.physical_offset(),
+        core_fs_skiplist_addr: SKIPLIST_HEAD_NODE,
+        free_cluster_list_addr: FREE_LIST_CLUSTER,
+        node_map_addr: NODE_MAP_CLUSTER,
+        refcounts_addr: 0,
+        snapshots_addr: 0,
+        journal_addr,
+        journal_clusters,
+        n_sectors_total: n_clusters,
+        n_sectors_used: N_RESERVED_CLUSTERS + journal_clusters,
+        sector_size_bytes: SECTOR_SIZE as u16,
+        fs_node_size_bytes: DEFAULT_LEAF_NODE_SIZE,
+    };
+    superblock.checksum = superblock.compute_checksum();
+
+    write_block(
+        driver,
+        SKIPLIST_HEAD_CLUSTER,
+        encode_cluster(&skiplist_head).map_err(|e| e.as_str())?,
+    )
+    .map_err(|_| "block device error")?;
+    for (cluster, block) in node_map {
+        write_block(driver, cluster, block).map_err(|_| "block device error")?;
+    }
+    free_list.store(driver).map_err(|e| e.as_str())?;
+    if journal_clusters != 0 {
+        Journal::format(driver, journal_addr).map_err(|e| e.as_str())?;
+    }
+    // superblocks last, a half formatted partition shouldnt look valid
+    // both slots, so a copy left over from an older format cant win at mount
+    for slot in SUPERBLOCK_CLUSTERS {
+        write_block(driver, slot, superblock.to_disk_format()).map_err(|_| "block device error")?;
+    }
+
+    // now its a valid empty fs, give it a root
+    let mut volume = Volume::open(&mut *driver).map_err(|_| "partition unreadable after format")?;
+    let mut index = SkipList::open(volume.superblock());
+    let root_leaf = index
+        .insert(&mut volume, ROOT_INODE)
+        .map_err(|e| e.as_str())?;
+    // no clock this early, the root starts at the epoch
+    let root = LeafNode::new(
+        ROOT_INODE,
+        &InodeRecord::new(FileType::Dir, 0, 0, Timestamp::default()),
+        ItemType::Directory(Directory::new(ROOT_INODE)),
+    );
+    volume
+        .write_node(root_leaf, &root)
+        .map_err(|e| e.as_str())?;
+    volume.commit().map_err(|e| e.as_str())?;
+
+    // hand back what is on disk now
+    let mut superblock = volume.superblock().clone();
+    superblock.checksum = superblock.compute_checksum();
+
+    Ok(superblock)
+}
+
+/// A superblock slot that didnt check out, and why
+pub type BadSlot = (ClusterNumber, MountError);
+
+/// Read both superblock slots and take the newest one that checks out. Also returns the other slot if it didnt
+pub fn read_superblock(
+    driver: &mut impl BlockDriver,
+) -> Result<(SuperBlock, Option<BadSlot>), MountError> {
+    let mut read = |slot| {
+        let block = read_block(driver, slot)?;
+        SuperBlock::from_disk_format(&block)
+    };
+    let first = read(SUPERBLOCK_CLUSTERS[0]);
+    let second = read(SUPERBLOCK_CLUSTERS[1]);
+
+    match (first, second) {
+        (Ok(a), Ok(b)) if b.generation > a.generation => Ok((b, None)),
+        (Ok(a), Ok(_)) => Ok((a, None)),
+        (Ok(a), Err(e)) => Ok((a, Some((SUPERBLOCK_CLUSTERS[1], e)))),
+        (Err(e), Ok(b)) => Ok((b, Some((SUPERBLOCK_CLUSTERS[0], e)))),
+        (Err(e), Err(_)) => Err(e),
+    }
+}
+
+/// Roll how many levels a new skiplist tower gets. Always at least 1, at most the head's height
+pub fn generate_level(mt: &mut Mt19937GenRand64) -> usize {
+    // another way is to slice the 64-bit generated number up into 8 chunks and check each one %2 break if 0 right away or go next if all 8 are 1
+    let mut level = 1;
+
+    // keep generating a level by % 2
+    while level < MAX_INTERNAL_ITEMS_PER_NODE {
+        let val = mt.next_u64() % 2;
+        // rolled a nothing, break
+        if val == 0 {
+            break;
+        }
+
+        // rolled a 1, increment level
+        level += 1;
+    }
+
+    level
+}
+
+/// A partition as the fs sees it: the driver, its superblock, where free clusters are and where each node is
+///
+/// Metadata is copy on write. A node that is part of the last commit is never written over, changing it puts it in a
+/// new cluster and moves its node map entry. Clusters the last commit uses are only freed by the next one,
+/// so whatever happens before `commit` lands, the last commit is still whole on disk
+/// File contents are written in place, only the extents pointing at them are copied. Unless a snapshot or a clone shares the
+/// cluster, then it is copied too, see RefCounts
+/// A fs made with a journal writes nodes the last commit has in place too, logging them first, see Journal
+pub struct Volume<D: BlockDriver> {
+    driver: D,
+    superblock: SuperBlock,
+    free_list: FreeList,
+    node_map: NodeMap,
+    refcounts: RefCounts,
+    snapshots: SnapshotTable,
+    // clusters the last commit uses that have been let go of. Free once the next commit lands
+    pending: Vec<DataNode>,
+    // node clusters handed out since the last commit. Nothing committed points at them, so they can be written over
+    fresh: BTreeSet<ClusterNumber>,
+    // anything to commit
+    dirty: bool,
+    // dirty only with access times, which the flush policy doesnt count unless it keeps them
+    atime_only: bool,
+    // the superblock slot that was skipped at mount, if one was
+    bad_slot: Option<BadSlot>,
+    // nothing is written, see NeFS::snapshot_view
+    read_only: bool,
+    // None for a fs made without one, then every node is copied on write
+    journal: Option<Journal>,
+    clock: Clock,
+    flush_policy: FlushPolicy,
+    // changes since the last commit and when the first of them was made, see FlushPolicy
+    n_pending: u64,
+    pending_since: Timestamp,
+    // why the last commit the policy asked for failed, None once one works
+    commit_error: Option<FsError>,
+}
+
+impl<D: BlockDriver> Volume<D> {
+    /// Read and validate the superblock, free list and node map
+    pub fn open(mut driver: D) -> Result<Self, MountError> {
+        let (mut superblock, mut bad_slot) = read_superblock(&mut driver)?;
+        if let Some(device_clusters) = driver.device_clusters() {
+            if superblock.n_sectors_total > device_clusters {
+                return Err(MountError::DeviceTooSmall {
+                    n_clusters: superblock.n_sectors_total,
+                    device_clusters,
+                });
+            }
+        }
+        if journal::replay(&mut driver, &superblock)? {
+            log::info!(
+                "finished commit {} from the journal",
+                superblock.generation + 1
+            );
+            (superblock, bad_slot) = read_superblock(&mut driver)?;
+        }
+        if let Some((slot, e)) = bad_slot {
+            // nodes written in place can leave the older slot pointing at a tree that isnt there anymore, see Journal
+            if !journal::fallback_is_safe(&mut driver, &superblock)? {
+                log::error!(
+                    "superblock in cluster {} is unusable ({:?}), and the journal cant vouch for generation {}",
+                    slot,
+                    e,
+                    superblock.generation
+                );
+                return Err(e);
+            }
+            log::warn!(
+                "superblock in cluster {} is unusable ({:?}), using generation {}",
+                slot,
+                e,
+                superblock.generation
+            );
+        }
+
+        let free_list = FreeList::load(&mut driver, superblock.free_cluster_list_addr)?;
+        let node_map = NodeMap::load(&mut driver, superblock.node_map_addr)?;
+        let refcounts = RefCounts::load(&mut driver, superblock.refcounts_addr)?;
+        let snapshots = SnapshotTable::load(&mut driver, superblock.snapshots_addr)?;
+        let journal = Journal::of(&superblock);
+
+        Ok(Self {
+            driver,
+            superblock,
+            free_list,
+            node_map,
+            refcounts,
+            snapshots,
+            pending: Vec::new(),
+            fresh: BTreeSet::new(),
+            dirty: false,
+            atime_only: false,
+            bad_slot,
+            read_only: false,
+            journal,
+            clock: epoch_clock,
+            flush_policy: FlushPolicy::MANUAL,
+            n_pending: 0,
+            pending_since: Timestamp::default(),
+            commit_error: None,
+        })
+    }
+
+    /// The superblock slot that didnt check out at mount, if the other one had to be used
+    pub fn bad_superblock_slot(&self) -> Option<BadSlot> {
+        self.bad_slot
+    }
+
+    pub fn superblock(&self) -> &SuperBlock {
+        &self.superblock
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fail every change to a read only volume before it starts
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+
+        Ok(())
+    }
+
+    /// The time, by whatever clock the fs was given
+    pub fn now(&self) -> Timestamp {
+        (self.clock)()
+    }
+
+    /// Give the driver back. Anything not committed is dropped
+    pub fn into_driver(self) -> D {
+        self.driver
+    }
+
+    pub fn read_cluster(&mut self, cluster_number: ClusterNumber) -> Result<Block, FsError> {
+        Ok(read_block(&mut self.driver, cluster_number)?)
+    }
+
+    pub fn write_cluster(
+        &mut self,
+        cluster_number: ClusterNumber,
+        block: Block,
+    ) -> Result<(), FsError> {
+        self.check_writable()?;
+        Ok(write_block(&mut self.driver, cluster_number, block)?)
+    }
+
+    /// Cluster node `number` is in right now
+    pub fn node_cluster(&self, number: NodeNumber) -> Result<ClusterNumber, FsError> {
+        // a pointer to a node that isnt there
+        self.node_map.get(number).ok_or(FsError::Corrupt(number))
+    }
+
+    /// Read a node's cluster, checking it against its checksum. A write waiting in the journal is newer than the disk
+    fn read_node_cluster(&mut self, number: NodeNumber) -> Result<Block, FsError> {
+        let cluster_number = self.node_cluster(number)?;
+        if let Some(block) = self.journal.as_ref().and_then(|j| j.staged(cluster_number)) {
+            return Ok(*block);
+        }
+
+        Self::read_mapped(&mut self.driver, &self.node_map, number)
+    }
+
+    /// Read a node's cluster as `map` has it, which doesnt have to be the live one
+    fn read_mapped(driver: &mut D, map: &NodeMap, number: NodeNumber) -> Result<Block, FsError> {
+        let cluster_number = map.get(number).ok_or(FsError::Corrupt(number))?;
+        let block = read_block(driver, cluster_number)?;
+        checksum::verify_node(&block, cluster_number)?;
+
+        Ok(block)
+    }
+
+    pub fn read_internal(&mut self, number: NodeNumber) -> Result<InternalNode, FsError> {
+        let block = self.read_node_cluster(number)?;
+        let (node, _): (InternalNode, usize) = bincode::decode_from_slice(&block, BINCODE_CONFIG)
+            .map_err(|_| FsError::Corrupt(number))?;
+
+        Ok(node)
+    }
+
+    pub fn read_leaf(&mut self, number: NodeNumber) -> Result<LeafNode, FsError> {
+        let block = self.read_node_cluster(number)?;
+        let (node, _): (LeafNode, usize) = bincode::decode_from_slice(&block, BINCODE_CONFIG)
+            .map_err(|_| FsError::Corrupt(number))?;
+
+        Ok(node)
+    }
+
+    pub fn read_dir_node(&mut self, number: NodeNumber) -> Result<DirEntryNode, FsError> {
+        let block = self.read_node_cluster(number)?;
+        let (node, _): (DirEntryNode, usize) = bincode::decode_from_slice(&block, BINCODE_CONFIG)
+            .map_err(|_| FsError::Corrupt(number))?;
+
+        Ok(node)
+    }
+
+    pub fn write_node<T: Encode>(&mut self, number: NodeNumber, node: &T) -> Result<(), FsError> {
+        let block = encode_cluster(node)?;
+        self.write_node_block(number, block)
+    }
+
+    /// Write an already encoded node. If the last commit has it, it goes to a new cluster instead,
+    /// or with a journal, in place once the next commit has logged it
+    pub fn write_node_block(&mut self, number: NodeNumber, block: Block) -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut cluster_number = self.node_cluster(number)?;
+
+        if !self.fresh.contains(&cluster_number) {
+            // a snapshot or clone has to keep seeing the old one
+            let shared = self.is_shared(cluster_number);
+            if let Some(journal) = self.journal.as_mut().filter(|_| !shared) {
+                if journal.stage(cluster_number, block) {
+                    self.dirty = true;
+                    return Ok(());
+                }
+            }
+
+            let copy = self.alloc_fresh()?;
+            self.release(DataNode::new(1, cluster_number));
+            self.node_map.set(number, copy);
+            cluster_number = copy;
+        }
+
+        self.dirty = true;
+        self.write_cluster(cluster_number, block)
+    }
+
+    /// A new node. Write it before anything points at it
+    pub fn alloc_node(&mut self) -> Result<NodeNumber, FsError> {
+        let cluster_number = self.alloc_fresh()?;
+
+        match self.node_map.insert(cluster_number) {
+            Ok(number) => Ok(number),
+            Err(e) => {
+                self.fresh.remove(&cluster_number);
+                self.free_list.free(DataNode::new(1, cluster_number));
+                self.update_used();
+                Err(e)
+            }
+        }
+    }
+
+    /// Drop a node. Its cluster is free right away if the last commit never saw it
+    pub fn free_node(&mut self, number: NodeNumber) -> Result<(), FsError> {
+        self.check_writable()?;
+        let cluster_number = self
+            .node_map
+            .remove(number)
+            .ok_or(FsError::Corrupt(number))?;
+
+        if self.fresh.remove(&cluster_number) {
+            self.free_list.free(DataNode::new(1, cluster_number));
+        } else {
+            if let Some(journal) = &mut self.journal {
+                journal.unstage(cluster_number);
+            }
+            self.release(DataNode::new(1, cluster_number));
+        }
+        self.update_used();
+
+        Ok(())
+    }
+
+    fn alloc_fresh(&mut self) -> Result<ClusterNumber, FsError> {
+        let cluster_number = self.alloc(1)?.cluster_start_number;
+        self.fresh.insert(cluster_number);
+
+        Ok(cluster_number)
+    }
+
+    pub fn free_list(&self) -> &FreeList {
+        &self.free_list
+    }
+
+    /// Free clusters a commit might need. Nothing else gets them, so there is always room to commit
+    fn commit_reserve(&self) -> u64 {
+        let n_tables = self.node_map.n_clusters()
+            + self.refcounts.n_store_clusters()
+            + self.snapshots.n_store_clusters();
+        // each of those can let go of a cluster it was in before
+        let n_released = self.pending.len() + n_tables as usize + 1;
+
+        n_tables + self.free_list.n_relocate_clusters(n_released)
+    }
+
+    /// Fail now if `n` clusters cant be had, for changes that cant be undone halfway through
+    pub fn ensure_free(&self, n: u64) -> Result<(), FsError> {
+        self.check_writable()?;
+        if self.free_list.n_free() < n + self.commit_reserve() {
+            return Err(FsError::NoSpace);
+        }
+
+        Ok(())
+    }
+
+    /// Up to `n` contiguous clusters, see FreeList::alloc. Less than `n` means no run was big enough, ask again for the rest
+    pub fn alloc(&mut self, n: u64) -> Result<DataNode, FsError> {
+        self.check_writable()?;
+        let available = self
+            .free_list
+            .n_free()
+            .saturating_sub(self.commit_reserve());
+        if available == 0 {
+            return Err(FsError::NoSpace);
+        }
+
+        let data_node = self
+            .free_list
+            .alloc(core::cmp::min(n, available))
+            .ok_or(FsError::NoSpace)?;
+        self.update_used();
+
+        Ok(data_node)
+    }
+
+    /// Hand back a run alloc just gave out, before anything points at it. Free again right away
+    pub fn unalloc(&mut self, data_node: DataNode) {
+        self.free_list.free(data_node);
+        self.update_used();
+    }
+
+    /// Give a run of clusters back. It is handed out again once the next commit lands, unless a snapshot still has it
+    pub fn free(&mut self, data_node: DataNode) -> Result<(), FsError> {
+        self.check_writable()?;
+        self.release(data_node);
+        self.update_used();
+
+        Ok(())
+    }
+
+    /// Let go of clusters the last commit has. The ones no snapshot holds on to are free after the next commit
+    fn release(&mut self, data_node: DataNode) {
+        let freed = self.refcounts.release(data_node);
+        self.pending.extend(freed);
+    }
+
+    /// Whether a snapshot or another file still has the cluster, so it has to be copied before it is written
+    pub fn is_shared(&self, cluster_number: ClusterNumber) -> bool {
+        self.refcounts.is_shared(cluster_number)
+    }
+
+    /// One more owner for every cluster of `runs`, for a file that starts sharing them. Fails with nothing changed
+    /// unless there is room to commit the counts and to write the leaf that will point at them
+    pub fn share(&mut self, runs: &[DataNode]) -> Result<(), FsError> {
+        self.check_writable()?;
+        let refcounts = self.refcounts.clone();
+
+        for run in runs {
+            self.refcounts.share(*run);
+        }
+        if let Err(e) = self.ensure_free(1) {
+            self.refcounts = refcounts;
+            return Err(e);
+        }
+        self.update_used();
+
+        Ok(())
+    }
+
+    /// Keep the in memory used count up to date. Clusters waiting on a commit are still used
+    fn update_used(&mut self) {
+        self.superblock.n_sectors_used = self.superblock.n_sectors_total - self.free_list.n_free();
+        self.dirty = true;
+    }
+
+    /// Whether anything was written since the last commit. Beyond dirty, so a commit never leaves a fresh or staged
+    /// node or a freed cluster behind
+    fn has_uncommitted(&self) -> bool {
+        self.dirty
+            || !self.fresh.is_empty()
+            || !self.pending.is_empty()
+            || self.node_map.is_dirty()
+            || self.journal.as_ref().is_some_and(|j| !j.is_empty())
+    }
+
+    /// Make everything since the last commit the state on disk. New nodes are already written, so this writes the node map
+    /// and free list to new clusters as well, then a superblock pointing at them with the next generation
+    /// A crash before the superblock lands leaves the last commit, after it this one. With nodes written in place,
+    /// the journal's commit record is that point instead, see Journal
+    pub fn commit(&mut self) -> Result<(), FsError> {
+        if self.read_only {
+            return Ok(());
+        }
+        if !self.has_uncommitted() {
+            self.n_pending = 0;
+            self.commit_error = None;
+            self.atime_only = false;
+            return Ok(());
+        }
+
+        let mut node_map = self.node_map.clone();
+        let mut free_list = self.free_list.clone();
+        let mut refcounts = self.refcounts.clone();
+        let mut snapshots = self.snapshots.clone();
+        let mut released = self.pending.clone();
+
+        // a snapshot can have the old map clusters too
+        let mut map_released = Vec::new();
+        node_map.store(&mut self.driver, &mut free_list, &mut map_released)?;
+        for run in map_released {
+            released.extend(refcounts.release(run));
+        }
+        snapshots.store(&mut self.driver, &mut free_list, &mut released)?;
+        // last, everything before can change the counts
+        refcounts.store(&mut self.driver, &mut free_list, &mut released)?;
+        let free_list = free_list.relocated(&mut self.driver, &released)?;
+
+        let mut superblock = self.superblock.clone();
+        superblock.generation += 1;
+        superblock.node_map_addr = node_map.root();
+        superblock.free_cluster_list_addr = free_list.addr();
+        superblock.refcounts_addr = refcounts.addr();
+        superblock.snapshots_addr = snapshots.addr();
+        superblock.n_sectors_used = superblock.n_sectors_total - free_list.n_free();
+
+        // everything the superblock points at has to be down before it is
+        // it goes over the older copy, the last commit's stays as it is
+        self.driver.flush()?;
+        let slot = SUPERBLOCK_CLUSTERS[(superblock.generation % 2) as usize];
+        match self.journal.as_mut().filter(|j| !j.is_empty()) {
+            Some(journal) => journal.commit(
+                &mut self.driver,
+                superblock.generation,
+                slot,
+                superblock.to_disk_format(),
+            )?,
+            None => {
+                self.write_cluster(slot, superblock.to_disk_format())?;
+                self.driver.flush()?;
+            }
+        }
+
+        self.superblock = superblock;
+        self.node_map = node_map;
+        self.free_list = free_list;
+        self.refcounts = refcounts;
+        self.snapshots = snapshots;
+        self.pending.clear();
+        self.fresh.clear();
+        self.dirty = false;
+        self.atime_only = false;
+        self.n_pending = 0;
+        self.commit_error = None;
+
+        Ok(())
+    }
+}
+
+// -----------------
+// USER API
+// -----------------
+
+/// A mounted NeFS partition
+pub struct NeFS<D: BlockDriver> {
+    volume: Volume<D>,
+    index: SkipList,
+    // recently used inodes and their leaf nodes, so they dont need an index search
+    inodes: Lru<InodeNumber, NodeNumber>,
+    // owner of everything created
+    uid: u32,
+    gid: u32,
+}
+
+impl<D: BlockDriver> NeFS<D> {
+    /// Read the superblocks in clusters 0 and 1, take the newer valid one and check it describes a partition we can use
+    /// If one slot is unusable mount falls back to the other, unless a journal says its tree was written over, see bad_superblock_slot
+    pub fn mount(driver: D) -> Result<Self, MountError> {
+        let volume = Volume::open(driver)?;
+        let index = SkipList::open(volume.superblock());
+
+        Ok(Self {
+            volume,
+            index,
+            inodes: Lru::new(INODE_CACHE_SIZE),
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    /// Where timestamps come from. Until this is called, everything happens at the epoch
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.volume.clock = clock;
+    }
+
+    /// Who new inodes belong to
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.uid = uid;
+        self.gid = gid;
+    }
+
+    pub fn superblock(&self) -> &SuperBlock {
+        self.volume.superblock()
+    }
+
+    /// A snapshot view, see snapshot_view
+    pub fn is_read_only(&self) -> bool {
+        self.volume.is_read_only()
+    }
+
+    /// Whether mount had to fall back to the older superblock, and what was wrong with the newer one
+    pub fn bad_superblock_slot(&self) -> Option<BadSlot> {
+        self.volume.bad_superblock_slot()
+    }
+
+    /// Inodes are looked up by the skiplist, and their nodes live on the volume. Empties the inode cache, the index
+    /// could change under it
+    pub fn index(&mut self) -> (&mut SkipList, &mut Volume<D>) {
+        self.inodes.clear();
+        (&mut self.index, &mut self.volume)
+    }
+
+    /// How the inode cache is doing
+    pub fn inode_cache_stats(&self) -> CacheStats {
+        self.inodes.stats()
+    }
+
+    /// Keep up to `capacity` inodes cached from now on. Starts out empty again
+    pub fn set_inode_cache_capacity(&mut self, capacity: usize) {
+        self.inodes = Lru::new(capacity);
+    }
+
+    /// The leaf node of inode `number`, from the cache if it was used recently
+    fn leaf_of(&mut self, number: InodeNumber) -> Result<NodeNumber, FsError> {
+        if let Some(leaf_node) = self.inodes.get(&number) {
+            return Ok(*leaf_node);
+        }
+
+        let leaf_node = self.index.lookup(&mut self.volume, number)?;
+        self.inodes.insert(number, leaf_node);
+
+        Ok(leaf_node)
+    }
+
+    /// Inode `number`'s leaf node, what is in it and its record
+    fn read_leaf_of(
+        &mut self,
+        number: InodeNumber,
+    ) -> Result<(NodeNumber, LeafNode, InodeRecord), FsError> {
+        let leaf_node = self.leaf_of(number)?;
+        let leaf = self.volume.read_leaf(leaf_node)?;
+        // a leaf that checks out but is some other inode's is an old node left in a cluster the write never reached
+        if leaf.inode != number {
+            self.inodes.remove(&number);
+            return Err(FsError::Corrupt(leaf_node));
+        }
+        let record = leaf.record().ok_or(FsError::Corrupt(leaf_node))?;
+
+        Ok((leaf_node, leaf, record))
+    }
+
+    /// Add an inode holding `item_type`, numbered one past the highest in use
+    fn create_item(
+        &mut self,
+        file_type: FileType,
+        item_type: ItemType,
+    ) -> Result<InodeNumber, FsError> {
+        let number = self.index.last(&mut self.volume)?.unwrap_or(0) + 1;
+        let leaf_node = self.index.insert(&mut self.volume, number)?;
+
+        let mut record = InodeRecord::new(file_type, self.uid, self.gid, self.volume.now());
+        if let ItemType::Symlink(target) = &item_type {
+            record.size = target.len() as u64;
+        }
+        let leaf = LeafNode::new(number, &record, item_type);
+        if let Err(e) = self.volume.write_node(leaf_node, &leaf) {
+            self.index.remove(&mut self.volume, number)?;
+            return Err(e);
+        }
+
+        Ok(number)
+    }
+
+    /// Add an empty file
+    pub fn create_inode(&mut self) -> Result<InodeNumber, FsError> {
+        self.create_item(FileType::File, ItemType::Payload(Payload::default()))
+    }
+
+    /// Add an empty directory under `parent`. Doesnt link it into the parent, see Dir::insert
+    pub fn create_dir(&mut self, parent: InodeNumber) -> Result<InodeNumber, FsError> {
+        self.create_item(FileType::Dir, ItemType::Directory(Directory::new(parent)))
+    }
+
+    /// Add a symlink to `target`
+    pub fn create_symlink(&mut self, target: &str) -> Result<InodeNumber, FsError> {
+        self.create_item(FileType::Symlink, ItemType::Symlink(String::from(target)))
+    }
+
+    /// The inode's attributes
+    pub fn record(&mut self, number: InodeNumber) -> Result<InodeRecord, FsError> {
+        Ok(self.read_leaf_of(number)?.2)
+    }
+
+    /// Change an inode's attributes in place. Bumps `changed`
+    pub fn update_record(
+        &mut self,
+        number: InodeNumber,
+        update: impl FnOnce(&mut InodeRecord),
+    ) -> Result<InodeRecord, FsError> {
+        let (leaf_node, mut leaf, mut record) = self.read_leaf_of(number)?;
+
+        update(&mut record);
+        record.changed = self.volume.now();
+        leaf.record = record.to_bytes();
+        self.volume.write_node(leaf_node, &leaf)?;
+
+        Ok(record)
+    }
+
+    /// Open an inode for reading and writing
+    pub fn inode(&mut self, number: InodeNumber) -> Result<Inode<'_, D>, FsError> {
+        let (leaf_node, leaf, record) = self.read_leaf_of(number)?;
+
+        let payload = match leaf.item_type {
+            ItemType::Payload(payload) => payload,
+            ItemType::Directory(_) => return Err(FsError::IsADirectory),
+            ItemType::Symlink(_) => return Err(FsError::IsASymlink),
+        };
+
+        Ok(Inode {
+            volume: &mut self.volume,
+            number,
+            leaf: leaf_node,
+            record,
+            data_nodes: payload.data_nodes,
+        })
+    }
+
+    /// Open a directory inode
+    pub fn dir(&mut self, number: InodeNumber) -> Result<Dir<'_, D>, FsError> {
+        let (leaf_node, leaf, record) = self.read_leaf_of(number)?;
+
+        match leaf.item_type {
+            ItemType::Directory(dir) => {
+                Ok(Dir::new(&mut self.volume, number, leaf_node, record, dir))
+            }
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// Drop an inode from the index and give back its clusters. A directory's entries go with it, not the inodes they name
+    pub fn remove_inode(&mut self, number: InodeNumber) -> Result<(), FsError> {
+        match self.inode(number) {
+            Ok(mut inode) => inode.shrink(0)?,
+            Err(FsError::IsADirectory) => self.dir(number)?.free_buckets()?,
+            // the target is in the leaf, nothing else to free
+            Err(FsError::IsASymlink) => {}
+            Err(e) => return Err(e),
+        }
+        self.inodes.remove(&number);
+        self.index.remove(&mut self.volume, number)
+    }
+
+    /// Make every change so far survive a crash, see Volume::commit
+    pub fn commit(&mut self) -> Result<(), FsError> {
+        self.volume.commit()
+    }
+
+    /// Commit, flush the driver and give it back
+    pub fn unmount(mut self) -> D {
+        if let Err(e) = self.volume.commit() {
+            log::error!("commit on unmount failed: {}", e.as_str());
+        }
+
+        let mut driver = self.volume.into_driver();
+        if let Err(e) = driver.flush() {
+            log::error!("flush on unmount failed: {:?}", e);
+        }
+
+        driver
+    }
+}
+
+/// An open file. Holds the inode's record and extents in memory and writes its leaf back whenever they change
+pub struct Inode<'fs, D: BlockDriver> {
+    volume: &'fs mut Volume<D>,
+    number: InodeNumber,
+    leaf: NodeNumber,
+    record: InodeRecord,
+    data_nodes: Vec<DataNode>,
+}
+
+impl<'fs, D: BlockDriver> Inode<'fs, D> {
+    pub fn number(&self) -> InodeNumber {
+        self.number
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.record.size
+    }
+
+    pub fn record(&self) -> &InodeRecord {
+        &self.record
+    }
+
+    pub fn data_nodes(&self) -> &[DataNode] {
+        &self.data_nodes
+    }
+
+    /// Number of clusters allocated to the file
+    fn n_clusters(&self) -> u64 {
+        self.data_nodes.iter().map(|d| d.clusters_used).sum()
+    }
+
+    /// The cluster holding the `index`th cluster of the file
+    fn cluster_of(&self, index: u64) -> ClusterNumber {
+        let mut skipped = 0;
+        for data_node in &self.data_nodes {
+            if index < skipped + data_node.clusters_used {
+                return data_node.cluster_start_number + index - skipped;
+            }
+            skipped += data_node.clusters_used;
+        }
+
+        unreachable!("cluster {} past the end of inode {}", index, self.number)
+    }
+
+    /// The cluster to write the `index`th cluster of the file to. If something else has it, the file gets a cluster of its own
+    /// in its place, and the caller writes all of it
+    fn unshare(&mut self, index: u64) -> Result<ClusterNumber, FsError> {
+        let cluster_number = self.cluster_of(index);
+        if !self.volume.is_shared(cluster_number) {
+            return Ok(cluster_number);
+        }
+
+        let copy = self.volume.alloc(1)?.cluster_start_number;
+        self.volume.free(DataNode::new(1, cluster_number))?;
+
+        // split the data node around it
+        let mut skipped = 0;
+        for i in 0..self.data_nodes.len() {
+            let data_node = self.data_nodes[i];
+            if index < skipped + data_node.clusters_used {
+                let at = index - skipped;
+                let mut pieces = Vec::new();
+                if at > 0 {
+                    pieces.push(DataNode::new(at, data_node.cluster_start_number));
+                }
+                pieces.push(DataNode::new(1, copy));
+                if at + 1 < data_node.clusters_used {
+                    pieces.push(DataNode::new(
+                        data_node.clusters_used - at - 1,
+                        data_node.cluster_start_number + at + 1,
+                    ));
+                }
+                self.data_nodes.splice(i..i + 1, pieces);
+                break;
+            }
+            skipped += data_node.clusters_used;
+        }
+
+        Ok(copy)
+    }
+
+    fn to_leaf(&self) -> LeafNode {
+        LeafNode::new(
+            self.number,
+            &self.record,
+            ItemType::Payload(Payload {
+                data_nodes: self.data_nodes.clone(),
+            }),
+        )
+    }
+
+    fn write_leaf(&mut self) -> Result<(), FsError> {
+        let leaf = self.to_leaf();
+        self.volume.write_node(self.leaf, &leaf)
+    }
+
+    /// The data was read fine, so a failed update is only logged
+    fn touch_accessed(&mut self, now: Timestamp) {
+        let accessed = self.record.accessed;
+        self.record.accessed = now;
+
+        let leaf = self.to_leaf();
+        if let Err(e) = self.volume.write_access_time(self.leaf, &leaf) {
+            self.record.accessed = accessed;
+            log::warn!(
+                "access time of inode {} not updated: {}",
+                self.number,
+                e.as_str()
+            );
+        }
+    }
+
+    /// Contents changed, which changes the record too
+    fn touch_modified(&mut self) {
+        let now = self.volume.now();
+        self.record.modified = now;
+        self.record.changed = now;
+    }
+
+    /// Allocate clusters until the file has `n_clusters`. Clusters outside `overwritten` are zeroed, so nothing stale shows up past the old end
+    /// If that fails partway, the file keeps the clusters it had and the new ones go back
+    fn grow(&mut self, n_clusters: u64, overwritten: core::ops::Range<u64>) -> Result<(), FsError> {
+        let mut new_runs = Vec::new();
+        if let Err(e) = self.alloc_zeroed(n_clusters, &overwritten, &mut new_runs) {
+            for run in new_runs {
+                self.volume.unalloc(run);
+            }
+            return Err(e);
+        }
+
+        for data_node in new_runs {
+            // extend the last data node if the new run is right after it
+            match self.data_nodes.last_mut() {
+                Some(last)
+                    if last.cluster_start_number + last.clusters_used
+                        == data_node.cluster_start_number =>
+                {
+                    last.clusters_used += data_node.clusters_used
+                }
+                _ => self.data_nodes.push(data_node),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The runs grow adds, in `runs` as they are allocated
+    fn alloc_zeroed(
+        &mut self,
+        n_clusters: u64,
+        overwritten: &core::ops::Range<u64>,
+        runs: &mut Vec<DataNode>,
+    ) -> Result<(), FsError> {
+        let mut index = self.n_clusters();
+
+        while index < n_clusters {
+            let data_node = self.volume.alloc(n_clusters - index)?;
+            runs.push(data_node);
+
+            for i in 0..data_node.clusters_used {
+                let start = (index + i) * PAGE_SIZE;
+                if start < overwritten.start || start + PAGE_SIZE > overwritten.end {
+                    let cluster_number = data_node.cluster_start_number + i;
+                    self.volume.write_cluster(cluster_number, make_block())?;
+                }
+            }
+
+            index += data_node.clusters_used;
+        }
+
+        Ok(())
+    }
+
+    /// Free clusters from the end until the file has `n_clusters`
+    fn shrink(&mut self, n_clusters: u64) -> Result<(), FsError> {
+        let mut to_free = self.n_clusters().saturating_sub(n_clusters);
+
+        while to_free > 0 {
+            let last = self
+                .data_nodes
+                .last_mut()
+                .expect("more clusters to free than allocated");
+
+            // free the tail of the last data node in one go
+            let n = core::cmp::min(to_free, last.clusters_used);
+            last.clusters_used -= n;
+            let freed = DataNode::new(n, last.cluster_start_number + last.clusters_used);
+            if last.clusters_used == 0 {
+                self.data_nodes.pop();
+            }
+
+            self.volume.free(freed)?;
+            to_free -= n;
+        }
+
+        Ok(())
+    }
+
+    /// Copy `buf` into the file at `offset`, cluster by cluster. Assumes the clusters are allocated
+    fn write_clusters(&mut self, buf: &[u8], offset: u64) -> Result<(), FsError> {
+        let mut written = 0;
+
+        while written < buf.len() {
+            let pos = offset + written as u64;
+            let in_cluster = (pos % PAGE_SIZE) as usize;
+            let len = core::cmp::min(buf.len() - written, PAGE_SIZE as usize - in_cluster);
+            let cluster_number = self.cluster_of(pos / PAGE_SIZE);
+
+            // only read back a cluster we partially overwrite
+            let mut block = if len == PAGE_SIZE as usize {
+                make_block()
+            } else {
+                self.volume.read_cluster(cluster_number)?
+            };
+            block[in_cluster..in_cluster + len].copy_from_slice(&buf[written..written + len]);
+            let cluster_number = self.unshare(pos / PAGE_SIZE)?;
+            self.volume.write_cluster(cluster_number, block)?;
+
+            written += len;
+        }
+
+        Ok(())
+    }
+
+    /// Replace the whole contents of the file
+    pub fn set_contents(&mut self, buf: &[u8]) -> Result<(), FsError> {
+        let n_needed = (buf.len() as u64).div_ceil(PAGE_SIZE);
+        if n_needed > self.n_clusters() {
+            self.grow(n_needed, 0..buf.len() as u64)?;
+        } else {
+            self.shrink(n_needed)?;
+        }
+
+        self.write_clusters(buf, 0)?;
+
+        // keep everything past the end zeroed, grow() relies on it
+        let tail = buf.len() % PAGE_SIZE as usize;
+        if tail != 0 {
+            let index = buf.len() as u64 / PAGE_SIZE;
+            let mut block = self.volume.read_cluster(self.cluster_of(index))?;
+            block[tail..].fill(0);
+            let cluster_number = self.unshare(index)?;
+            self.volume.write_cluster(cluster_number, block)?;
+        }
+
+        self.record.size = buf.len() as u64;
+        self.touch_modified();
+        self.write_leaf()?;
+        self.volume.changed(Change::Data);
+
+        Ok(())
+    }
+
+    /// Write `buf` at `offset`, growing the file if needed. A gap past the old end reads as zeroes
+    pub fn write_bytes(&mut self, buf: &[u8], offset: u64) -> Result<usize, FsError> {
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::FileTooLarge)?;
+        let n_needed = end.div_ceil(PAGE_SIZE);
+        if n_needed > self.n_clusters() {
+            self.grow(n_needed, offset..end)?;
+        }
+
+        self.write_clusters(buf, offset)?;
+
+        if end > self.record.size {
+            self.record.size = end;
+        }
+        self.touch_modified();
+        self.write_leaf()?;
+        self.volume.changed(Change::Data);
+
+        Ok(buf.len())
+    }
+
+    /// Read from `offset` until `buf` is full or the file ends
+    pub fn read_bytes(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, FsError> {
+        if offset >= self.record.size {
+            return Ok(0);
+        }
+
+        let to_read = core::cmp::min(buf.len() as u64, self.record.size - offset) as usize;
+        let mut read = 0;
+
+        while read < to_read {
+            let pos = offset + read as u64;
+            let in_cluster = (pos % PAGE_SIZE) as usize;
+            let len = core::cmp::min(to_read - read, PAGE_SIZE as usize - in_cluster);
+
+            let block = self.volume.read_cluster(self.cluster_of(pos / PAGE_SIZE))?;
+            buf[read..read + len].copy_from_slice(&block[in_cluster..in_cluster + len]);
+
+            read += len;
+        }
+
+        // relatime: only write the leaf for a read if the last access is older than the last change and the clock
+        // has moved past it. A read only volume cant, and leaves it be
+        let now = self.volume.now();
+        if self.record.accessed <= self.record.modified
+            && now > self.record.accessed
+            && !self.volume.is_read_only()
+        {
+            self.touch_accessed(now);
+        }
+
+        Ok(to_read)
+    }
+}
+
+impl<'fs, D: BlockDriver> Readable for Inode<'fs, D> {
+    fn read_all(&mut self) -> String {
+        // Read all the data nodes. NOTE: assuming memory is either cached in RAM
+        // If you need to, call the block driver to actually read from the SSD
+        let mut buf = vec![0; self.record.size as usize];
+        if let Err(e) = self.read_bytes(&mut buf, 0) {
+            // like rewrite, nowhere to put the error
+            log::error!("read of inode {} failed: {}", self.number, e.as_str());
+            return String::new();
+        }
+
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, &'static str> {
+        // If file too small, just read as much as you can. Should return >= 0
+        self.read_bytes(buf, offset).map_err(|e| e.as_str())
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), &'static str> {
+        // basically read_at, but if the file is too small (run into EOF), then it should return an error
+        // and not fill the buf
+        match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= self.record.size => {}
+            _ => return Err("unexpected end of file"),
+        }
+
+        self.read_bytes(buf, offset).map_err(|e| e.as_str())?;
+
+        Ok(())
+    }
+}
+
+impl<'fs, D: BlockDriver> Writable for Inode<'fs, D> {
+    fn rewrite(&mut self, buf: &[u8]) {
+        // the trait has no way to report failure, so the best we can do is log it
+        if let Err(e) = self.set_contents(buf) {
+            log::error!("rewrite of inode {} failed: {}", self.number, e.as_str());
+        }
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<usize, &'static str> {
+        self.write_bytes(buf, offset).map_err(|e| e.as_str())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), &'static str> {
+        // write_at always writes everything or fails
+        self.write_at(buf, offset).map(|_| ())
+    }
+}
+
+// ------------
+// TESTS
+// ------------
+
+#[test]
+fn test_basics() {}
+
+#[test]
+fn test_mkfs_superblock() {
+    use super::block::RamDisk;
+
+    let mut disk = RamDisk::new(64);
+    let superblock = mkfs(&mut disk, 64, "rootfs").unwrap();
+    assert_eq!(superblock.label(), "rootfs");
+    assert_eq!(superblock.n_sectors_total(), 64);
+    assert_eq!(superblock.compute_checksum(), superblock.checksum());
+
+    // what landed on disk decodes back to the same thing
+    let block = read_block(&mut disk, SUPERBLOCK_CLUSTERS[0]).unwrap();
+    let (on_disk, _): (SuperBlock, usize) =
+        bincode::decode_from_slice(&block, BINCODE_CONFIG).unwrap();
+    assert_eq!(on_disk.magic, NEFS_MAGIC);
+    assert_eq!(on_disk.fs_uuid(), superblock.fs_uuid());
+    assert_eq!(on_disk.checksum(), on_disk.compute_checksum());
+}
+
+#[test]
+fn test_mkfs_rejects() {
+    use super::block::RamDisk;
+
+    let mut disk = RamDisk::new(64);
+    assert!(mkfs(&mut disk, 2, "tiny").is_err());
+
+    let long_label = String::from_utf8(vec![b'a'; 0x101]).unwrap();
+    assert!(mkfs(&mut disk, 64, &long_label).is_err());
+
+    // uuids differ between formats
+    let a = mkfs(&mut disk, 64, "a").unwrap();
+    let b = mkfs(&mut disk, 64, "a").unwrap();
+    assert_ne!(a.fs_uuid(), b.fs_uuid());
+}
+
+#[test]
+fn test_mount() {
+    use super::block::RamDisk;
+
+    let mut disk = RamDisk::new(64);
+    let formatted = mkfs(&mut disk, 64, "rootfs").unwrap();
+
+    let fs = NeFS::mount(disk).unwrap();
+    assert_eq!(fs.superblock().fs_uuid(), formatted.fs_uuid());
+    assert_eq!(fs.superblock().label(), "rootfs");
+
+    // never formatted
+    assert_eq!(
+        NeFS::mount(RamDisk::new(64)).err(),
+        Some(MountError::BadMagic(0))
+    );
+}
+
+#[test]
+fn test_mount_refuses() {
+    use super::block::RamDisk;
+
+    let mut disk = RamDisk::new(64);
+    let superblock = mkfs(&mut disk, 64, "rootfs").unwrap();
+    // one bad copy is fine, see test_superblock_fallback
+    let write_both = |disk: &mut RamDisk, block| {
+        for slot in SUPERBLOCK_CLUSTERS {
+            write_block(disk, slot, block).unwrap();
+        }
+    };
+
+    // flip a bit in the label
+    let mut block = superblock.to_disk_format();
+    block[40] ^= 1;
+    write_both(&mut disk, block);
+    assert!(matches!(
+        NeFS::mount(disk.clone()).err(),
+        Some(MountError::BadChecksum { .. })
+    ));
+
+    // valid checksum, but a sector size we dont do
+    let mut odd = superblock.clone();
+    odd.sector_size_bytes = 512;
+    write_both(&mut disk, odd.to_disk_format());
+    assert_eq!(
+        NeFS::mount(disk.clone()).err(),
+        Some(MountError::UnsupportedSectorSize(512))
+    );
+
+    let mut odd = superblock.clone();
+    odd.fs_node_size_bytes = 1024;
+    write_both(&mut disk, odd.to_disk_format());
+    assert_eq!(
+        NeFS::mount(disk.clone()).err(),
+        Some(MountError::UnsupportedNodeSize(1024))
+    );
+
+    // cut short, e.g. an image truncated after mkfs
+    write_both(&mut disk, superblock.to_disk_format());
+    assert!(NeFS::mount(disk.clone()).is_ok());
+    let mut short = super::block::PartitionView::new(disk.clone(), 0, 32);
+    assert_eq!(
+        NeFS::mount(&mut short).err(),
+        Some(MountError::DeviceTooSmall {
+            n_clusters: 64,
+            device_clusters: 32
+        })
+    );
+
+    write_both(&mut disk, [0xff; 4096]);
+    assert_eq!(NeFS::mount(disk).err(), Some(MountError::Undecodable));
+}
+
+#[cfg(test)]
+fn test_fs(n_clusters: u64) -> NeFS<super::block::RamDisk> {
+    use super::block::RamDisk;
+
+    let mut disk = RamDisk::new(n_clusters as usize);
+    mkfs(&mut disk, n_clusters, "rootfs").unwrap();
+    NeFS::mount(disk).unwrap()
+}
+
+#[cfg(test)]
+fn read_file<D: BlockDriver>(fs: &mut NeFS<D>, path: &str) -> Vec<u8> {
+    let mut inode = fs.open(path, OpenFlags::NONE).unwrap();
+    let mut buf = vec![0; inode.size() as usize];
+    inode.read_bytes(&mut buf, 0).unwrap();
+
+    buf
+}
+
+#[test]
+fn test_inode_read_write() {
+    let mut fs = test_fs(256);
+    let number = fs.create_inode().unwrap();
+    assert_eq!(number, ROOT_INODE + 1);
+
+    let mut inode = fs.inode(number).unwrap();
+    inode.rewrite(b"hello nefs");
+    assert_eq!(inode.read_all(), "hello nefs");
+
+    // overwrite in the middle, append past the end
+    assert_eq!(inode.write_at(b"NEFS", 6), Ok(4));
+    inode.write_all_at(b"!", 10).unwrap();
+    assert_eq!(inode.read_all(), "hello NEFS!");
+
+    let mut buf = [0; 4];
+    assert_eq!(inode.read_at(&mut buf, 9), Ok(2));
+    assert_eq!(&buf[..2], b"S!");
+    assert!(inode.read_exact_at(&mut buf, 9).is_err());
+    inode.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(&buf, b"hell");
+
+    // offsets that would wrap around
+    assert!(inode.read_exact_at(&mut buf, u64::MAX - 1).is_err());
+    assert_eq!(
+        inode.write_bytes(&buf, u64::MAX - 1),
+        Err(FsError::FileTooLarge)
+    );
+
+    // everything went through the leaf on disk
+    let mut fs = NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(fs.inode(number).unwrap().read_all(), "hello NEFS!");
+}
+
+#[test]
+fn test_inode_grow_shrink() {
+    let mut fs = test_fs(256);
+    let number = fs.create_inode().unwrap();
+    fs.commit().unwrap();
+    let used = fs.superblock().n_sectors_used();
+
+    let big: Vec<u8> = (0..3 * PAGE_SIZE as usize + 100).map(|i| i as u8).collect();
+    let mut inode = fs.inode(number).unwrap();
+    inode.rewrite(&big);
+    assert_eq!(inode.size(), big.len() as u64);
+    // clusters were handed out in order, so it all fits in one data node
+    assert_eq!(inode.data_nodes().len(), 1);
+    assert_eq!(inode.data_nodes()[0].clusters_used(), 4);
+
+    let mut read = vec![0; big.len()];
+    inode.read_exact_at(&mut read, 0).unwrap();
+    assert_eq!(read, big);
+
+    // shrinking frees the extra clusters, once it is committed
+    inode.rewrite(b"tiny");
+    assert_eq!(inode.data_nodes()[0].clusters_used(), 1);
+    fs.commit().unwrap();
+    assert_eq!(fs.superblock().n_sectors_used(), used + 1);
+
+    // writing past the end leaves a gap of zeroes, not whatever was there before
+    let mut inode = fs.inode(number).unwrap();
+    inode.write_all_at(b"end", 2 * PAGE_SIZE).unwrap();
+    let mut gap = vec![0xff; 2 * PAGE_SIZE as usize - 4];
+    inode.read_exact_at(&mut gap, 4).unwrap();
+    assert!(gap.iter().all(|b| *b == 0));
+
+    fs.remove_inode(number).unwrap();
+    fs.commit().unwrap();
+    assert_eq!(fs.superblock().n_sectors_used(), used - 2);
+    assert_eq!(fs.inode(number).err(), Some(FsError::NotFound));
+}
+
+#[test]
+fn test_alloc_used_in_sync() {
+    let mut fs = test_fs(256);
+    let (_, volume) = fs.index();
+    let total = volume.superblock().n_sectors_total();
+
+    let a = volume.alloc(10).unwrap();
+    let b = volume.alloc(20).unwrap();
+    assert_eq!(a.clusters_used(), 10);
+    assert_eq!(b.cluster_start_number(), a.cluster_start_number() + 10);
+    volume.free(a).unwrap();
+    // the last commit might still need it
+    assert_eq!(
+        volume.superblock().n_sectors_used() + volume.free_list().n_free(),
+        total
+    );
+    assert_ne!(volume.alloc(10).unwrap(), a);
+
+    volume.commit().unwrap();
+    let used = volume.superblock().n_sectors_used();
+    assert_eq!(used + volume.free_list().n_free(), total);
+
+    // free list and count survive a remount
+    let fs = NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(fs.superblock().n_sectors_used(), used);
+
+    // what was freed comes back before the untouched clusters after b
+    let mut fs = fs;
+    let (_, volume) = fs.index();
+    assert!(volume.alloc(10).unwrap().cluster_start_number() < b.cluster_start_number());
+}
+
+#[test]
+fn test_inode_out_of_space() {
+    let mut fs = test_fs(32);
+    let number = fs.create_inode().unwrap();
+
+    let mut inode = fs.inode(number).unwrap();
+    assert!(inode.write_at(&[1; 100], 0).is_ok());
+    assert_eq!(
+        inode.write_at(&[1; 100], 32 * PAGE_SIZE),
+        Err(FsError::NoSpace.as_str())
+    );
+    // what is left is enough to commit
+    fs.commit().unwrap();
+}
+
+#[test]
+fn test_inode_failed_grow_frees() {
+    let mut fs = test_fs(64);
+    let used = fs.superblock().n_sectors_used();
+
+    // runs out partway, the clusters it did get go back
+    fs.create("/f").unwrap();
+    let mut inode = fs.open("/f", OpenFlags::NONE).unwrap();
+    assert_eq!(
+        inode.write_bytes(&[1; 10], 1000 * PAGE_SIZE),
+        Err(FsError::NoSpace)
+    );
+    assert!(inode.data_nodes().is_empty());
+    fs.unlink("/f").unwrap();
+    fs.commit().unwrap();
+
+    let fs = NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(fs.superblock().n_sectors_used(), used);
+}
+
+#[test]
+fn test_inode_record() {
+    use neutronapi::fs::{Readable, Writable};
+
+    let record = InodeRecord {
+        file_type: FileType::Pipe,
+        mode: 0o640,
+        n_links: 3,
+        uid: 1000,
+        gid: 100,
+        size: u64::MAX,
+        created: Timestamp::new(-1, 999_999_999),
+        modified: Timestamp::new(1, 2),
+        accessed: Timestamp::new(3, 4),
+        changed: Timestamp::new(i64::MAX, 0),
+    };
+    assert_eq!(InodeRecord::from_bytes(&record.to_bytes()), Some(record));
+    let mut bytes = record.to_bytes();
+    bytes[0] = 6;
+    assert_eq!(InodeRecord::from_bytes(&bytes), None);
+
+    // writes move modified, reads only move accessed when it is behind
+    let mut fs = test_fs(64);
+    fs.set_clock(|| Timestamp::new(10, 0));
+    let number = fs.create_inode().unwrap();
+    fs.set_clock(|| Timestamp::new(20, 0));
+    fs.inode(number).unwrap().rewrite(b"hello");
+
+    let record = fs.record(number).unwrap();
+    assert_eq!(record.size, 5);
+    assert_eq!(record.created, Timestamp::new(10, 0));
+    assert_eq!(record.modified, Timestamp::new(20, 0));
+    assert_eq!(record.accessed, Timestamp::new(10, 0));
+
+    fs.set_clock(|| Timestamp::new(30, 0));
+    fs.inode(number).unwrap().read_all();
+    assert_eq!(fs.record(number).unwrap().accessed, Timestamp::new(30, 0));
+    fs.set_clock(|| Timestamp::new(40, 0));
+    fs.inode(number).unwrap().read_all();
+    assert_eq!(fs.record(number).unwrap().accessed, Timestamp::new(30, 0));
+
+    // a type byte nothing writes is a broken leaf, not a file
+    let leaf_node = fs.leaf_of(number).unwrap();
+    let mut leaf = fs.volume.read_leaf(leaf_node).unwrap();
+    leaf.record[0] = 0xff;
+    fs.volume.write_node(leaf_node, &leaf).unwrap();
+    assert_eq!(fs.record(number), Err(FsError::Corrupt(leaf_node)));
+    assert_eq!(fs.inode(number).err(), Some(FsError::Corrupt(leaf_node)));
+}
+
+#[test]
+fn test_access_time() {
+    let mut fs = test_fs(64);
+    let number = fs.create_inode().unwrap();
+    fs.inode(number).unwrap().rewrite(b"hello");
+    fs.commit().unwrap();
+    let generation = fs.superblock().generation();
+
+    // the clock hasnt moved past the last access, nothing to write
+    fs.inode(number).unwrap().read_all();
+    assert!(!fs.volume.dirty);
+
+    // an access time alone doesnt set off the policy, but goes with the next commit
+    fs.set_flush_policy(FlushPolicy::IMMEDIATE | FlushPolicy::timed(0));
+    fs.set_clock(|| Timestamp::new(10, 0));
+    fs.inode(number).unwrap().read_all();
+    assert_eq!(fs.record(number).unwrap().accessed, Timestamp::new(10, 0));
+    assert_eq!(fs.n_pending(), 0);
+    assert_eq!(fs.tick(), Ok(false));
+    assert_eq!(fs.superblock().generation(), generation);
+    fs.set_flush_policy(FlushPolicy::MANUAL);
+    fs.commit().unwrap();
+    assert_eq!(fs.superblock().generation(), generation + 1);
+
+    // unless the policy keeps them
+    fs.inode(number).unwrap().rewrite(b"again");
+    fs.commit().unwrap();
+    fs.set_clock(|| Timestamp::new(20, 0));
+    fs.set_flush_policy(FlushPolicy::ACCESS_TIMES);
+    fs.inode(number).unwrap().read_all();
+    assert_eq!(fs.n_pending(), 1);
+    fs.commit().unwrap();
+    assert_eq!(fs.superblock().generation(), generation + 3);
+    let mut fs = NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(fs.record(number).unwrap().accessed, Timestamp::new(20, 0));
+
+    // changed since the last access, so a read wants to write the leaf. A full fs still reads, the access time stays
+    fs.set_clock(|| Timestamp::new(30, 0));
+    fs.inode(number).unwrap().rewrite(b"full");
+    fs.commit().unwrap();
+    let filler = fs.create_inode().unwrap();
+    let mut offset = 0;
+    let err = loop {
+        let block = [1; PAGE_SIZE as usize];
+        match fs.inode(filler).unwrap().write_bytes(&block, offset) {
+            Ok(_) => offset += PAGE_SIZE,
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(err, FsError::NoSpace);
+    fs.set_clock(|| Timestamp::new(40, 0));
+    let mut buf = [0; 4];
+    assert_eq!(fs.inode(number).unwrap().read_bytes(&mut buf, 0), Ok(4));
+    assert_eq!(&buf, b"full");
+    assert_eq!(fs.record(number).unwrap().accessed, Timestamp::new(20, 0));
+    assert_eq!(
+        fs.inode(filler).unwrap().write_bytes(b"more", offset),
+        Err(FsError::NoSpace)
+    );
+}
+
+#[test]
+fn test_stale_inode_cache() {
+    let mut fs = test_fs(64);
+    let a = fs.create_inode().unwrap();
+    let b = fs.create_inode().unwrap();
+    fs.inode(a).unwrap().rewrite(b"a");
+    fs.inode(b).unwrap().rewrite(b"b");
+    let (index, volume) = fs.index();
+    let b_leaf = index.lookup(volume, b).unwrap();
+
+    // the cache points a at b's leaf, a node that checks out but isnt a's
+    fs.inodes.insert(a, b_leaf);
+    assert_eq!(fs.record(a).err(), Some(FsError::Corrupt(b_leaf)));
+
+    // the bad entry is dropped, the next look goes through the index
+    assert!(fs.inodes.peek(&a).is_none());
+    assert_eq!(fs.record(a).unwrap().size, 1);
+    assert_eq!(fs.inode(a).unwrap().read_all(), "a");
+}
+
+#[test]
+fn test_node_checksums() {
+    let mut fs = test_fs(64);
+    let number = fs.create_inode().unwrap();
+    fs.inode(number).unwrap().rewrite(b"checked");
+
+    // a bad sector in the leaf, past where the node's bytes end
+    let (index, volume) = fs.index();
+    let leaf = index.lookup(volume, number).unwrap();
+    let leaf = volume.node_cluster(leaf).unwrap();
+    let mut block = volume.read_cluster(leaf).unwrap();
+    block[PAGE_SIZE as usize - 1] ^= 1;
+    volume.write_cluster(leaf, block).unwrap();
+
+    assert_eq!(fs.inode(number).err(), Some(FsError::BadChecksum(leaf)));
+    assert_eq!(fs.record(number), Err(FsError::BadChecksum(leaf)));
+    // the rest of the index is fine
+    assert!(fs.dir(ROOT_INODE).is_ok());
+
+    fs.commit().unwrap();
+    let free_list = fs.superblock().free_cluster_list_addr;
+    let mut disk = fs.unmount();
+    let mut block = read_block(&mut disk, free_list).unwrap();
+    block[30] ^= 1;
+    write_block(&mut disk, free_list, block).unwrap();
+    assert_eq!(
+        NeFS::mount(disk).err(),
+        Some(MountError::BadNodeChecksum(free_list))
+    );
+}
+
+/// Drops every write after the first `writes_left`, like the power going out
+#[cfg(test)]
+struct CrashAfter {
+    disk: super::block::RamDisk,
+    writes_left: usize,
+}
+
+#[cfg(test)]
+impl BlockDriver for CrashAfter {
+    fn push_read_request(
+        &mut self,
+        cluster_number: u64,
+    ) -> Result<super::block::RequestId, IoError> {
+        self.disk.push_read_request(cluster_number)
+    }
+
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<super::block::RequestId, IoError> {
+        if self.writes_left == 0 {
+            // write back what is there, so the request still completes
+            let current = read_block(&mut self.disk, cluster_number)?;
+            return self.disk.push_write_request(cluster_number, current);
+        }
+
+        self.writes_left -= 1;
+        self.disk.push_write_request(cluster_number, block)
+    }
+
+    fn poll_completion(&mut self, id: super::block::RequestId) -> Option<super::block::Completion> {
+        self.disk.poll_completion(id)
+    }
+
+    fn device_clusters(&self) -> Option<u64> {
+        self.disk.device_clusters()
+    }
+}
+
+#[test]
+fn test_commit_is_atomic() {
+    use super::block::RamDisk;
+
+    let mut disk = RamDisk::new(128);
+    mkfs(&mut disk, 128, "rootfs").unwrap();
+    let mut fs = NeFS::mount(disk).unwrap();
+    fs.mkdir("/old").unwrap();
+    fs.create("/old/file").unwrap();
+    let disk = fs.unmount();
+    let generation = NeFS::mount(disk.clone()).unwrap().superblock().generation();
+
+    // crash after every possible write of the next commit
+    for writes_left in 0.. {
+        let mut fs = NeFS::mount(CrashAfter {
+            disk: disk.clone(),
+            writes_left,
+        })
+        .unwrap();
+        // once the power is out, reads dont see what was written and things fail. Doesnt matter, only the disk does
+        let _ = (|| {
+            fs.unlink("/old/file")?;
+            fs.rmdir("/old")?;
+            fs.mkdir("/new")?;
+            fs.create("/new/file")?;
+            fs.commit()
+        })();
+
+        let mut fs = NeFS::mount(fs.unmount().disk).unwrap();
+        let old = fs.stat("/old/file").is_ok() && fs.stat("/new").is_err();
+        let new = fs.stat("/old").is_err() && fs.stat("/new/file").is_ok();
+        assert!(old != new, "torn after {} writes", writes_left);
+
+        if new {
+            assert_eq!(fs.superblock().generation(), generation + 1);
+            break;
+        }
+        assert_eq!(fs.superblock().generation(), generation);
+        assert!(writes_left < 1000);
+    }
+}
+
+#[test]
+fn test_superblock_fallback() {
+    let mut fs = test_fs(64);
+    fs.create("/first").unwrap();
+    fs.commit().unwrap();
+    let older = fs.superblock().generation();
+    fs.create("/second").unwrap();
+    fs.commit().unwrap();
+    assert_eq!(fs.superblock().generation(), older + 1);
+    assert_eq!(fs.bad_superblock_slot(), None);
+
+    // tear the newest copy, the one before it is still whole and so is everything it points at
+    let newest = SUPERBLOCK_CLUSTERS[(older as usize + 1) % 2];
+    let mut disk = fs.unmount();
+    let mut block = read_block(&mut disk, newest).unwrap();
+    block[40] ^= 1;
+    write_block(&mut disk, newest, block).unwrap();
+
+    let mut fs = NeFS::mount(disk).unwrap();
+    assert_eq!(fs.superblock().generation(), older);
+    assert!(matches!(
+        fs.bad_superblock_slot(),
+        Some((slot, MountError::BadChecksum { .. })) if slot == newest
+    ));
+    assert!(fs.stat("/first").is_ok());
+    assert_eq!(fs.stat("/second").err(), Some(FsError::NotFound));
+
+    // the next commit goes over the torn copy
+    fs.create("/third").unwrap();
+    fs.commit().unwrap();
+    let fs = NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(fs.superblock().generation(), older + 1);
+    assert_eq!(fs.bad_superblock_slot(), None);
+}
@@ -0,0 +1,257 @@
+// -------------
+// NODE MAP
+// -------------
+
+// Nodes point at each other by node number, never by cluster. The node map says which cluster each number is in right now
+// That is what makes copy on write cheap: rewriting a node puts it in a new cluster and changes one entry here,
+// instead of rewriting everything that points at it (and everything that points at those)
+// On disk it is a root listing chunk clusters, each chunk a slice of the table. A commit writes the chunks that changed
+// and a new root to fresh clusters, then the superblock points at the new root
+
+use super::{
+    checksum::verify_node, encode_cluster, ClusterNumber, DataNode, FreeList, FsError, MountError,
+    NodeHeader, NodeMapChunk, NodeMapRoot, NodeNumber, BINCODE_CONFIG,
+};
+use crate::driver::block::{read_block, write_block, Block, BlockDriver};
+use alloc::{collections::BTreeSet, vec, vec::Vec};
+
+/// Entries in one chunk. An entry is at most 9 bytes encoded, so this fits a cluster with the header
+pub const NODE_MAP_ENTRIES_PER_CHUNK: usize = 400;
+/// Chunks the root can list, which caps how many nodes there can be
+pub const MAX_NODE_MAP_CHUNKS: usize = 400;
+
+/// The node map, loaded into memory
+#[derive(Debug, Clone)]
+pub struct NodeMap {
+    // cluster of the root and of each chunk as of the last commit. 0 for a chunk that was never written
+    root: ClusterNumber,
+    chunks: Vec<ClusterNumber>,
+    // cluster of each node by number, 0 for a number not in use. Number 0 is never a node
+    clusters: Vec<ClusterNumber>,
+    // chunks changed since the last commit
+    dirty: BTreeSet<usize>,
+    // numbers given back, handed out before new ones
+    free_numbers: Vec<NodeNumber>,
+}
+
+impl NodeMap {
+    /// Read the root at `root` and every chunk it lists
+    pub fn load(driver: &mut impl BlockDriver, root: ClusterNumber) -> Result<Self, MountError> {
+        let block = read_block(driver, root)?;
+        verify_node(&block, root).map_err(|_| MountError::BadNodeChecksum(root))?;
+        let (node, _): (NodeMapRoot, usize) = bincode::decode_from_slice(&block, BINCODE_CONFIG)
+            .map_err(|_| MountError::BadStructure(root))?;
+
+        let mut clusters = Vec::new();
+        for chunk in &node.chunks {
+            let block = read_block(driver, *chunk)?;
+            verify_node(&block, *chunk).map_err(|_| MountError::BadNodeChecksum(*chunk))?;
+            let (chunk_node, _): (NodeMapChunk, usize) =
+                bincode::decode_from_slice(&block, BINCODE_CONFIG)
+                    .map_err(|_| MountError::BadStructure(*chunk))?;
+
+            clusters.extend(chunk_node.clusters);
+        }
+        clusters.truncate(node.n_numbers as usize);
+        if clusters.is_empty() {
+            clusters.push(0);
+        }
+
+        let free_numbers = (1..clusters.len() as NodeNumber)
+            .rev()
+            .filter(|n| clusters[*n as usize] == 0)
+            .collect();
+
+        Ok(Self {
+            root,
+            chunks: node.chunks,
+            clusters,
+            dirty: BTreeSet::new(),
+            free_numbers,
+        })
+    }
+
+    /// Encode a map holding just `nodes`, numbered from 1, for mkfs. The root goes at `root`, its one chunk at `chunk`
+    pub fn format(
+        nodes: &[ClusterNumber],
+        root: ClusterNumber,
+        chunk: ClusterNumber,
+    ) -> Result<[(ClusterNumber, Block); 2], FsError> {
+        let mut clusters = vec![0];
+        clusters.extend_from_slice(nodes);
+
+        let root_node = NodeMapRoot {
+            header: NodeHeader::new(0),
+            n_numbers: clusters.len() as u64,
+            chunks: vec![chunk],
+        };
+        let chunk_node = NodeMapChunk {
+            header: NodeHeader::new(0),
+            clusters,
+        };
+
+        Ok([
+            (root, encode_cluster(&root_node)?),
+            (chunk, encode_cluster(&chunk_node)?),
+        ])
+    }
+
+    /// Cluster the root was in at the last commit
+    pub fn root(&self) -> ClusterNumber {
+        self.root
+    }
+
+    /// Clusters a commit of the map can take at most
+    pub fn n_clusters(&self) -> u64 {
+        self.clusters.len().div_ceil(NODE_MAP_ENTRIES_PER_CHUNK) as u64 + 1
+    }
+
+    /// Every cluster the map was in at the last commit, and every cluster a node is in
+    pub fn held_clusters(&self) -> Vec<DataNode> {
+        core::iter::once(self.root)
+            .chain(self.chunks.iter().copied())
+            .chain(self.clusters.iter().copied())
+            .filter(|c| *c != 0)
+            .map(|c| DataNode::new(1, c))
+            .collect()
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// Where node `number` is, if it is in use
+    pub fn get(&self, number: NodeNumber) -> Option<ClusterNumber> {
+        match self.clusters.get(number as usize) {
+            Some(0) | None => None,
+            Some(cluster) => Some(*cluster),
+        }
+    }
+
+    /// Node `number` moved to `cluster`
+    pub fn set(&mut self, number: NodeNumber, cluster: ClusterNumber) {
+        self.clusters[number as usize] = cluster;
+        self.dirty
+            .insert(number as usize / NODE_MAP_ENTRIES_PER_CHUNK);
+    }
+
+    /// A number for a new node in `cluster`
+    pub fn insert(&mut self, cluster: ClusterNumber) -> Result<NodeNumber, FsError> {
+        let number = match self.free_numbers.pop() {
+            Some(n) => n,
+            None => {
+                if self.clusters.len() >= NODE_MAP_ENTRIES_PER_CHUNK * MAX_NODE_MAP_CHUNKS {
+                    return Err(FsError::NoSpace);
+                }
+                self.clusters.push(0);
+                (self.clusters.len() - 1) as NodeNumber
+            }
+        };
+
+        self.set(number, cluster);
+        Ok(number)
+    }
+
+    /// Drop node `number`. Returns the cluster it was in
+    pub fn remove(&mut self, number: NodeNumber) -> Option<ClusterNumber> {
+        let cluster = self.get(number)?;
+        self.set(number, 0);
+        self.free_numbers.push(number);
+
+        Some(cluster)
+    }
+
+    /// Write the changed chunks and a new root to clusters from `free_list`. The clusters they replace go in `released`,
+    /// they are still part of the last commit so they cant be reused until the next one lands
+    /// Nothing is written if nothing changed, like when every node was written in place
+    pub fn store(
+        &mut self,
+        driver: &mut impl BlockDriver,
+        free_list: &mut FreeList,
+        released: &mut Vec<DataNode>,
+    ) -> Result<(), FsError> {
+        if self.dirty.is_empty() && self.root != 0 {
+            return Ok(());
+        }
+
+        let n_chunks = self.clusters.len().div_ceil(NODE_MAP_ENTRIES_PER_CHUNK);
+        self.chunks.resize(n_chunks, 0);
+
+        let dirty: Vec<usize> = self.dirty.iter().copied().collect();
+        for index in dirty {
+            let start = index * NODE_MAP_ENTRIES_PER_CHUNK;
+            let end = core::cmp::min(start + NODE_MAP_ENTRIES_PER_CHUNK, self.clusters.len());
+            let node = NodeMapChunk {
+                header: NodeHeader::new(0),
+                clusters: self.clusters[start..end].to_vec(),
+            };
+
+            let cluster = free_list.alloc(1).ok_or(FsError::NoSpace)?;
+            write_block(
+                driver,
+                cluster.cluster_start_number(),
+                encode_cluster(&node)?,
+            )?;
+            if self.chunks[index] != 0 {
+                released.push(DataNode::new(1, self.chunks[index]));
+            }
+            self.chunks[index] = cluster.cluster_start_number();
+        }
+
+        let root = NodeMapRoot {
+            header: NodeHeader::new(0),
+            n_numbers: self.clusters.len() as u64,
+            chunks: self.chunks.clone(),
+        };
+        let cluster = free_list.alloc(1).ok_or(FsError::NoSpace)?;
+        write_block(
+            driver,
+            cluster.cluster_start_number(),
+            encode_cluster(&root)?,
+        )?;
+        released.push(DataNode::new(1, self.root));
+        self.root = cluster.cluster_start_number();
+
+        self.dirty.clear();
+        Ok(())
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_node_map_store_load() {
+    use crate::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(64);
+    for (cluster, block) in NodeMap::format(&[10], 1, 2).unwrap() {
+        write_block(&mut disk, cluster, block).unwrap();
+    }
+    let mut free_list = FreeList::new(3, 20, 64);
+
+    let mut map = NodeMap::load(&mut disk, 1).unwrap();
+    assert_eq!(map.get(1), Some(10));
+    assert_eq!(map.get(2), None);
+
+    // enough numbers to need a second chunk
+    for i in 0..NODE_MAP_ENTRIES_PER_CHUNK as u64 {
+        assert_eq!(map.insert(1000 + i), Ok(i + 2));
+    }
+    map.remove(5).unwrap();
+    map.set(1, 11);
+
+    let mut released = Vec::new();
+    map.store(&mut disk, &mut free_list, &mut released).unwrap();
+    // the old root and chunk, the new ones went somewhere else
+    assert_eq!(released, [DataNode::new(1, 2), DataNode::new(1, 1)]);
+    assert!(!map.is_dirty());
+
+    let mut loaded = NodeMap::load(&mut disk, map.root()).unwrap();
+    assert_eq!(loaded.get(1), Some(11));
+    assert_eq!(loaded.get(5), None);
+    assert_eq!(loaded.get(401), Some(1399));
+    // a removed number is the first one reused
+    assert_eq!(loaded.insert(7), Ok(5));
+}
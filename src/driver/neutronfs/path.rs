@@ -0,0 +1,598 @@
+// -------------
+// PATHS
+// -------------
+
+// Absolute paths like "/sys/config/kernel.toml", walked one directory entry at a time from ROOT_INODE
+// Symlinks in the middle of a path are always followed, the last one depending on the call. Relative targets
+// are relative to the directory the link is in
+
+use super::{
+    dir::validate_name, Change, DirEntry, FsError, Inode, InodeNumber, InodeRecord, ItemType, NeFS,
+    ROOT_INODE,
+};
+use crate::driver::block::BlockDriver;
+use alloc::{string::String, vec::Vec};
+
+/// Most symlinks followed for one path, same as Linux
+pub const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// How `open` treats the path
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const NONE: OpenFlags = OpenFlags(0);
+    /// Make an empty file if nothing is there
+    pub const CREATE: OpenFlags = OpenFlags(1);
+    /// With CREATE, fail if something is there
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(1 << 1);
+    /// Empty the file
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 2);
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+/// What stat says about an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub inode: InodeNumber,
+    pub record: InodeRecord,
+}
+
+/// Split an absolute path into its parts, dropping empty ones and `.`
+pub fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    Ok(path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect())
+}
+
+impl<D: BlockDriver> NeFS<D> {
+    /// What the inode holds: data, directory buckets or a symlink target
+    pub fn read_item(&mut self, number: InodeNumber) -> Result<ItemType, FsError> {
+        Ok(self.read_leaf_of(number)?.1.item_type)
+    }
+
+    /// One less entry points at `number`. Gone once nothing does
+    fn drop_link(&mut self, number: InodeNumber) -> Result<(), FsError> {
+        let record = self.update_record(number, |r| r.n_links = r.n_links.saturating_sub(1))?;
+        if record.n_links == 0 {
+            self.remove_inode(number)?;
+        }
+
+        Ok(())
+    }
+
+    /// Walk `parts` starting at `dir`. The last part is only followed if it is a symlink and `follow` is set
+    fn walk(
+        &mut self,
+        dir: InodeNumber,
+        parts: &[&str],
+        follow: bool,
+        depth: &mut usize,
+    ) -> Result<InodeNumber, FsError> {
+        let mut curr = dir;
+
+        for (i, part) in parts.iter().enumerate() {
+            let parent = curr;
+            curr = self.dir(curr)?.lookup(part)?;
+
+            let is_last = i + 1 == parts.len();
+            if is_last && !follow {
+                break;
+            }
+
+            if let ItemType::Symlink(target) = self.read_item(curr)? {
+                *depth += 1;
+                if *depth > MAX_SYMLINK_DEPTH {
+                    return Err(FsError::TooManySymlinks);
+                }
+
+                let (start, target_parts) = if target.starts_with('/') {
+                    (ROOT_INODE, components(&target)?)
+                } else {
+                    (
+                        parent,
+                        target
+                            .split('/')
+                            .filter(|c| !c.is_empty() && *c != ".")
+                            .collect(),
+                    )
+                };
+                curr = self.walk(start, &target_parts, true, depth)?;
+            }
+        }
+
+        Ok(curr)
+    }
+
+    /// Inode at `path`. Follows a symlink at the end if `follow`
+    pub fn resolve(&mut self, path: &str, follow: bool) -> Result<InodeNumber, FsError> {
+        let parts = components(path)?;
+        self.walk(ROOT_INODE, &parts, follow, &mut 0)
+    }
+
+    /// Directory `path` goes in and its last part, which has to be a plain name
+    fn resolve_parent<'p>(&mut self, path: &'p str) -> Result<(InodeNumber, &'p str), FsError> {
+        let parts = components(path)?;
+        let (name, dir_parts) = parts.split_last().ok_or(FsError::InvalidPath)?;
+        validate_name(name)?;
+
+        let dir = self.walk(ROOT_INODE, dir_parts, true, &mut 0)?;
+        // make sure its a directory before handing it back
+        self.dir(dir)?;
+
+        Ok((dir, name))
+    }
+
+    /// Open a file. See OpenFlags
+    pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<Inode<'_, D>, FsError> {
+        let number = match self.resolve(path, true) {
+            Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+                return Err(FsError::AlreadyExists)
+            }
+            Ok(number) => number,
+            Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => self.create(path)?,
+            Err(e) => return Err(e),
+        };
+
+        let mut inode = self.inode(number)?;
+        if flags.contains(OpenFlags::TRUNCATE) {
+            inode.set_contents(&[])?;
+        }
+
+        Ok(inode)
+    }
+
+    /// Link a new inode into the parent of `path`, dropping it again if that fails
+    fn link_new(&mut self, path: &str, number: InodeNumber) -> Result<InodeNumber, FsError> {
+        let res = self
+            .resolve_parent(path)
+            .and_then(|(parent, name)| self.dir(parent)?.insert(name, number));
+
+        match res {
+            Ok(()) => Ok(number),
+            Err(e) => {
+                self.remove_inode(number)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Make an empty file
+    pub fn create(&mut self, path: &str) -> Result<InodeNumber, FsError> {
+        // check first, so a bad path doesnt cost an inode
+        self.resolve_parent(path)?;
+        let number = self.create_inode()?;
+        self.link_new(path, number)?;
+        self.volume.changed(Change::Metadata);
+
+        Ok(number)
+    }
+
+    /// Make an empty directory
+    pub fn mkdir(&mut self, path: &str) -> Result<InodeNumber, FsError> {
+        let (parent, _) = self.resolve_parent(path)?;
+        let number = self.create_dir(parent)?;
+        self.link_new(path, number)?;
+
+        // the new directory's ..
+        self.update_record(parent, |r| r.n_links += 1)?;
+        self.volume.changed(Change::Metadata);
+
+        Ok(number)
+    }
+
+    /// Make a symlink at `path` pointing at `target`. The target doesnt have to exist
+    pub fn symlink(&mut self, target: &str, path: &str) -> Result<InodeNumber, FsError> {
+        if target.is_empty() {
+            return Err(FsError::InvalidPath);
+        }
+
+        self.resolve_parent(path)?;
+        let number = self.create_symlink(target)?;
+        self.link_new(path, number)?;
+        self.volume.changed(Change::Metadata);
+
+        Ok(number)
+    }
+
+    /// Another name for the file at `existing`. Directories only get the one
+    pub fn link(&mut self, existing: &str, path: &str) -> Result<(), FsError> {
+        let number = self.resolve(existing, false)?;
+        if let ItemType::Directory(_) = self.read_item(number)? {
+            return Err(FsError::IsADirectory);
+        }
+
+        let (parent, name) = self.resolve_parent(path)?;
+        self.dir(parent)?.insert(name, number)?;
+        self.update_record(number, |r| r.n_links += 1)?;
+        self.volume.changed(Change::Metadata);
+
+        Ok(())
+    }
+
+    /// Where the symlink at `path` points
+    pub fn readlink(&mut self, path: &str) -> Result<String, FsError> {
+        let number = self.resolve(path, false)?;
+        match self.read_item(number)? {
+            ItemType::Symlink(target) => Ok(target),
+            _ => Err(FsError::InvalidPath),
+        }
+    }
+
+    /// Remove a name for a file or symlink. The inode goes with its last name
+    pub fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let number = self.dir(parent)?.lookup(name)?;
+        if let ItemType::Directory(_) = self.read_item(number)? {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.dir(parent)?.remove(name)?;
+        self.drop_link(number)?;
+        self.volume.changed(Change::Metadata);
+
+        Ok(())
+    }
+
+    /// Remove an empty directory
+    pub fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let number = self.dir(parent)?.lookup(name)?;
+        if !self.dir(number)?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+
+        self.dir(parent)?.remove(name)?;
+        self.remove_inode(number)?;
+        self.update_record(parent, |r| r.n_links = r.n_links.saturating_sub(1))?;
+        self.volume.changed(Change::Metadata);
+
+        Ok(())
+    }
+
+    /// Set the permission bits of what `path` points at
+    pub fn chmod(&mut self, path: &str, mode: u16) -> Result<(), FsError> {
+        let number = self.resolve(path, true)?;
+        self.update_record(number, |r| r.mode = mode & 0o7777)?;
+        self.volume.changed(Change::Metadata);
+
+        Ok(())
+    }
+
+    /// Give what `path` points at to someone else
+    pub fn chown(&mut self, path: &str, uid: u32, gid: u32) -> Result<(), FsError> {
+        let number = self.resolve(path, true)?;
+        self.update_record(number, |r| {
+            r.uid = uid;
+            r.gid = gid;
+        })?;
+        self.volume.changed(Change::Metadata);
+
+        Ok(())
+    }
+
+    /// Move `from` to `to`, replacing what is at `to` if its the same kind. A directory only replaces an empty one
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let (from_parent, from_name) = self.resolve_parent(from)?;
+        let (to_parent, to_name) = self.resolve_parent(to)?;
+        let number = self.dir(from_parent)?.lookup(from_name)?;
+        let is_dir = matches!(self.read_item(number)?, ItemType::Directory(_));
+
+        if from_parent == to_parent && from_name == to_name {
+            return Ok(());
+        }
+
+        // a directory cant go under itself
+        if is_dir {
+            let mut curr = to_parent;
+            loop {
+                if curr == number {
+                    return Err(FsError::InvalidPath);
+                }
+                if curr == ROOT_INODE {
+                    break;
+                }
+                curr = self.dir(curr)?.parent();
+            }
+        }
+
+        match self.dir(to_parent)?.lookup(to_name) {
+            Ok(existing) if existing == number => return Ok(()),
+            Ok(existing) => {
+                match (is_dir, self.read_item(existing)?) {
+                    (true, ItemType::Directory(_)) => {
+                        if !self.dir(existing)?.is_empty() {
+                            return Err(FsError::NotEmpty);
+                        }
+                    }
+                    (true, _) => return Err(FsError::NotADirectory),
+                    (false, ItemType::Directory(_)) => return Err(FsError::IsADirectory),
+                    (false, _) => {}
+                }
+                self.dir(to_parent)?.remove(to_name)?;
+                if is_dir {
+                    self.remove_inode(existing)?;
+                    self.update_record(to_parent, |r| r.n_links = r.n_links.saturating_sub(1))?;
+                } else {
+                    self.drop_link(existing)?;
+                }
+            }
+            Err(FsError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        self.dir(to_parent)?.insert(to_name, number)?;
+        self.dir(from_parent)?.remove(from_name)?;
+        if is_dir && from_parent != to_parent {
+            self.dir(number)?.set_parent(to_parent)?;
+            self.update_record(from_parent, |r| r.n_links = r.n_links.saturating_sub(1))?;
+            self.update_record(to_parent, |r| r.n_links += 1)?;
+        }
+        self.volume.changed(Change::Metadata);
+
+        Ok(())
+    }
+
+    /// Every entry of the directory at `path`, `.` and `..` included
+    pub fn readdir(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let number = self.resolve(path, true)?;
+        let mut dir = self.dir(number)?;
+        let entries = dir.entries().collect();
+
+        entries
+    }
+
+    fn stat_inode(&mut self, number: InodeNumber) -> Result<Stat, FsError> {
+        Ok(Stat {
+            inode: number,
+            record: self.record(number)?,
+        })
+    }
+
+    /// Follows a symlink at the end of the path
+    pub fn stat(&mut self, path: &str) -> Result<Stat, FsError> {
+        let number = self.resolve(path, true)?;
+        self.stat_inode(number)
+    }
+
+    /// Stat the symlink itself rather than what it points at
+    pub fn lstat(&mut self, path: &str) -> Result<Stat, FsError> {
+        let number = self.resolve(path, false)?;
+        self.stat_inode(number)
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_path_create_open() {
+    use neutronapi::fs::{Readable, Writable};
+
+    let mut fs = super::test_fs(256);
+    fs.mkdir("/sys").unwrap();
+    fs.mkdir("/sys/config").unwrap();
+    let number = fs.create("/sys/config/kernel.toml").unwrap();
+
+    fs.open("/sys/config/kernel.toml", OpenFlags::NONE)
+        .unwrap()
+        .rewrite(b"debug = true");
+    // . and .. along the way are fine
+    let mut file = fs
+        .open("/sys/./config/../config//kernel.toml", OpenFlags::NONE)
+        .unwrap();
+    assert_eq!(file.number(), number);
+    assert_eq!(file.read_all(), "debug = true");
+
+    assert_eq!(
+        fs.open("/sys/config/missing", OpenFlags::NONE).err(),
+        Some(FsError::NotFound)
+    );
+    let created = fs
+        .open("/sys/config/missing", OpenFlags::CREATE)
+        .unwrap()
+        .number();
+    assert_eq!(fs.resolve("/sys/config/missing", true), Ok(created));
+    assert_eq!(
+        fs.open(
+            "/sys/config/missing",
+            OpenFlags::CREATE | OpenFlags::EXCLUSIVE
+        )
+        .err(),
+        Some(FsError::AlreadyExists)
+    );
+
+    let truncated = fs
+        .open("/sys/config/kernel.toml", OpenFlags::TRUNCATE)
+        .unwrap();
+    assert_eq!(truncated.size(), 0);
+
+    assert_eq!(
+        fs.open("/sys", OpenFlags::NONE).err(),
+        Some(FsError::IsADirectory)
+    );
+    assert_eq!(fs.create("relative").err(), Some(FsError::InvalidPath));
+    assert_eq!(
+        fs.create("/sys/config/kernel.toml/x").err(),
+        Some(FsError::NotADirectory)
+    );
+    assert_eq!(fs.mkdir("/sys").err(), Some(FsError::AlreadyExists));
+    assert_eq!(fs.mkdir("/nope/sys").err(), Some(FsError::NotFound));
+}
+
+#[test]
+fn test_path_unlink_rmdir() {
+    use alloc::string::ToString;
+
+    let mut fs = super::test_fs(256);
+    let used = fs.superblock().n_sectors_used();
+
+    fs.mkdir("/home").unwrap();
+    fs.create("/home/notes").unwrap();
+    assert_eq!(fs.rmdir("/home"), Err(FsError::NotEmpty));
+    assert_eq!(fs.unlink("/home"), Err(FsError::IsADirectory));
+    assert_eq!(fs.rmdir("/home/notes"), Err(FsError::NotADirectory));
+    assert_eq!(fs.rmdir("/"), Err(FsError::InvalidPath));
+
+    fs.unlink("/home/notes").unwrap();
+    assert_eq!(fs.stat("/home/notes"), Err(FsError::NotFound));
+    fs.rmdir("/home").unwrap();
+
+    let names: Vec<String> = fs
+        .readdir("/")
+        .unwrap()
+        .into_iter()
+        .map(|e| e.name().to_string())
+        .collect();
+    assert_eq!(names, [".", ".."]);
+    // what was freed comes back with the commit
+    fs.commit().unwrap();
+    assert_eq!(fs.superblock().n_sectors_used(), used);
+}
+
+#[test]
+fn test_path_rename() {
+    let mut fs = super::test_fs(256);
+    fs.mkdir("/a").unwrap();
+    fs.mkdir("/b").unwrap();
+    let file = fs.create("/a/file").unwrap();
+    let other = fs.create("/b/other").unwrap();
+
+    // plain move
+    fs.rename("/a/file", "/b/file").unwrap();
+    assert_eq!(fs.stat("/b/file").unwrap().inode, file);
+    assert_eq!(fs.stat("/a/file"), Err(FsError::NotFound));
+
+    // replaces a file
+    fs.rename("/b/file", "/b/other").unwrap();
+    assert_eq!(fs.stat("/b/other").unwrap().inode, file);
+    assert_eq!(fs.inode(other).err(), Some(FsError::NotFound));
+
+    // directories move with their .. fixed up
+    let a = fs.resolve("/a", true).unwrap();
+    let b = fs.resolve("/b", true).unwrap();
+    fs.rename("/a", "/b/a").unwrap();
+    assert_eq!(fs.resolve("/b/a/..", true), Ok(b));
+    assert_eq!(fs.resolve("/b/a", true), Ok(a));
+
+    assert_eq!(fs.rename("/b", "/b/a/b"), Err(FsError::InvalidPath));
+    assert_eq!(fs.rename("/b/other", "/b/a"), Err(FsError::IsADirectory));
+    fs.mkdir("/c").unwrap();
+    fs.create("/c/x").unwrap();
+    assert_eq!(fs.rename("/b/a", "/c"), Err(FsError::NotEmpty));
+    assert_eq!(fs.rename("/b/a", "/b/other"), Err(FsError::NotADirectory));
+}
+
+#[test]
+fn test_path_symlinks() {
+    use super::FileType;
+
+    let mut fs = super::test_fs(256);
+    fs.mkdir("/etc").unwrap();
+    let file = fs.create("/etc/hosts").unwrap();
+
+    fs.symlink("/etc", "/conf").unwrap();
+    fs.symlink("hosts", "/etc/hosts-link").unwrap();
+    fs.symlink("../conf/hosts-link", "/etc/chain").unwrap();
+
+    assert_eq!(fs.resolve("/conf/hosts", true), Ok(file));
+    assert_eq!(fs.resolve("/etc/hosts-link", true), Ok(file));
+    assert_eq!(fs.resolve("/etc/chain", true), Ok(file));
+    assert_eq!(
+        fs.open("/conf/hosts-link", OpenFlags::NONE)
+            .unwrap()
+            .number(),
+        file
+    );
+
+    assert_eq!(fs.readlink("/etc/chain").unwrap(), "../conf/hosts-link");
+    assert_eq!(
+        fs.lstat("/conf").unwrap().record.file_type,
+        FileType::Symlink
+    );
+    assert_eq!(fs.stat("/conf").unwrap().record.file_type, FileType::Dir);
+
+    // loops give up instead of spinning
+    fs.symlink("/loop-b", "/loop-a").unwrap();
+    fs.symlink("/loop-a", "/loop-b").unwrap();
+    assert_eq!(fs.stat("/loop-a"), Err(FsError::TooManySymlinks));
+    assert_eq!(
+        fs.open("/loop-a/x", OpenFlags::CREATE).err(),
+        Some(FsError::TooManySymlinks)
+    );
+
+    // removing a link leaves the target alone
+    fs.unlink("/conf").unwrap();
+    assert_eq!(fs.resolve("/etc/hosts", true), Ok(file));
+}
+
+#[test]
+fn test_path_links_and_attributes() {
+    use super::{FileType, Timestamp};
+
+    let mut fs = super::test_fs(256);
+    fs.set_clock(|| Timestamp::new(1_000, 0));
+    fs.set_owner(1000, 100);
+
+    fs.mkdir("/home").unwrap();
+    fs.mkdir("/home/user").unwrap();
+    let file = fs.create("/home/user/notes").unwrap();
+
+    let stat = fs.stat("/home/user/notes").unwrap();
+    assert_eq!(stat.inode, file);
+    assert_eq!(stat.record.file_type, FileType::File);
+    assert_eq!((stat.record.uid, stat.record.gid), (1000, 100));
+    assert_eq!(stat.record.mode, 0o644);
+    assert_eq!(stat.record.n_links, 1);
+    assert_eq!(stat.record.created, Timestamp::new(1_000, 0));
+
+    // . and every child's .. count
+    assert_eq!(fs.stat("/home").unwrap().record.n_links, 3);
+    assert_eq!(fs.stat("/").unwrap().record.n_links, 3);
+    assert_eq!(fs.stat("/home/user").unwrap().record.size, 1);
+
+    // the inode lives as long as it has a name
+    fs.link("/home/user/notes", "/home/notes").unwrap();
+    assert_eq!(fs.stat("/home/notes").unwrap().record.n_links, 2);
+    fs.unlink("/home/user/notes").unwrap();
+    assert_eq!(fs.stat("/home/notes").unwrap().inode, file);
+    assert_eq!(fs.stat("/home/notes").unwrap().record.n_links, 1);
+    assert_eq!(fs.link("/home/user", "/user"), Err(FsError::IsADirectory));
+
+    fs.set_clock(|| Timestamp::new(2_000, 5));
+    fs.chmod("/home/notes", 0o600).unwrap();
+    fs.chown("/home/notes", 0, 0).unwrap();
+    let record = fs.stat("/home/notes").unwrap().record;
+    assert_eq!(record.mode, 0o600);
+    assert_eq!(record.uid, 0);
+    assert_eq!(record.changed, Timestamp::new(2_000, 5));
+    assert_eq!(record.modified, Timestamp::new(1_000, 0));
+
+    // moving a directory moves its .. link too
+    fs.rename("/home/user", "/user").unwrap();
+    assert_eq!(fs.stat("/home").unwrap().record.n_links, 2);
+    assert_eq!(fs.stat("/").unwrap().record.n_links, 4);
+    fs.rmdir("/user").unwrap();
+    assert_eq!(fs.stat("/").unwrap().record.n_links, 3);
+
+    // survives a remount
+    let mut fs = super::NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(fs.stat("/home/notes").unwrap().record, record);
+}
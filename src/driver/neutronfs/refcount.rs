@@ -0,0 +1,350 @@
+// -------------
+// REFCOUNTS
+// -------------
+
+// How many owners each cluster has. Every file that has the cluster is an owner, in the live fs or in a snapshot
+// Only shared clusters are listed, as runs of neighbouring clusters with the same count, so sharing a big file is one
+// run rather than an entry for each cluster. Anything in use that isnt listed has exactly one owner. Letting go of a
+// cluster takes one off its count, and it is only free once the count drops to zero
+// On disk it is a chain of RefCountNodes starting at the superblock's refcounts_addr, rewritten somewhere new by
+// every commit that changed it, like the node map
+
+use super::{
+    checksum::verify_node, encode_cluster, ClusterNumber, DataNode, FreeList, FsError, MountError,
+    NodeHeader, RefCountNode, RefCountRun, BINCODE_CONFIG,
+};
+use crate::driver::block::{read_block, write_block, BlockDriver};
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// How many runs go in one cluster of the table. A run is at most ~23 bytes encoded
+pub const REF_RUNS_PER_NODE: usize = 150;
+
+/// The refcount table, loaded into memory
+#[derive(Debug, Clone, Default)]
+pub struct RefCounts {
+    // clusters the table is stored in as of the last commit, empty if it isnt stored at all
+    chain: Vec<ClusterNumber>,
+    // runs of shared clusters by their first cluster, none overlapping and neighbours with the same count joined.
+    // Every count is at least 2
+    runs: BTreeMap<ClusterNumber, RefCountRun>,
+    // changed since the last commit
+    dirty: bool,
+}
+
+impl RefCounts {
+    /// Follow the chain from `addr`. 0 is an empty table
+    pub fn load(driver: &mut impl BlockDriver, addr: ClusterNumber) -> Result<Self, MountError> {
+        let mut chain = Vec::new();
+        let mut runs = BTreeMap::new();
+        let mut end = 0;
+
+        let mut next = addr;
+        while next != 0 {
+            if chain.contains(&next) {
+                return Err(MountError::BadStructure(next));
+            }
+
+            let block = read_block(driver, next)?;
+            verify_node(&block, next).map_err(|_| MountError::BadNodeChecksum(next))?;
+            let (node, _): (RefCountNode, usize) =
+                bincode::decode_from_slice(&block, BINCODE_CONFIG)
+                    .map_err(|_| MountError::BadStructure(next))?;
+
+            // runs are stored in order
+            for run in node.runs {
+                if run.count < 2 || run.clusters_used == 0 || run.cluster_start_number < end {
+                    return Err(MountError::BadStructure(next));
+                }
+                end = run
+                    .cluster_start_number
+                    .checked_add(run.clusters_used)
+                    .ok_or(MountError::BadStructure(next))?;
+                runs.insert(run.cluster_start_number, run);
+            }
+            chain.push(next);
+            next = node.next;
+        }
+
+        Ok(Self {
+            chain,
+            runs,
+            dirty: false,
+        })
+    }
+
+    /// Where the table starts on disk, 0 if it isnt stored
+    pub fn addr(&self) -> ClusterNumber {
+        self.chain.first().copied().unwrap_or(0)
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Nothing is shared
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// The run `cluster_number` is in, if it is shared
+    fn run_of(&self, cluster_number: ClusterNumber) -> Option<&RefCountRun> {
+        self.runs
+            .range(..=cluster_number)
+            .next_back()
+            .map(|(_, run)| run)
+            .filter(|run| cluster_number < run.cluster_start_number + run.clusters_used)
+    }
+
+    /// Owners of a cluster that is in use
+    pub fn count(&self, cluster_number: ClusterNumber) -> u32 {
+        self.run_of(cluster_number).map_or(1, |run| run.count)
+    }
+
+    /// Whether something other than the live fs still has the cluster, so it cant be written in place
+    pub fn is_shared(&self, cluster_number: ClusterNumber) -> bool {
+        self.run_of(cluster_number).is_some()
+    }
+
+    /// Make a run start at `cluster_number` if one goes over it
+    fn split(&mut self, cluster_number: ClusterNumber) {
+        let Some(run) = self.run_of(cluster_number).copied() else {
+            return;
+        };
+        if run.cluster_start_number == cluster_number {
+            return;
+        }
+
+        let head = cluster_number - run.cluster_start_number;
+        self.runs.insert(
+            run.cluster_start_number,
+            RefCountRun {
+                clusters_used: head,
+                ..run
+            },
+        );
+        self.runs.insert(
+            cluster_number,
+            RefCountRun {
+                cluster_start_number: cluster_number,
+                clusters_used: run.clusters_used - head,
+                count: run.count,
+            },
+        );
+    }
+
+    /// Join the runs from the one before `start` up to `end` that are neighbours with the same count
+    fn join(&mut self, start: ClusterNumber, end: ClusterNumber) {
+        let from = self
+            .runs
+            .range(..start)
+            .next_back()
+            .map_or(start, |(c, _)| *c);
+        let keys: Vec<ClusterNumber> = self.runs.range(from..=end).map(|(c, _)| *c).collect();
+
+        let mut last: Option<ClusterNumber> = None;
+        for key in keys {
+            let run = self.runs[&key];
+            if let Some(prev) = last.and_then(|c| self.runs.get_mut(&c)) {
+                if prev.count == run.count && prev.cluster_start_number + prev.clusters_used == key
+                {
+                    prev.clusters_used += run.clusters_used;
+                    self.runs.remove(&key);
+                    continue;
+                }
+            }
+            last = Some(key);
+        }
+    }
+
+    /// Cut the runs at the edges of `data_node` and list what is in it in order: a shared run, or a gap nobody
+    /// else has as a run with count 1
+    fn pieces(&mut self, data_node: DataNode) -> Vec<RefCountRun> {
+        let start = data_node.cluster_start_number();
+        let end = start + data_node.clusters_used();
+        self.split(start);
+        self.split(end);
+
+        let mut pieces = Vec::new();
+        let mut at = start;
+        for run in self.runs.range(start..end).map(|(_, run)| *run) {
+            if at < run.cluster_start_number {
+                pieces.push(RefCountRun {
+                    cluster_start_number: at,
+                    clusters_used: run.cluster_start_number - at,
+                    count: 1,
+                });
+            }
+            at = run.cluster_start_number + run.clusters_used;
+            pieces.push(run);
+        }
+        if at < end {
+            pieces.push(RefCountRun {
+                cluster_start_number: at,
+                clusters_used: end - at,
+                count: 1,
+            });
+        }
+
+        pieces
+    }
+
+    /// One more owner for every cluster of `data_node`
+    pub fn share(&mut self, data_node: DataNode) {
+        if data_node.clusters_used() == 0 {
+            return;
+        }
+
+        for piece in self.pieces(data_node) {
+            self.runs.insert(
+                piece.cluster_start_number,
+                RefCountRun {
+                    count: piece.count + 1,
+                    ..piece
+                },
+            );
+        }
+        let start = data_node.cluster_start_number();
+        self.join(start, start + data_node.clusters_used());
+        self.dirty = true;
+    }
+
+    /// One less owner for every cluster of `data_node`. Returns the ones nobody owns anymore, which are free
+    pub fn release(&mut self, data_node: DataNode) -> Vec<DataNode> {
+        // nothing shared in it, the common case
+        let start = data_node.cluster_start_number();
+        let end = start + data_node.clusters_used();
+        if start == end || !self.is_shared(start) && self.runs.range(start..end).next().is_none() {
+            return Vec::from([data_node]);
+        }
+
+        let mut freed = Vec::new();
+        for piece in self.pieces(data_node) {
+            match piece.count {
+                1 => freed.push(DataNode::new(
+                    piece.clusters_used,
+                    piece.cluster_start_number,
+                )),
+                2 => {
+                    self.runs.remove(&piece.cluster_start_number);
+                }
+                count => {
+                    self.runs.insert(
+                        piece.cluster_start_number,
+                        RefCountRun {
+                            count: count - 1,
+                            ..piece
+                        },
+                    );
+                }
+            }
+        }
+        self.join(start, end);
+        self.dirty = true;
+
+        freed
+    }
+
+    /// Clusters `store` takes at most
+    pub fn n_store_clusters(&self) -> u64 {
+        self.runs.len().div_ceil(REF_RUNS_PER_NODE) as u64
+    }
+
+    /// Write the table to a new chain from `free_list`, if it changed. The old chain goes in `released`,
+    /// the last commit still points at it
+    pub fn store(
+        &mut self,
+        driver: &mut impl BlockDriver,
+        free_list: &mut FreeList,
+        released: &mut Vec<DataNode>,
+    ) -> Result<(), FsError> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let runs: Vec<RefCountRun> = self.runs.values().copied().collect();
+        let mut chain = Vec::new();
+        for _ in 0..runs.len().div_ceil(REF_RUNS_PER_NODE) {
+            let cluster = free_list.alloc(1).ok_or(FsError::NoSpace)?;
+            chain.push(cluster.cluster_start_number());
+        }
+
+        let mut chunks = runs.chunks(REF_RUNS_PER_NODE);
+        for (i, cluster_number) in chain.iter().enumerate() {
+            let node = RefCountNode {
+                header: NodeHeader::new(0),
+                next: chain.get(i + 1).copied().unwrap_or(0),
+                runs: chunks.next().map(|c| c.to_vec()).unwrap_or_default(),
+            };
+            write_block(driver, *cluster_number, encode_cluster(&node)?)?;
+        }
+
+        for cluster_number in core::mem::replace(&mut self.chain, chain) {
+            released.push(DataNode::new(1, cluster_number));
+        }
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_refcounts_share_release() {
+    use crate::driver::block::RamDisk;
+
+    let mut counts = RefCounts::default();
+    assert!(!counts.is_shared(10));
+    // an unshared run is freed whole
+    assert_eq!(counts.release(DataNode::new(3, 10)), [DataNode::new(3, 10)]);
+
+    counts.share(DataNode::new(4, 10));
+    counts.share(DataNode::new(1, 11));
+    assert_eq!(counts.count(10), 2);
+    assert_eq!(counts.count(11), 3);
+    // a run only splits where the count changes
+    let runs: Vec<RefCountRun> = counts.runs.values().copied().collect();
+    let run = |cluster_start_number, clusters_used, count| RefCountRun {
+        cluster_start_number,
+        clusters_used,
+        count,
+    };
+    assert_eq!(runs, [run(10, 1, 2), run(11, 1, 3), run(12, 2, 2)]);
+
+    // the owners left keep it
+    assert_eq!(counts.release(DataNode::new(4, 10)), []);
+    assert!(!counts.is_shared(10));
+    assert_eq!(counts.count(11), 2);
+
+    // only the clusters nobody else has come back
+    assert_eq!(
+        counts.release(DataNode::new(5, 8)),
+        [DataNode::new(3, 8), DataNode::new(1, 12)]
+    );
+    assert_eq!(counts.count(11), 1);
+
+    // a big share is one run however many clusters it has
+    counts.share(DataNode::new(1 << 40, 1 << 20));
+    assert_eq!(counts.runs.len(), 1);
+    assert_eq!(counts.n_store_clusters(), 1);
+    assert_eq!(counts.release(DataNode::new(1 << 40, 1 << 20)), []);
+    assert!(counts.is_empty());
+
+    counts.share(DataNode::new(200, 100));
+    counts.share(DataNode::new(1, 150));
+    assert_eq!(counts.runs.len(), 3);
+    let mut disk = RamDisk::new(64);
+    let mut free_list = FreeList::new(2, 3, 64);
+    let mut released = Vec::new();
+    counts
+        .store(&mut disk, &mut free_list, &mut released)
+        .unwrap();
+    assert!(!counts.is_dirty());
+
+    let loaded = RefCounts::load(&mut disk, counts.addr()).unwrap();
+    assert_eq!(loaded.runs, counts.runs);
+    assert_eq!(loaded.count(150), 3);
+    assert_eq!(loaded.count(300), 1);
+}
@@ -0,0 +1,347 @@
+// -------------
+// REFLINKS
+// -------------
+
+// A clone shares the source's clusters instead of copying them. Each file holding a cluster is one of its owners in
+// RefCounts, same as a file in a snapshot, so writing to either file later only copies the clusters written,
+// see Inode::unshare. Ranges go by whole clusters, except the last one may be the partial cluster at the end of the source
+
+use super::{Change, DataNode, FsError, Inode, InodeNumber, NeFS, PAGE_SIZE};
+use crate::driver::block::BlockDriver;
+use alloc::vec::Vec;
+
+/// Split `runs` after the first `at` clusters
+fn split_runs(runs: &[DataNode], at: u64) -> (Vec<DataNode>, Vec<DataNode>) {
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    let mut skipped = 0;
+
+    for run in runs {
+        if skipped + run.clusters_used <= at {
+            before.push(*run);
+        } else if skipped >= at {
+            after.push(*run);
+        } else {
+            let n = at - skipped;
+            before.push(DataNode::new(n, run.cluster_start_number));
+            after.push(DataNode::new(
+                run.clusters_used - n,
+                run.cluster_start_number + n,
+            ));
+        }
+        skipped += run.clusters_used;
+    }
+
+    (before, after)
+}
+
+/// Add `run` to the end of `runs`, as part of the last one if it follows on from it
+fn push_run(runs: &mut Vec<DataNode>, run: DataNode) {
+    match runs.last_mut() {
+        Some(last)
+            if last.cluster_start_number + last.clusters_used == run.cluster_start_number =>
+        {
+            last.clusters_used += run.clusters_used
+        }
+        _ => runs.push(run),
+    }
+}
+
+impl<'fs, D: BlockDriver> Inode<'fs, D> {
+    /// The runs holding clusters `first..first + n` of the file
+    fn cluster_runs(&self, first: u64, n: u64) -> Vec<DataNode> {
+        let (_, rest) = split_runs(&self.data_nodes, first);
+        let (runs, _) = split_runs(&rest, n);
+
+        runs
+    }
+
+    /// Make `runs` clusters `first..` of the file, sharing them with whoever has them now. Clusters they replace are
+    /// let go of, a gap before `first` is filled with zeroed clusters
+    fn share_clusters(&mut self, first: u64, runs: &[DataNode]) -> Result<(), FsError> {
+        if first > self.n_clusters() {
+            self.grow(first, 0..0)?;
+        }
+        self.volume.share(runs)?;
+
+        let n: u64 = runs.iter().map(|r| r.clusters_used).sum();
+        let (before, rest) = split_runs(&self.data_nodes, first);
+        let (replaced, after) = split_runs(&rest, n);
+        for run in replaced {
+            self.volume.free(run)?;
+        }
+
+        self.data_nodes = before;
+        for run in runs.iter().chain(after.iter()) {
+            push_run(&mut self.data_nodes, *run);
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: BlockDriver> NeFS<D> {
+    /// Make `dst` a copy of `src` that shares all of its clusters. `dst` is created if it isnt there, and emptied if it is
+    pub fn clone_file(&mut self, src: &str, dst: &str) -> Result<(), FsError> {
+        let src_number = self.resolve(src, true)?;
+        let size = self.inode(src_number)?.size();
+
+        let (dst_number, created) = match self.resolve(dst, true) {
+            Ok(number) => (number, false),
+            Err(FsError::NotFound) => (self.create(dst)?, true),
+            Err(e) => return Err(e),
+        };
+        if dst_number == src_number {
+            return Ok(());
+        }
+
+        let result = self
+            .inode(dst_number)
+            .and_then(|mut inode| inode.set_contents(&[]))
+            .and_then(|_| self.clone_inode_range(src_number, 0, dst_number, 0, size));
+        // dont leave behind an empty file that wasnt there before
+        if result.is_err() && created {
+            let _ = self.unlink(dst);
+        }
+
+        result
+    }
+
+    /// Make `len` bytes of `dst` at `dst_offset` share the clusters of `src` at `src_offset`. Both offsets have to be
+    /// on a cluster boundary, and so does `len` unless the range runs to the end of `src`. `dst` grows to fit
+    pub fn clone_range(
+        &mut self,
+        src: &str,
+        src_offset: u64,
+        dst: &str,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<(), FsError> {
+        let src_number = self.resolve(src, true)?;
+        let dst_number = self.resolve(dst, true)?;
+
+        self.clone_inode_range(src_number, src_offset, dst_number, dst_offset, len)
+    }
+
+    fn clone_inode_range(
+        &mut self,
+        src_number: InodeNumber,
+        src_offset: u64,
+        dst_number: InodeNumber,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<(), FsError> {
+        // not is_multiple_of, older toolchains dont have it
+        #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+        let aligned = |n: u64| n % PAGE_SIZE == 0;
+        let src = self.inode(src_number)?;
+        let src_end = src_offset.checked_add(len).ok_or(FsError::InvalidRange)?;
+        let dst_end = dst_offset.checked_add(len).ok_or(FsError::InvalidRange)?;
+        if !aligned(src_offset)
+            || !aligned(dst_offset)
+            || src_end > src.size()
+            || (!aligned(len) && src_end != src.size())
+        {
+            return Err(FsError::InvalidRange);
+        }
+        // a file can clone to another part of itself, just not onto the part it is cloning
+        if src_number == dst_number && src_offset < dst_end && dst_offset < src_end {
+            return Err(FsError::InvalidRange);
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        let runs = src.cluster_runs(src_offset / PAGE_SIZE, len.div_ceil(PAGE_SIZE));
+        let mut dst = self.inode(dst_number)?;
+        // the partial last cluster would cut off whatever dst has after it
+        if !aligned(len) && dst_end < dst.size() {
+            return Err(FsError::InvalidRange);
+        }
+
+        dst.share_clusters(dst_offset / PAGE_SIZE, &runs)?;
+        if dst_end > dst.record.size {
+            dst.record.size = dst_end;
+        }
+        dst.touch_modified();
+        dst.write_leaf()?;
+        dst.volume.changed(Change::Data);
+
+        Ok(())
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[cfg(test)]
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn test_clone_file() {
+    use super::{read_file, test_fs, OpenFlags};
+
+    let mut fs = test_fs(256);
+    let contents = pattern(10 * PAGE_SIZE as usize + 100);
+    fs.create("/image").unwrap();
+    fs.open("/image", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(&contents)
+        .unwrap();
+    fs.commit().unwrap();
+    let used = fs.superblock().n_sectors_used();
+
+    // no data copied, just a leaf and its tower, the dir entry and the counts
+    fs.clone_file("/image", "/copy").unwrap();
+    fs.commit().unwrap();
+    assert_eq!(read_file(&mut fs, "/copy"), contents);
+    assert!(fs.superblock().n_sectors_used() < used + 5);
+
+    // a write only copies the cluster it lands in
+    fs.open("/copy", OpenFlags::NONE)
+        .unwrap()
+        .write_bytes(b"changed", PAGE_SIZE * 3 + 10)
+        .unwrap();
+    fs.commit().unwrap();
+    let mut clusters = |path| -> Vec<super::ClusterNumber> {
+        let inode = fs.open(path, OpenFlags::NONE).unwrap();
+        inode
+            .data_nodes()
+            .iter()
+            .flat_map(|d| d.cluster_start_number..d.cluster_start_number + d.clusters_used)
+            .collect()
+    };
+    let (image, copy) = (clusters("/image"), clusters("/copy"));
+    let differ: Vec<usize> = (0..image.len()).filter(|i| image[*i] != copy[*i]).collect();
+    assert_eq!(differ, [3]);
+    assert_eq!(read_file(&mut fs, "/image"), contents);
+    let mut expected = contents.clone();
+    expected[PAGE_SIZE as usize * 3 + 10..][..7].copy_from_slice(b"changed");
+    assert_eq!(read_file(&mut fs, "/copy"), expected);
+
+    // the copy outlives the original
+    fs.unlink("/image").unwrap();
+    let mut fs = NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(read_file(&mut fs, "/copy"), expected);
+
+    // cloning over a file replaces it
+    fs.create("/small").unwrap();
+    fs.open("/small", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(b"small")
+        .unwrap();
+    fs.clone_file("/small", "/copy").unwrap();
+    assert_eq!(read_file(&mut fs, "/copy"), b"small");
+    fs.unlink("/copy").unwrap();
+    assert_eq!(read_file(&mut fs, "/small"), b"small");
+    assert_eq!(fs.clone_file("/", "/x").err(), Some(FsError::IsADirectory));
+}
+
+#[test]
+fn test_clone_file_no_space() {
+    use super::{read_file, test_fs, OpenFlags};
+
+    // a clone that runs out of room partway doesnt leave an empty dst behind. Try it with less and less room left
+    let contents = pattern(4 * PAGE_SIZE as usize);
+    for n_clusters in [64, 80] {
+        let mut n_failed = 0;
+        for n_pages in 0.. {
+            let mut fs = test_fs(n_clusters);
+            fs.create("/image").unwrap();
+            fs.open("/image", OpenFlags::NONE)
+                .unwrap()
+                .set_contents(&contents)
+                .unwrap();
+            fs.create("/filler").unwrap();
+            let filler = alloc::vec![1; n_pages * PAGE_SIZE as usize];
+            if fs
+                .open("/filler", OpenFlags::NONE)
+                .unwrap()
+                .set_contents(&filler)
+                .is_err()
+            {
+                break;
+            }
+
+            match fs.clone_file("/image", "/copy") {
+                Ok(()) => assert_eq!(read_file(&mut fs, "/copy"), contents),
+                Err(e) => {
+                    assert_eq!(e, FsError::NoSpace);
+                    assert_eq!(fs.stat("/copy").err(), Some(FsError::NotFound));
+                    n_failed += 1;
+                }
+            }
+        }
+        assert!(n_failed > 0);
+    }
+}
+
+#[test]
+fn test_clone_range() {
+    use super::{read_file, test_fs, OpenFlags};
+
+    let page = PAGE_SIZE as usize;
+    let mut fs = test_fs(256);
+    let src = pattern(4 * page + 7);
+    fs.create("/src").unwrap();
+    fs.open("/src", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(&src)
+        .unwrap();
+    fs.create("/dst").unwrap();
+    fs.open("/dst", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(&[9; 3 * 4096])
+        .unwrap();
+
+    // the middle of dst
+    fs.clone_range("/src", PAGE_SIZE, "/dst", PAGE_SIZE, PAGE_SIZE)
+        .unwrap();
+    let mut expected = alloc::vec![9; 3 * page];
+    expected[page..2 * page].copy_from_slice(&src[page..2 * page]);
+    assert_eq!(read_file(&mut fs, "/dst"), expected);
+
+    // past the end of dst with a gap, up to the partial end of src
+    fs.clone_range("/src", 3 * PAGE_SIZE, "/dst", 5 * PAGE_SIZE, PAGE_SIZE + 7)
+        .unwrap();
+    expected.resize(5 * page, 0);
+    expected.extend_from_slice(&src[3 * page..]);
+    assert_eq!(read_file(&mut fs, "/dst"), expected);
+
+    // to another part of the same file
+    fs.clone_range("/src", 0, "/src", 6 * PAGE_SIZE, PAGE_SIZE)
+        .unwrap();
+    assert_eq!(read_file(&mut fs, "/src")[6 * page..], src[..page]);
+
+    let bad = [
+        (1, 0, PAGE_SIZE),
+        (0, 1, PAGE_SIZE),
+        (0, 0, 10),
+        (0, 0, 100 * PAGE_SIZE),
+        (0, 0, u64::MAX),
+    ];
+    for (src_offset, dst_offset, len) in bad {
+        assert_eq!(
+            fs.clone_range("/src", src_offset, "/dst", dst_offset, len),
+            Err(FsError::InvalidRange)
+        );
+    }
+    assert_eq!(
+        fs.clone_range("/src", 0, "/src", PAGE_SIZE, 2 * PAGE_SIZE),
+        Err(FsError::InvalidRange)
+    );
+    // the partial end of src into the middle of dst
+    assert_eq!(
+        fs.clone_range("/src", 3 * PAGE_SIZE, "/dst", 0, PAGE_SIZE + 7),
+        Err(FsError::InvalidRange)
+    );
+
+    // everything is let go of cleanly
+    fs.unlink("/src").unwrap();
+    fs.unlink("/dst").unwrap();
+    let fs = NeFS::mount(fs.unmount()).unwrap();
+    assert!(fs.volume.refcounts.is_empty());
+}
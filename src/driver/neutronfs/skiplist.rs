@@ -0,0 +1,413 @@
+// -------------
+// SKIPLIST
+// -------------
+
+// The inode index. Every node is a cluster, every pointer is a node number
+// Each inode has a tower (InternalNode) linking it into every level it rolled, and a leaf (LeafNode) holding its items
+// Search goes right while the next key is smaller, then down a level. Like RootList::search in docs/NOTES.md
+
+use super::{
+    generate_level, FsError, InodeNumber, InodeRecord, InternalNode, ItemType, LeafNode,
+    NodeHeader, NodeNumber, Payload, SuperBlock, Volume, MAX_INTERNAL_ITEMS_PER_NODE,
+};
+use crate::driver::block::BlockDriver;
+use core::ops::{Bound, RangeBounds};
+use rand_mt::Mt19937GenRand64;
+
+/// Handle to the on disk skiplist. Only the head's node number and the level rng live in memory
+pub struct SkipList {
+    head: NodeNumber,
+    mt: Mt19937GenRand64,
+}
+
+impl SkipList {
+    pub fn new(head: NodeNumber, seed: u64) -> Self {
+        Self {
+            head,
+            mt: Mt19937GenRand64::new(seed),
+        }
+    }
+
+    /// The skiplist a superblock points to. Levels are seeded off the fs uuid and the generation, so each mount rolls
+    /// new ones instead of repeating the last mount's
+    pub fn open(superblock: &SuperBlock) -> Self {
+        let mut seed = [0; 8];
+        seed.copy_from_slice(&superblock.fs_uuid[..8]);
+        let seed = u64::from_le_bytes(seed).wrapping_add(superblock.generation);
+
+        Self::new(superblock.core_fs_skiplist_addr, seed)
+    }
+
+    pub fn head(&self) -> NodeNumber {
+        self.head
+    }
+
+    /// For each level, the last tower with a key smaller than `inode`
+    fn predecessors<D: BlockDriver>(
+        &self,
+        volume: &mut Volume<D>,
+        inode: InodeNumber,
+    ) -> Result<[NodeNumber; MAX_INTERNAL_ITEMS_PER_NODE], FsError> {
+        let mut update = [self.head; MAX_INTERNAL_ITEMS_PER_NODE];
+        let mut curr = self.head;
+        let mut curr_node = volume.read_internal(curr)?;
+
+        for level in (0..MAX_INTERNAL_ITEMS_PER_NODE).rev() {
+            loop {
+                let next = curr_node.pointers[level];
+                // end of this level
+                if next == 0 {
+                    break;
+                }
+
+                let next_node = volume.read_internal(next)?;
+                // node bounded, go down a level
+                if next_node.key >= inode {
+                    break;
+                }
+
+                // node farther away, go next node
+                curr = next;
+                curr_node = next_node;
+            }
+
+            update[level] = curr;
+        }
+
+        Ok(update)
+    }
+
+    /// The tower with exactly this key, if there is one
+    fn find<D: BlockDriver>(
+        &self,
+        volume: &mut Volume<D>,
+        inode: InodeNumber,
+    ) -> Result<Option<(NodeNumber, InternalNode)>, FsError> {
+        let update = self.predecessors(volume, inode)?;
+        let candidate = volume.read_internal(update[0])?.pointers[0];
+        if candidate == 0 {
+            return Ok(None);
+        }
+
+        let node = volume.read_internal(candidate)?;
+        if node.key == inode {
+            Ok(Some((candidate, node)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Node of the leaf that holds the inode's items
+    pub fn lookup<D: BlockDriver>(
+        &self,
+        volume: &mut Volume<D>,
+        inode: InodeNumber,
+    ) -> Result<NodeNumber, FsError> {
+        match self.find(volume, inode)? {
+            Some((_, node)) => Ok(node.leaf),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    /// Highest inode in the list
+    pub fn last<D: BlockDriver>(
+        &self,
+        volume: &mut Volume<D>,
+    ) -> Result<Option<InodeNumber>, FsError> {
+        let update = self.predecessors(volume, InodeNumber::MAX)?;
+        if update[0] == self.head {
+            return Ok(None);
+        }
+
+        Ok(Some(volume.read_internal(update[0])?.key))
+    }
+
+    /// Add an inode with an empty leaf. Returns the leaf's node
+    pub fn insert<D: BlockDriver>(
+        &mut self,
+        volume: &mut Volume<D>,
+        inode: InodeNumber,
+    ) -> Result<NodeNumber, FsError> {
+        if inode == 0 {
+            return Err(FsError::ReservedInode);
+        }
+
+        let update = self.predecessors(volume, inode)?;
+        let candidate = volume.read_internal(update[0])?.pointers[0];
+        if candidate != 0 && volume.read_internal(candidate)?.key == inode {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let n_levels = generate_level(&mut self.mt);
+        // the leaf, the tower, and a copy of each predecessor. Running out halfway through splicing would tear the list
+        volume.ensure_free(n_levels as u64 + 2)?;
+
+        let leaf_node = volume.alloc_node()?;
+        let tower_node = match volume.alloc_node() {
+            Ok(n) => n,
+            Err(e) => {
+                volume.free_node(leaf_node)?;
+                return Err(e);
+            }
+        };
+
+        let leaf = LeafNode::new(
+            inode,
+            &InodeRecord::default(),
+            ItemType::Payload(Payload::default()),
+        );
+        volume.write_node(leaf_node, &leaf)?;
+
+        let mut tower = InternalNode {
+            header: NodeHeader::new(n_levels as u64),
+            key: inode,
+            leaf: leaf_node,
+            pointers: [0; MAX_INTERNAL_ITEMS_PER_NODE],
+        };
+
+        // new tower points at what its predecessors pointed at, written before anything links to it
+        for (level, pred) in update.iter().enumerate().take(n_levels) {
+            tower.pointers[level] = volume.read_internal(*pred)?.pointers[level];
+        }
+        volume.write_node(tower_node, &tower)?;
+
+        // splice in bottom up. A predecessor can span several levels, so reread each time
+        for (level, pred_node) in update.iter().enumerate().take(n_levels) {
+            let mut pred = volume.read_internal(*pred_node)?;
+            pred.pointers[level] = tower_node;
+            volume.write_node(*pred_node, &pred)?;
+        }
+
+        Ok(leaf_node)
+    }
+
+    /// Unlink an inode's tower and free it along with its leaf
+    pub fn remove<D: BlockDriver>(
+        &mut self,
+        volume: &mut Volume<D>,
+        inode: InodeNumber,
+    ) -> Result<(), FsError> {
+        let update = self.predecessors(volume, inode)?;
+        let candidate = volume.read_internal(update[0])?.pointers[0];
+        if candidate == 0 {
+            return Err(FsError::NotFound);
+        }
+
+        let tower = volume.read_internal(candidate)?;
+        if tower.key != inode {
+            return Err(FsError::NotFound);
+        }
+        // a copy of each predecessor
+        volume.ensure_free(tower.header.n_levels)?;
+
+        // top down, so a reader going right never lands on the removed tower from above
+        for level in (0..tower.header.n_levels as usize).rev() {
+            let mut pred = volume.read_internal(update[level])?;
+            if pred.pointers[level] == candidate {
+                pred.pointers[level] = tower.pointers[level];
+                volume.write_node(update[level], &pred)?;
+            }
+        }
+
+        volume.free_node(candidate)?;
+        volume.free_node(tower.leaf)
+    }
+
+    /// Walk inodes in order, starting from the first one in range
+    pub fn range<'v, D: BlockDriver>(
+        &self,
+        volume: &'v mut Volume<D>,
+        range: impl RangeBounds<InodeNumber>,
+    ) -> Result<SkipListIter<'v, D>, FsError> {
+        let start = match range.start_bound() {
+            Bound::Included(s) => *s,
+            Bound::Excluded(s) => s.saturating_add(1),
+            Bound::Unbounded => 1,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(e) => Bound::Included(*e),
+            Bound::Excluded(e) => Bound::Excluded(*e),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        let update = self.predecessors(volume, start)?;
+        let next = volume.read_internal(update[0])?.pointers[0];
+
+        Ok(SkipListIter { volume, next, end })
+    }
+}
+
+/// Follows level 0. Yields (inode, leaf node)
+pub struct SkipListIter<'v, D: BlockDriver> {
+    volume: &'v mut Volume<D>,
+    next: NodeNumber,
+    end: Bound<InodeNumber>,
+}
+
+impl<'v, D: BlockDriver> Iterator for SkipListIter<'v, D> {
+    type Item = Result<(InodeNumber, NodeNumber), FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == 0 {
+            return None;
+        }
+
+        let node = match self.volume.read_internal(self.next) {
+            Ok(n) => n,
+            Err(e) => {
+                // dont keep walking a broken list
+                self.next = 0;
+                return Some(Err(e));
+            }
+        };
+
+        let in_range = match self.end {
+            Bound::Included(e) => node.key <= e,
+            Bound::Excluded(e) => node.key < e,
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            self.next = 0;
+            return None;
+        }
+
+        self.next = node.pointers[0];
+        Some(Ok((node.key, node.leaf)))
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[cfg(test)]
+fn test_volume(n_clusters: u64) -> Volume<crate::driver::block::RamDisk> {
+    use super::mkfs;
+    use crate::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(n_clusters as usize);
+    mkfs(&mut disk, n_clusters, "skiplist").unwrap();
+    let mut volume = Volume::open(disk).unwrap();
+
+    // these tests want the index to themselves
+    let mut list = SkipList::open(volume.superblock());
+    list.remove(&mut volume, super::ROOT_INODE).unwrap();
+    volume.commit().unwrap();
+
+    volume
+}
+
+#[test]
+fn test_skiplist_levels_differ_across_commits() {
+    let volume = test_volume(64);
+    let mut superblock = volume.superblock().clone();
+    let rolls = |superblock: &SuperBlock| {
+        let mut list = SkipList::open(superblock);
+        (0..64)
+            .map(|_| generate_level(&mut list.mt))
+            .collect::<alloc::vec::Vec<_>>()
+    };
+
+    let before = rolls(&superblock);
+    assert_eq!(rolls(&superblock), before);
+    superblock.generation += 1;
+    assert_ne!(rolls(&superblock), before);
+}
+
+#[test]
+fn test_skiplist_insert_lookup() {
+    let mut volume = test_volume(512);
+    let mut list = SkipList::open(volume.superblock());
+
+    for inode in [5, 1, 9, 3, 7] {
+        list.insert(&mut volume, inode).unwrap();
+    }
+
+    for inode in [1, 3, 5, 7, 9] {
+        let leaf = list.lookup(&mut volume, inode).unwrap();
+        assert_eq!(volume.read_leaf(leaf).unwrap().inode, inode);
+    }
+
+    assert_eq!(list.lookup(&mut volume, 4), Err(FsError::NotFound));
+    assert_eq!(list.insert(&mut volume, 5), Err(FsError::AlreadyExists));
+    assert_eq!(list.insert(&mut volume, 0), Err(FsError::ReservedInode));
+}
+
+#[test]
+fn test_skiplist_remove() {
+    let mut volume = test_volume(512);
+    let mut list = SkipList::open(volume.superblock());
+    let used_before = volume.superblock().n_sectors_used();
+
+    for inode in 1..=50 {
+        list.insert(&mut volume, inode).unwrap();
+    }
+    for inode in (2..=50).step_by(2) {
+        list.remove(&mut volume, inode).unwrap();
+    }
+
+    assert_eq!(list.remove(&mut volume, 2), Err(FsError::NotFound));
+    assert_eq!(list.lookup(&mut volume, 4), Err(FsError::NotFound));
+    assert!(list.lookup(&mut volume, 49).is_ok());
+
+    for inode in (1..=50).step_by(2) {
+        list.remove(&mut volume, inode).unwrap();
+    }
+    // every tower and leaf went back
+    volume.commit().unwrap();
+    assert_eq!(volume.superblock().n_sectors_used(), used_before);
+}
+
+#[test]
+fn test_skiplist_range_persists() {
+    use alloc::vec::Vec;
+
+    let mut volume = test_volume(512);
+    let mut list = SkipList::open(volume.superblock());
+    for inode in (1..=40).rev() {
+        list.insert(&mut volume, inode).unwrap();
+    }
+
+    // remount, the list is all on disk
+    volume.commit().unwrap();
+    let mut volume = Volume::open(volume.into_driver()).unwrap();
+    let list = SkipList::open(volume.superblock());
+
+    let all: Vec<InodeNumber> = list
+        .range(&mut volume, ..)
+        .unwrap()
+        .map(|r| r.unwrap().0)
+        .collect();
+    assert_eq!(all, (1..=40).collect::<Vec<_>>());
+
+    let some: Vec<InodeNumber> = list
+        .range(&mut volume, 10..15)
+        .unwrap()
+        .map(|r| r.unwrap().0)
+        .collect();
+    assert_eq!(some, [10, 11, 12, 13, 14]);
+
+    assert_eq!(list.range(&mut volume, 41..).unwrap().count(), 0);
+    assert_eq!(list.last(&mut volume), Ok(Some(40)));
+}
+
+#[test]
+fn test_skiplist_out_of_space() {
+    let mut volume = test_volume(32);
+    let mut list = SkipList::open(volume.superblock());
+
+    let mut inode = 1;
+    while list.insert(&mut volume, inode).is_ok() {
+        inode += 1;
+    }
+    assert!(inode > 1);
+
+    // the failed insert left nothing behind and the list is whole
+    assert_eq!(list.lookup(&mut volume, inode), Err(FsError::NotFound));
+    assert_eq!(
+        list.range(&mut volume, ..).unwrap().count() as u64,
+        inode - 1
+    );
+    // and there is still room to commit it
+    volume.commit().unwrap();
+}
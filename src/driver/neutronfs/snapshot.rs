@@ -0,0 +1,546 @@
+// -------------
+// SNAPSHOTS
+// -------------
+
+// A snapshot keeps a commit around after the live fs moves on. Every node is found through the node map, so holding on
+// to the map a commit had is holding on to the whole commit. Taking one walks that commit once and adds an owner to every
+// cluster in it, see RefCounts. Nothing is copied until the live fs writes to a shared cluster
+// The table of snapshots is a single node, rewritten somewhere new by every commit that changed it
+
+use super::{
+    checksum::verify_node, dir::validate_name, encode_cluster, ClusterNumber, DataNode,
+    FlushPolicy, FreeList, FsError, InternalNode, ItemType, LeafNode, MountError, NeFS, NodeHeader,
+    NodeMap, SkipList, Snapshot, SnapshotTableNode, Timestamp, Volume, BINCODE_CONFIG,
+    INODE_CACHE_SIZE,
+};
+use crate::driver::block::{read_block, write_block, BlockDriver};
+use crate::driver::cache::Lru;
+use alloc::{collections::BTreeSet, string::String, vec::Vec};
+
+/// Most snapshots there can be at once. Keeps the table inside a cluster
+pub const MAX_SNAPSHOTS: usize = 32;
+/// Longest snapshot name in bytes
+pub const MAX_SNAPSHOT_NAME_LEN: usize = 64;
+
+/// The snapshot table, loaded into memory
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotTable {
+    // cluster of the table as of the last commit, 0 if it isnt stored
+    addr: ClusterNumber,
+    snapshots: Vec<Snapshot>,
+    // changed since the last commit
+    dirty: bool,
+}
+
+impl SnapshotTable {
+    /// Read the table at `addr`. 0 is an empty table
+    pub fn load(driver: &mut impl BlockDriver, addr: ClusterNumber) -> Result<Self, MountError> {
+        if addr == 0 {
+            return Ok(Self::default());
+        }
+
+        let block = read_block(driver, addr)?;
+        verify_node(&block, addr).map_err(|_| MountError::BadNodeChecksum(addr))?;
+        let (node, _): (SnapshotTableNode, usize) =
+            bincode::decode_from_slice(&block, BINCODE_CONFIG)
+                .map_err(|_| MountError::BadStructure(addr))?;
+
+        Ok(Self {
+            addr,
+            snapshots: node.snapshots,
+            dirty: false,
+        })
+    }
+
+    /// Where the table is on disk, 0 if it isnt stored
+    pub fn addr(&self) -> ClusterNumber {
+        self.addr
+    }
+
+    pub fn snapshots(&self) -> &[Snapshot] {
+        &self.snapshots
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.snapshots.iter().position(|s| s.name == name)
+    }
+
+    /// Clusters `store` takes at most
+    pub fn n_store_clusters(&self) -> u64 {
+        if self.snapshots.is_empty() {
+            0
+        } else {
+            1
+        }
+    }
+
+    /// Write the table to a new cluster from `free_list`, if it changed. The old cluster goes in `released`
+    pub fn store(
+        &mut self,
+        driver: &mut impl BlockDriver,
+        free_list: &mut FreeList,
+        released: &mut Vec<DataNode>,
+    ) -> Result<(), FsError> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let mut addr = 0;
+        if !self.snapshots.is_empty() {
+            let node = SnapshotTableNode {
+                header: NodeHeader::new(0),
+                snapshots: self.snapshots.clone(),
+            };
+            addr = free_list
+                .alloc(1)
+                .ok_or(FsError::NoSpace)?
+                .cluster_start_number();
+            write_block(driver, addr, encode_cluster(&node)?)?;
+        }
+
+        if self.addr != 0 {
+            released.push(DataNode::new(1, self.addr));
+        }
+        self.addr = addr;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+impl<D: BlockDriver> Volume<D> {
+    /// Every cluster the commit with this node map holds: the map, every node, and every file's data
+    fn tree_clusters(&mut self, map: &NodeMap) -> Result<Vec<DataNode>, FsError> {
+        let mut held = map.held_clusters();
+
+        // the bottom level of the skiplist has every inode
+        let head = self.superblock.core_fs_skiplist_addr;
+        let mut number = head;
+        while number != 0 {
+            let block = Self::read_mapped(&mut self.driver, map, number)?;
+            let (node, _): (InternalNode, usize) =
+                bincode::decode_from_slice(&block, BINCODE_CONFIG)
+                    .map_err(|_| FsError::Corrupt(number))?;
+
+            if number != head {
+                let block = Self::read_mapped(&mut self.driver, map, node.leaf)?;
+                let (leaf, _): (LeafNode, usize) =
+                    bincode::decode_from_slice(&block, BINCODE_CONFIG)
+                        .map_err(|_| FsError::Corrupt(node.leaf))?;
+                if let ItemType::Payload(payload) = leaf.item_type {
+                    held.extend(payload.data_nodes);
+                }
+            }
+
+            number = node.pointers[0];
+        }
+
+        // neighbours go in as one run, so the counts dont split them. Two files sharing a cluster stay two owners
+        held.sort_by_key(|run| run.cluster_start_number());
+        let mut runs: Vec<DataNode> = Vec::with_capacity(held.len());
+        for run in held {
+            match runs.last_mut() {
+                Some(last)
+                    if last.cluster_start_number() + last.clusters_used()
+                        == run.cluster_start_number() =>
+                {
+                    *last = DataNode::new(
+                        last.clusters_used() + run.clusters_used(),
+                        last.cluster_start_number(),
+                    )
+                }
+                _ => runs.push(run),
+            }
+        }
+
+        Ok(runs)
+    }
+
+    /// The node map of a snapshot
+    fn load_node_map(&mut self, root: ClusterNumber) -> Result<NodeMap, FsError> {
+        NodeMap::load(&mut self.driver, root).map_err(|e| match e {
+            MountError::Io(e) => FsError::Io(e),
+            MountError::BadNodeChecksum(cluster) => FsError::BadChecksum(cluster),
+            // the map isnt a node, it has no number of its own
+            _ => FsError::Corrupt(0),
+        })
+    }
+
+    /// Make a change to the node map, counts and snapshots, and take it back if there wouldnt be room to commit it
+    fn change_tables(&mut self, change: impl FnOnce(&mut Self)) -> Result<(), FsError> {
+        self.check_writable()?;
+        let node_map = self.node_map.clone();
+        let refcounts = self.refcounts.clone();
+        let snapshots = self.snapshots.clone();
+        let n_pending = self.pending.len();
+
+        change(self);
+
+        if self.free_list.n_free() < self.commit_reserve() {
+            self.node_map = node_map;
+            self.refcounts = refcounts;
+            self.snapshots = snapshots;
+            self.pending.truncate(n_pending);
+            return Err(FsError::NoSpace);
+        }
+        self.snapshots.dirty = true;
+        self.update_used();
+
+        Ok(())
+    }
+}
+
+impl<D: BlockDriver> NeFS<D> {
+    /// Commit, and keep that commit around as `name`
+    pub fn snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        validate_name(name)?;
+        if name.len() > MAX_SNAPSHOT_NAME_LEN {
+            return Err(FsError::NameTooLong);
+        }
+        if self.volume.snapshots.position(name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        if self.volume.snapshots.snapshots.len() >= MAX_SNAPSHOTS {
+            return Err(FsError::TooManySnapshots);
+        }
+
+        // from here the live map is exactly what is on disk, and nothing in it is fresh
+        self.volume.commit()?;
+        assert!(
+            self.volume.fresh.is_empty(),
+            "commit left fresh nodes behind"
+        );
+        let map = self.volume.node_map.clone();
+        let held = self.volume.tree_clusters(&map)?;
+
+        let snapshot = Snapshot {
+            name: String::from(name),
+            generation: self.volume.superblock.generation,
+            created: self.volume.now(),
+            node_map_addr: map.root(),
+        };
+        self.volume.change_tables(|volume| {
+            for run in held {
+                volume.refcounts.share(run);
+            }
+            volume.snapshots.snapshots.push(snapshot);
+        })?;
+
+        self.volume.commit()
+    }
+
+    /// Browse snapshot `name` as it was, without rolling back. The view reads through this fs's driver and shares every
+    /// cluster with it. Anything that would change it fails with ReadOnly, reads dont touch access times
+    pub fn snapshot_view(&mut self, name: &str) -> Result<NeFS<&mut D>, FsError> {
+        let index = self
+            .volume
+            .snapshots
+            .position(name)
+            .ok_or(FsError::NotFound)?;
+
+        let root = self.volume.snapshots.snapshots[index].node_map_addr;
+        let node_map = self.volume.load_node_map(root)?;
+        let volume = Volume {
+            driver: &mut self.volume.driver,
+            superblock: self.volume.superblock.clone(),
+            free_list: self.volume.free_list.clone(),
+            node_map,
+            refcounts: self.volume.refcounts.clone(),
+            snapshots: self.volume.snapshots.clone(),
+            pending: Vec::new(),
+            fresh: BTreeSet::new(),
+            dirty: false,
+            atime_only: false,
+            bad_slot: None,
+            read_only: true,
+            journal: None,
+            clock: self.volume.clock,
+            flush_policy: FlushPolicy::MANUAL,
+            n_pending: 0,
+            pending_since: Timestamp::default(),
+            commit_error: None,
+        };
+        let index = SkipList::open(&volume.superblock);
+
+        Ok(NeFS {
+            volume,
+            index,
+            inodes: Lru::new(INODE_CACHE_SIZE),
+            uid: self.uid,
+            gid: self.gid,
+        })
+    }
+
+    /// Every snapshot, oldest first
+    pub fn list_snapshots(&self) -> &[Snapshot] {
+        self.volume.snapshots.snapshots()
+    }
+
+    /// Drop a snapshot. Clusters only it had are free after the commit this ends with
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        let index = self
+            .volume
+            .snapshots
+            .position(name)
+            .ok_or(FsError::NotFound)?;
+
+        let root = self.volume.snapshots.snapshots[index].node_map_addr;
+        let map = self.volume.load_node_map(root)?;
+        let held = self.volume.tree_clusters(&map)?;
+
+        self.volume.change_tables(|volume| {
+            for run in held {
+                volume.release(run);
+            }
+            volume.snapshots.snapshots.remove(index);
+        })?;
+
+        self.volume.commit()
+    }
+
+    /// Throw away everything since snapshot `name` was taken. The snapshot stays, so it can be rolled back to again
+    pub fn rollback(&mut self, name: &str) -> Result<(), FsError> {
+        let index = self
+            .volume
+            .snapshots
+            .position(name)
+            .ok_or(FsError::NotFound)?;
+
+        self.volume.commit()?;
+        let root = self.volume.snapshots.snapshots[index].node_map_addr;
+        let map = self.volume.load_node_map(root)?;
+        let live = self.volume.node_map.clone();
+        let held = self.volume.tree_clusters(&map)?;
+        let dropped = self.volume.tree_clusters(&live)?;
+
+        // the live fs takes on the snapshot's clusters before letting go of its own, the ones both have stay put
+        self.volume.change_tables(|volume| {
+            for run in held {
+                volume.refcounts.share(run);
+            }
+            for run in dropped {
+                volume.release(run);
+            }
+            volume.node_map = map;
+        })?;
+        // the snapshot's leaves can be in other nodes
+        self.inodes.clear();
+
+        self.volume.commit()
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_snapshot_rollback() {
+    use super::{read_file, test_fs, OpenFlags};
+
+    let mut fs = test_fs(256);
+    fs.mkdir("/etc").unwrap();
+    fs.create("/etc/config").unwrap();
+    fs.open("/etc/config", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(&[1; 10000])
+        .unwrap();
+    fs.create("/old").unwrap();
+
+    fs.snapshot("pre-upgrade").unwrap();
+    let generation = fs.superblock().generation();
+    assert_eq!(fs.list_snapshots().len(), 1);
+    assert_eq!(fs.list_snapshots()[0].name(), "pre-upgrade");
+
+    // the upgrade: change a file in place, remove one, add one
+    fs.open("/etc/config", OpenFlags::NONE)
+        .unwrap()
+        .write_bytes(&[2; 100], 5000)
+        .unwrap();
+    fs.unlink("/old").unwrap();
+    fs.create("/new").unwrap();
+    fs.commit().unwrap();
+
+    let mut expected = alloc::vec![1; 10000];
+    expected[5000..5100].fill(2);
+    assert_eq!(read_file(&mut fs, "/etc/config"), expected);
+
+    fs.rollback("pre-upgrade").unwrap();
+    assert_eq!(read_file(&mut fs, "/etc/config"), [1; 10000]);
+    assert!(fs.stat("/old").is_ok());
+    assert_eq!(fs.stat("/new").err(), Some(FsError::NotFound));
+    assert!(fs.superblock().generation() > generation);
+
+    // it all survives a remount, the snapshot too
+    let mut fs = NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(read_file(&mut fs, "/etc/config"), [1; 10000]);
+    assert_eq!(fs.list_snapshots()[0].generation(), generation - 1);
+
+    // and it can be rolled back to again
+    fs.unlink("/etc/config").unwrap();
+    fs.rollback("pre-upgrade").unwrap();
+    assert_eq!(read_file(&mut fs, "/etc/config"), [1; 10000]);
+}
+
+#[test]
+fn test_snapshot_delete_frees() {
+    use super::{test_fs, OpenFlags};
+
+    let mut fs = test_fs(256);
+    fs.create("/file").unwrap();
+    fs.open("/file", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(&[1; 40000])
+        .unwrap();
+    fs.commit().unwrap();
+    let used = fs.superblock().n_sectors_used();
+
+    // nothing copied yet, just the two tables. The map hasnt changed, the snapshot shares its root with the fs
+    fs.snapshot("a").unwrap();
+    assert_eq!(fs.superblock().n_sectors_used(), used + 2);
+
+    // rewriting the file copies every cluster of it, the snapshot keeps the old ones
+    fs.open("/file", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(&[2; 40000])
+        .unwrap();
+    fs.commit().unwrap();
+    assert!(fs.superblock().n_sectors_used() >= used + 10);
+
+    // deleting the file only frees the copies, the snapshot still has the rest
+    fs.unlink("/file").unwrap();
+    fs.commit().unwrap();
+    assert!(fs.superblock().n_sectors_used() >= used);
+
+    fs.delete_snapshot("a").unwrap();
+    assert!(fs.list_snapshots().is_empty());
+    let after = fs.superblock().n_sectors_used();
+    assert!(after <= used - 10);
+
+    let fs = NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(fs.superblock().n_sectors_used(), after);
+}
+
+#[test]
+fn test_snapshot_names() {
+    let mut fs = super::test_fs(128);
+
+    fs.snapshot("a").unwrap();
+    assert_eq!(fs.snapshot("a"), Err(FsError::AlreadyExists));
+    assert_eq!(fs.snapshot(""), Err(FsError::InvalidName));
+    assert_eq!(
+        fs.snapshot(&"x".repeat(MAX_SNAPSHOT_NAME_LEN + 1)),
+        Err(FsError::NameTooLong)
+    );
+    assert_eq!(fs.rollback("b"), Err(FsError::NotFound));
+    assert_eq!(fs.delete_snapshot("b"), Err(FsError::NotFound));
+
+    for i in 1..MAX_SNAPSHOTS {
+        fs.snapshot(&alloc::format!("s{}", i)).unwrap();
+    }
+    assert_eq!(fs.snapshot("one more"), Err(FsError::TooManySnapshots));
+    assert_eq!(fs.list_snapshots().len(), MAX_SNAPSHOTS);
+}
+
+#[test]
+fn test_snapshot_view() {
+    use super::{read_file, test_fs, OpenFlags};
+
+    let mut fs = test_fs(256);
+    fs.mkdir("/etc").unwrap();
+    fs.create("/etc/config").unwrap();
+    fs.open("/etc/config", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(b"old")
+        .unwrap();
+    fs.snapshot("a").unwrap();
+
+    fs.open("/etc/config", OpenFlags::NONE)
+        .unwrap()
+        .set_contents(b"new")
+        .unwrap();
+    fs.create("/etc/added").unwrap();
+    let used = fs.superblock().n_sectors_used();
+    assert_eq!(fs.snapshot_view("b").err(), Some(FsError::NotFound));
+
+    {
+        let mut view = fs.snapshot_view("a").unwrap();
+        assert!(view.is_read_only());
+        assert_eq!(read_file(&mut view, "/etc/config"), b"old");
+        let names: Vec<String> = view
+            .readdir("/etc")
+            .unwrap()
+            .iter()
+            .map(|e| String::from(e.name()))
+            .collect();
+        assert_eq!(names, [".", "..", "config"]);
+        let accessed = view.stat("/etc/config").unwrap().record.accessed;
+        read_file(&mut view, "/etc/config");
+        assert_eq!(view.stat("/etc/config").unwrap().record.accessed, accessed);
+
+        // every way of changing it is turned away
+        assert_eq!(view.create("/etc/x"), Err(FsError::ReadOnly));
+        assert_eq!(view.mkdir("/x"), Err(FsError::ReadOnly));
+        assert_eq!(view.unlink("/etc/config"), Err(FsError::ReadOnly));
+        assert_eq!(view.rename("/etc", "/x"), Err(FsError::ReadOnly));
+        assert_eq!(view.chmod("/etc", 0o700), Err(FsError::ReadOnly));
+        assert_eq!(view.snapshot("c"), Err(FsError::ReadOnly));
+        assert_eq!(view.delete_snapshot("a"), Err(FsError::ReadOnly));
+        let mut file = view.open("/etc/config", OpenFlags::NONE).unwrap();
+        assert_eq!(file.write_bytes(b"x", 0), Err(FsError::ReadOnly));
+        assert_eq!(file.set_contents(b""), Err(FsError::ReadOnly));
+        assert_eq!(
+            view.open("/etc/config", OpenFlags::TRUNCATE).err(),
+            Some(FsError::ReadOnly)
+        );
+        view.unmount();
+    }
+
+    // the live fs didnt notice
+    assert_eq!(read_file(&mut fs, "/etc/config"), b"new");
+    assert!(fs.stat("/etc/added").is_ok());
+    assert_eq!(fs.superblock().n_sectors_used(), used);
+    let mut fs = NeFS::mount(fs.unmount()).unwrap();
+    assert_eq!(read_file(&mut fs, "/etc/config"), b"new");
+}
+
+#[test]
+fn test_snapshot_after_access_time() {
+    use super::{mkfs_with, read_file, MkfsOptions, OpenFlags, Timestamp, PAGE_SIZE};
+    use crate::driver::block::RamDisk;
+
+    // with and without a journal, a read that only moved an access time is still committed before the snapshot
+    for journal_clusters in [0, 16] {
+        let mut disk = RamDisk::new(128);
+        mkfs_with(&mut disk, 128, "rootfs", MkfsOptions { journal_clusters }).unwrap();
+        let mut fs = NeFS::mount(disk).unwrap();
+        fs.create("/f").unwrap();
+        fs.open("/f", OpenFlags::NONE)
+            .unwrap()
+            .set_contents(b"kept")
+            .unwrap();
+        fs.commit().unwrap();
+        fs.set_clock(|| Timestamp::new(10, 0));
+        assert_eq!(read_file(&mut fs, "/f"), b"kept");
+        fs.snapshot("a").unwrap();
+
+        // nothing the snapshot has can be handed out again
+        fs.create("/filler").unwrap();
+        let mut offset = 0;
+        for _ in 0..2 {
+            while fs
+                .open("/filler", OpenFlags::NONE)
+                .unwrap()
+                .write_bytes(&[1; PAGE_SIZE as usize], offset)
+                .is_ok()
+            {
+                offset += PAGE_SIZE;
+            }
+            fs.commit().unwrap();
+        }
+
+        let mut view = fs.snapshot_view("a").unwrap();
+        assert_eq!(view.stat("/f").unwrap().record.size, 4);
+        assert_eq!(read_file(&mut view, "/f"), b"kept");
+    }
+}
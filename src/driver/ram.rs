@@ -5,7 +5,30 @@
 // On NeFS, we dont care about file extensions
 // I also dont see why we dont just store the file's data on the heap / shared memory and CoW
 
-use alloc::{borrow::Cow, string::String, vec::Vec};
+// Every file lives in one arena (RootFS::nodes) and files point at each other by index into it. A directory maps names
+// to indices and every file keeps the index of its directory, so nothing in the tree is a pointer into the tree
+// Paths work like on NeFS: absolute, `.` is the directory itself, `..` its parent, and the root is its own parent
+
+use super::{
+    block::BlockDriver,
+    neutronfs::{
+        dir::validate_name,
+        path::{components, MAX_SYMLINK_DEPTH},
+        FileType, FlushPolicy, FsError, InodeNumber, ItemType, NeFS, ROOT_INODE,
+    },
+};
+use alloc::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec::Vec,
+};
+
+/// Where a file is in the RootFS arena
+pub type FileIndex = usize;
+
+/// The root directory is always the first file
+pub const ROOT_INDEX: FileIndex = 0;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -21,36 +44,85 @@ pub enum FileEncoding {
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct FileData {
-    name: String,
     // high level description of the file type, e.g. PNG
     file_type: String,
     encoding: FileEncoding,
     data: Vec<u8>,
 }
 
-/// Always contains . and .. links to dirs
+impl FileData {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            file_type: String::new(),
+            encoding: FileEncoding::Raw,
+            data,
+        }
+    }
+
+    pub fn file_type(&self) -> &str {
+        &self.file_type
+    }
+
+    pub fn encoding(&self) -> FileEncoding {
+        self.encoding
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+}
+
+/// Always contains . and .. links to dirs. They arent stored, they are the directory itself and its RamNode's parent
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct DirData {
-    name: String,
-    files: Vec<NeFSFile>,
-    parent: *mut DirData,
+    files: BTreeMap<String, FileIndex>,
 }
 
 impl DirData {
-    pub fn new_dir(&mut self, name: String, files: Vec<NeFSFile>, parent: *mut DirData) -> Self {
-        Self {
-            name,
-            files,
-            parent,
-        }
+    pub fn new_dir() -> Self {
+        Self::default()
+    }
+
+    /// Entries besides `.` and `..`
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<FileIndex> {
+        self.files.get(name).copied()
+    }
+
+    /// Entries besides `.` and `..`, by name
+    pub fn files(&self) -> impl Iterator<Item = (&str, FileIndex)> {
+        self.files
+            .iter()
+            .map(|(name, index)| (name.as_str(), *index))
     }
 
     // add a file
-    pub fn add_file(&mut self) {}
+    pub fn add_file(&mut self, name: &str, index: FileIndex) -> Result<(), FsError> {
+        validate_name(name)?;
+        if self.files.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        self.files.insert(String::from(name), index);
+        Ok(())
+    }
 
     // remove a file
-    pub fn remove_file(&mut self) {}
+    pub fn remove_file(&mut self, name: &str) -> Result<FileIndex, FsError> {
+        self.files.remove(name).ok_or(FsError::NotFound)
+    }
 }
 
 #[repr(C)]
@@ -60,19 +132,867 @@ pub enum NeFSFile {
     Dir(DirData),
     /// No separation between char/block/socket?
     Device,
-    Symlink,
+    /// Holds the target
+    Symlink(String),
     Socket,
     /// Named pipe. Anonymous pipes are not files
     Pipe,
 }
 
-impl NeFSFile {
-    pub fn rename(&mut self) {}
+pub struct NeFSFileCoW<'file>(Cow<'file, NeFSFile>);
+
+/// A file in the arena, the directory it is in and the name it has there
+#[derive(Debug, Clone)]
+pub struct RamNode {
+    name: String,
+    parent: FileIndex,
+    file: NeFSFile,
+    // the NeFS inode it was loaded from or last flushed to, None until then
+    inode: Option<InodeNumber>,
+    // changed since it was loaded or flushed. For a directory that means its entries
+    dirty: bool,
 }
 
-pub struct NeFSFileCoW<'file>(Cow<'file, NeFSFile>);
+impl RamNode {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The directory the file is in. The root is in itself
+    pub fn parent(&self) -> FileIndex {
+        self.parent
+    }
+
+    pub fn file(&self) -> &NeFSFile {
+        &self.file
+    }
+
+    /// The NeFS inode it goes to, if it has been loaded or flushed
+    pub fn inode(&self) -> Option<InodeNumber> {
+        self.inode
+    }
+
+    /// Has changes the next flush_to writes
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+}
+
+/// A whole filesystem in memory. Works as a tmpfs by itself
+#[derive(Debug, Clone)]
+pub struct RootFS {
+    // None where a file was deleted, the slot goes to the next one created
+    nodes: Vec<Option<RamNode>>,
+    free: Vec<FileIndex>,
+}
+
+impl Default for RootFS {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RootFS {
+    /// Just an empty root directory
+    pub fn new() -> Self {
+        let root = RamNode {
+            name: String::new(),
+            parent: ROOT_INDEX,
+            file: NeFSFile::Dir(DirData::new_dir()),
+            inode: Some(ROOT_INODE),
+            dirty: false,
+        };
+
+        Self {
+            nodes: Vec::from([Some(root)]),
+            free: Vec::new(),
+        }
+    }
+
+    /// Number of files, the root included
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.free.len()
+    }
+
+    /// Never, the root is always there
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    pub fn get(&self, index: FileIndex) -> Option<&RamNode> {
+        self.nodes.get(index)?.as_ref()
+    }
+
+    fn node(&self, index: FileIndex) -> Result<&RamNode, FsError> {
+        self.get(index).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut(&mut self, index: FileIndex) -> Result<&mut RamNode, FsError> {
+        self.nodes
+            .get_mut(index)
+            .and_then(|n| n.as_mut())
+            .ok_or(FsError::NotFound)
+    }
+
+    pub fn dir(&self, index: FileIndex) -> Result<&DirData, FsError> {
+        match &self.node(index)?.file {
+            NeFSFile::Dir(dir) => Ok(dir),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn dir_mut(&mut self, index: FileIndex) -> Result<&mut DirData, FsError> {
+        match &mut self.node_mut(index)?.file {
+            NeFSFile::Dir(dir) => Ok(dir),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    pub fn file(&self, index: FileIndex) -> Result<&FileData, FsError> {
+        match &self.node(index)?.file {
+            NeFSFile::File(file) => Ok(file),
+            NeFSFile::Dir(_) => Err(FsError::IsADirectory),
+            NeFSFile::Symlink(_) => Err(FsError::IsASymlink),
+            _ => Err(FsError::InvalidPath),
+        }
+    }
+
+    /// The file's data to change. Marks it dirty
+    pub fn file_mut(&mut self, index: FileIndex) -> Result<&mut FileData, FsError> {
+        let node = self.node_mut(index)?;
+        if let NeFSFile::File(_) = node.file {
+            node.dirty = true;
+        }
+
+        match &mut node.file {
+            NeFSFile::File(file) => Ok(file),
+            NeFSFile::Dir(_) => Err(FsError::IsADirectory),
+            NeFSFile::Symlink(_) => Err(FsError::IsASymlink),
+            _ => Err(FsError::InvalidPath),
+        }
+    }
+
+    /// The entry `name` of directory `dir`, `.` and `..` included
+    fn child(&self, dir: FileIndex, name: &str) -> Result<FileIndex, FsError> {
+        let dir_data = self.dir(dir)?;
+        match name {
+            "." => Ok(dir),
+            ".." => Ok(self.node(dir)?.parent),
+            _ => dir_data.lookup(name).ok_or(FsError::NotFound),
+        }
+    }
+
+    /// Walk `parts` starting at `dir`. The last part is only followed if it is a symlink and `follow` is set
+    fn walk(
+        &self,
+        dir: FileIndex,
+        parts: &[&str],
+        follow: bool,
+        depth: &mut usize,
+    ) -> Result<FileIndex, FsError> {
+        let mut curr = dir;
+
+        for (i, part) in parts.iter().enumerate() {
+            let parent = curr;
+            curr = self.child(curr, part)?;
+
+            let is_last = i + 1 == parts.len();
+            if is_last && !follow {
+                break;
+            }
+
+            if let NeFSFile::Symlink(target) = &self.node(curr)?.file {
+                *depth += 1;
+                if *depth > MAX_SYMLINK_DEPTH {
+                    return Err(FsError::TooManySymlinks);
+                }
+
+                let (start, target_parts) = if target.starts_with('/') {
+                    (ROOT_INDEX, components(target)?)
+                } else {
+                    (
+                        parent,
+                        target
+                            .split('/')
+                            .filter(|c| !c.is_empty() && *c != ".")
+                            .collect(),
+                    )
+                };
+                curr = self.walk(start, &target_parts, true, depth)?;
+            }
+        }
+
+        Ok(curr)
+    }
+
+    /// File at `path`. Follows a symlink at the end if `follow`
+    pub fn lookup(&self, path: &str, follow: bool) -> Result<FileIndex, FsError> {
+        let parts = components(path)?;
+        self.walk(ROOT_INDEX, &parts, follow, &mut 0)
+    }
+
+    /// Directory `path` goes in and its last part, which has to be a plain name
+    fn lookup_parent<'p>(&self, path: &'p str) -> Result<(FileIndex, &'p str), FsError> {
+        let parts = components(path)?;
+        let (name, dir_parts) = parts.split_last().ok_or(FsError::InvalidPath)?;
+        validate_name(name)?;
+
+        let dir = self.walk(ROOT_INDEX, dir_parts, true, &mut 0)?;
+        self.dir(dir)?;
+
+        Ok((dir, name))
+    }
+
+    /// The absolute path of a file, following `..` up to the root
+    pub fn path_of(&self, index: FileIndex) -> Result<String, FsError> {
+        let mut names = Vec::new();
+        let mut curr = index;
+
+        while curr != ROOT_INDEX {
+            let node = self.node(curr)?;
+            names.push(node.name.as_str());
+            curr = node.parent;
+        }
+
+        let mut path = String::new();
+        for name in names.iter().rev() {
+            path.push('/');
+            path.push_str(name);
+        }
+        if path.is_empty() {
+            path.push('/');
+        }
+
+        Ok(path)
+    }
+
+    /// A directory's entries changed
+    fn mark_dirty(&mut self, index: FileIndex) -> Result<(), FsError> {
+        self.node_mut(index)?.dirty = true;
+        Ok(())
+    }
+
+    /// Put `file` at `path`
+    pub fn insert(&mut self, path: &str, file: NeFSFile) -> Result<FileIndex, FsError> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let index = self.add_node(parent, name, file, None)?;
+        self.mark_dirty(parent)?;
+
+        Ok(index)
+    }
+
+    /// Put `file` in directory `parent` as `name`. Dirty unless it came from `inode`
+    fn add_node(
+        &mut self,
+        parent: FileIndex,
+        name: &str,
+        file: NeFSFile,
+        inode: Option<InodeNumber>,
+    ) -> Result<FileIndex, FsError> {
+        if self.dir(parent)?.lookup(name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
 
-pub struct RootFS {}
+        let node = RamNode {
+            name: String::from(name),
+            parent,
+            file,
+            inode,
+            dirty: inode.is_none(),
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = Some(node);
+                index
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+
+        self.dir_mut(parent)?.add_file(name, index)?;
+        Ok(index)
+    }
+
+    /// Make an empty file
+    pub fn create(&mut self, path: &str) -> Result<FileIndex, FsError> {
+        self.insert(path, NeFSFile::File(FileData::new(Vec::new())))
+    }
+
+    /// Make an empty directory
+    pub fn mkdir(&mut self, path: &str) -> Result<FileIndex, FsError> {
+        self.insert(path, NeFSFile::Dir(DirData::new_dir()))
+    }
+
+    /// Make a symlink at `path` pointing at `target`. The target doesnt have to exist
+    pub fn symlink(&mut self, target: &str, path: &str) -> Result<FileIndex, FsError> {
+        if target.is_empty() {
+            return Err(FsError::InvalidPath);
+        }
+
+        self.insert(path, NeFSFile::Symlink(String::from(target)))
+    }
+
+    /// Every entry of the directory at `path`, `.` and `..` first
+    pub fn list(&self, path: &str) -> Result<Vec<String>, FsError> {
+        let dir = self.dir(self.lookup(path, true)?)?;
+
+        let mut names = Vec::from([String::from("."), String::from("..")]);
+        names.extend(dir.files().map(|(name, _)| String::from(name)));
+
+        Ok(names)
+    }
+
+    /// Give a file's slot back, and the slots of everything under it
+    fn drop_file(&mut self, index: FileIndex) {
+        if let Some(Some(RamNode {
+            file: NeFSFile::Dir(dir),
+            ..
+        })) = self.nodes.get(index)
+        {
+            let files: Vec<FileIndex> = dir.files().map(|(_, f)| f).collect();
+            for file in files {
+                self.drop_file(file);
+            }
+        }
+
+        self.nodes[index] = None;
+        self.free.push(index);
+    }
+
+    /// Remove a file, symlink or anything else that isnt a directory
+    pub fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let index = self.child(parent, name)?;
+        if let NeFSFile::Dir(_) = self.node(index)?.file {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.dir_mut(parent)?.remove_file(name)?;
+        self.drop_file(index);
+        self.mark_dirty(parent)
+    }
+
+    /// Remove an empty directory
+    pub fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let index = self.child(parent, name)?;
+        if !self.dir(index)?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+
+        self.dir_mut(parent)?.remove_file(name)?;
+        self.drop_file(index);
+        self.mark_dirty(parent)
+    }
+
+    /// Move `from` to `to`, replacing what is at `to` if its the same kind. A directory only replaces an empty one
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let (from_parent, from_name) = self.lookup_parent(from)?;
+        let (to_parent, to_name) = self.lookup_parent(to)?;
+        let index = self.child(from_parent, from_name)?;
+        let is_dir = matches!(self.node(index)?.file, NeFSFile::Dir(_));
+
+        if from_parent == to_parent && from_name == to_name {
+            return Ok(());
+        }
+
+        // a directory cant go under itself
+        if is_dir {
+            let mut curr = to_parent;
+            loop {
+                if curr == index {
+                    return Err(FsError::InvalidPath);
+                }
+                if curr == ROOT_INDEX {
+                    break;
+                }
+                curr = self.node(curr)?.parent;
+            }
+        }
+
+        if let Some(existing) = self.dir(to_parent)?.lookup(to_name) {
+            match (is_dir, &self.node(existing)?.file) {
+                (true, NeFSFile::Dir(dir)) => {
+                    if !dir.is_empty() {
+                        return Err(FsError::NotEmpty);
+                    }
+                }
+                (true, _) => return Err(FsError::NotADirectory),
+                (false, NeFSFile::Dir(_)) => return Err(FsError::IsADirectory),
+                (false, _) => {}
+            }
+            self.dir_mut(to_parent)?.remove_file(to_name)?;
+            self.drop_file(existing);
+        }
+
+        self.dir_mut(from_parent)?.remove_file(from_name)?;
+        self.dir_mut(to_parent)?.add_file(to_name, index)?;
+        let node = self.node_mut(index)?;
+        node.name = String::from(to_name);
+        node.parent = to_parent;
+        self.mark_dirty(from_parent)?;
+        self.mark_dirty(to_parent)
+    }
+}
+
+// -------------
+// SYNC WITH NEFS
+// -------------
+
+// Every RamNode remembers the NeFS inode it goes to. A flush only visits dirty nodes: a dirty file gets its contents
+// rewritten and a dirty directory gets its entries diffed against the ones on disk, by name and inode number
+// 1. entries on disk that arent in RAM anymore, or name something else now, are taken out
+// 2. entries in RAM that arent on disk are put in, creating inodes for new files. A moved file keeps its inode
+// 3. whatever was taken out and isnt anywhere in RAM anymore is removed, directories with everything under them
+// NeFS has no hard links in RAM, a file linked twice loads as two files that share an inode on disk
+
+impl RootFS {
+    /// Read all of `fs` into memory. Nothing starts out dirty
+    /// Takes `&mut`, reading a NeFS can update access times
+    pub fn load_from<D: BlockDriver>(fs: &mut NeFS<D>) -> Result<Self, FsError> {
+        let mut ram = Self::new();
+        let mut dirs = Vec::from([(ROOT_INODE, ROOT_INDEX)]);
+
+        while let Some((number, index)) = dirs.pop() {
+            let entries = fs.dir(number)?.entries().collect::<Result<Vec<_>, _>>()?;
+
+            for entry in entries {
+                if entry.name() == "." || entry.name() == ".." {
+                    continue;
+                }
+
+                let inode = entry.inode();
+                let file = match fs.read_item(inode)? {
+                    ItemType::Directory(_) => NeFSFile::Dir(DirData::new_dir()),
+                    ItemType::Symlink(target) => NeFSFile::Symlink(target),
+                    ItemType::Payload(_) => match fs.record(inode)?.file_type {
+                        FileType::Device => NeFSFile::Device,
+                        FileType::Socket => NeFSFile::Socket,
+                        FileType::Pipe => NeFSFile::Pipe,
+                        _ => {
+                            let mut file = fs.inode(inode)?;
+                            let mut data = alloc::vec![0; file.size() as usize];
+                            file.read_bytes(&mut data, 0)?;
+                            NeFSFile::File(FileData::new(data))
+                        }
+                    },
+                };
+
+                let is_dir = matches!(file, NeFSFile::Dir(_));
+                let child = ram.add_node(index, entry.name(), file, Some(inode))?;
+                if is_dir {
+                    dirs.push((inode, child));
+                }
+            }
+        }
+
+        Ok(ram)
+    }
+
+    /// Anything changed since the last load or flush
+    pub fn is_dirty(&self) -> bool {
+        self.nodes.iter().flatten().any(|n| n.dirty)
+    }
+
+    /// Write every dirty file and directory to `fs`, which should be the one this was loaded from, then commit.
+    /// On an error nothing is committed, whatever the flush policy of `fs`, and everything stays dirty. What did get
+    /// written is still in `fs` though, its next commit takes it, so flush again once the error is dealt with
+    pub fn flush_to<D: BlockDriver>(&mut self, fs: &mut NeFS<D>) -> Result<(), FsError> {
+        // a policy commit halfway through would make half a flush stick
+        let policy = fs.flush_policy();
+        fs.set_flush_policy(FlushPolicy::MANUAL);
+        let result = self.write_dirty(fs).and_then(|_| fs.commit());
+        fs.set_flush_policy(policy);
+        result?;
+
+        for node in self.nodes.iter_mut().flatten() {
+            node.dirty = false;
+        }
+
+        Ok(())
+    }
+
+    /// The changes flush_to commits
+    fn write_dirty<D: BlockDriver>(&mut self, fs: &mut NeFS<D>) -> Result<(), FsError> {
+        // parents before children, so a new directory exists before anything goes in it
+        let mut dirs = Vec::new();
+        let mut queue = Vec::from([ROOT_INDEX]);
+        while let Some(index) = queue.pop() {
+            dirs.push(index);
+            queue.extend(
+                self.dir(index)?
+                    .files()
+                    .map(|(_, f)| f)
+                    .filter(|f| self.dir(*f).is_ok()),
+            );
+        }
+        let in_ram: BTreeSet<InodeNumber> = self
+            .nodes
+            .iter()
+            .flatten()
+            .filter_map(|n| n.inode)
+            .collect();
 
-// NOTE: VFS = NeFS in memory
-// skiplists and such? Maybe that specific skip-b-list
+        // 1. take out what changed
+        let mut detached = Vec::new();
+        for index in &dirs {
+            let node = self.node(*index)?;
+            let Some(number) = node.inode.filter(|_| node.dirty) else {
+                continue;
+            };
+
+            let entries = fs.dir(number)?.entries().collect::<Result<Vec<_>, _>>()?;
+            for entry in entries {
+                if entry.name() == "." || entry.name() == ".." {
+                    continue;
+                }
+
+                let in_ram = self.dir(*index)?.lookup(entry.name());
+                if in_ram.and_then(|f| self.nodes[f].as_ref()?.inode) != Some(entry.inode()) {
+                    fs.dir(number)?.remove(entry.name())?;
+                    detached.push(entry.inode());
+                }
+            }
+        }
+
+        // 2. put in what is new
+        let mut moved = BTreeSet::new();
+        for index in &dirs {
+            if !self.node(*index)?.dirty {
+                continue;
+            }
+            let number = self.inode_of(fs, *index, ROOT_INODE)?;
+
+            let files: Vec<(String, FileIndex)> = self
+                .dir(*index)?
+                .files()
+                .map(|(name, f)| (String::from(name), f))
+                .collect();
+            let mut n_subdirs = 0;
+            for (name, file) in files {
+                let is_dir = matches!(self.node(file)?.file, NeFSFile::Dir(_));
+                if is_dir {
+                    n_subdirs += 1;
+                }
+
+                let existing = self.node(file)?.inode;
+                let child = self.inode_of(fs, file, number)?;
+                match fs.dir(number)?.lookup(&name) {
+                    Ok(on_disk) if on_disk == child => continue,
+                    Ok(_) => return Err(FsError::AlreadyExists),
+                    Err(FsError::NotFound) => {}
+                    Err(e) => return Err(e),
+                }
+
+                fs.dir(number)?.insert(&name, child)?;
+                if existing.is_some() {
+                    moved.insert(child);
+                    if is_dir {
+                        fs.dir(child)?.set_parent(number)?;
+                    }
+                }
+            }
+
+            if fs.record(number)?.n_links != 2 + n_subdirs {
+                fs.update_record(number, |r| r.n_links = 2 + n_subdirs)?;
+            }
+        }
+
+        for node in self.nodes.iter().flatten() {
+            if let (true, Some(number), NeFSFile::File(file)) = (node.dirty, node.inode, &node.file)
+            {
+                fs.inode(number)?.set_contents(&file.data)?;
+            }
+        }
+
+        // 3. drop what is gone
+        for number in detached {
+            remove_detached(fs, number, &in_ram, &moved)?;
+        }
+
+        Ok(())
+    }
+
+    /// The inode a file goes to, making it in directory `parent` if it doesnt have one yet
+    fn inode_of<D: BlockDriver>(
+        &mut self,
+        fs: &mut NeFS<D>,
+        index: FileIndex,
+        parent: InodeNumber,
+    ) -> Result<InodeNumber, FsError> {
+        let node = self.node(index)?;
+        if let Some(number) = node.inode {
+            return Ok(number);
+        }
+
+        let special = |fs: &mut NeFS<D>, file_type| -> Result<InodeNumber, FsError> {
+            let number = fs.create_inode()?;
+            fs.update_record(number, |r| {
+                r.file_type = file_type;
+                r.mode = file_type.default_mode();
+            })?;
+            Ok(number)
+        };
+        let number = match &node.file {
+            NeFSFile::File(_) => fs.create_inode()?,
+            NeFSFile::Dir(_) => fs.create_dir(parent)?,
+            NeFSFile::Symlink(target) => fs.create_symlink(target)?,
+            NeFSFile::Device => special(fs, FileType::Device)?,
+            NeFSFile::Socket => special(fs, FileType::Socket)?,
+            NeFSFile::Pipe => special(fs, FileType::Pipe)?,
+        };
+        self.node_mut(index)?.inode = Some(number);
+
+        Ok(number)
+    }
+}
+
+/// Let go of an inode taken out of its directory. It stays if it was moved, loses a link if RAM still has it under
+/// another name, and is removed otherwise, along with everything under it
+fn remove_detached<D: BlockDriver>(
+    fs: &mut NeFS<D>,
+    number: InodeNumber,
+    in_ram: &BTreeSet<InodeNumber>,
+    moved: &BTreeSet<InodeNumber>,
+) -> Result<(), FsError> {
+    if moved.contains(&number) {
+        return Ok(());
+    }
+    if in_ram.contains(&number) {
+        fs.update_record(number, |r| r.n_links = r.n_links.saturating_sub(1))?;
+        return Ok(());
+    }
+
+    if let ItemType::Directory(_) = fs.read_item(number)? {
+        let entries = fs.dir(number)?.entries().collect::<Result<Vec<_>, _>>()?;
+        for entry in entries {
+            if entry.name() != "." && entry.name() != ".." {
+                remove_detached(fs, entry.inode(), in_ram, moved)?;
+            }
+        }
+    }
+
+    fs.remove_inode(number)
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_rootfs_create_lookup() {
+    let mut fs = RootFS::new();
+    let etc = fs.mkdir("/etc").unwrap();
+    let config = fs.create("/etc/config").unwrap();
+    fs.file_mut(config)
+        .unwrap()
+        .data_mut()
+        .extend_from_slice(b"hi");
+
+    assert_eq!(fs.lookup("/", true), Ok(ROOT_INDEX));
+    assert_eq!(fs.lookup("/etc/config", true), Ok(config));
+    assert_eq!(fs.lookup("/etc/./../etc/config", true), Ok(config));
+    // the root is its own parent
+    assert_eq!(fs.lookup("/../..", true), Ok(ROOT_INDEX));
+    assert_eq!(fs.lookup("/etc/nope", true), Err(FsError::NotFound));
+    assert_eq!(fs.lookup("etc", true), Err(FsError::InvalidPath));
+    assert_eq!(
+        fs.lookup("/etc/config/x", true),
+        Err(FsError::NotADirectory)
+    );
+    assert_eq!(fs.create("/etc/config"), Err(FsError::AlreadyExists));
+    assert_eq!(fs.create("/etc/.."), Err(FsError::InvalidName));
+
+    assert_eq!(fs.file(config).unwrap().data(), b"hi");
+    assert_eq!(fs.list("/etc").unwrap(), [".", "..", "config"]);
+    assert_eq!(fs.get(etc).unwrap().parent(), ROOT_INDEX);
+    assert_eq!(fs.path_of(config).unwrap(), "/etc/config");
+    assert_eq!(fs.path_of(ROOT_INDEX).unwrap(), "/");
+
+    // symlinks, relative ones from the directory they are in
+    fs.symlink("config", "/etc/rel").unwrap();
+    fs.symlink("/etc", "/abs").unwrap();
+    assert_eq!(fs.lookup("/etc/rel", true), Ok(config));
+    assert_eq!(fs.lookup("/abs/rel", true), Ok(config));
+    assert_ne!(fs.lookup("/abs", false), Ok(etc));
+    fs.symlink("/loop", "/loop").unwrap();
+    assert_eq!(fs.lookup("/loop", true), Err(FsError::TooManySymlinks));
+}
+
+#[test]
+fn test_rootfs_rename_delete() {
+    let mut fs = RootFS::new();
+    fs.mkdir("/a").unwrap();
+    fs.mkdir("/a/b").unwrap();
+    let file = fs.create("/a/b/file").unwrap();
+    fs.mkdir("/c").unwrap();
+
+    // move a directory, it takes its files along and its .. follows
+    fs.rename("/a/b", "/c/b").unwrap();
+    assert_eq!(fs.lookup("/c/b/file", true), Ok(file));
+    assert_eq!(fs.lookup("/c/b/..", true), fs.lookup("/c", true));
+    assert_eq!(fs.path_of(file).unwrap(), "/c/b/file");
+    assert_eq!(fs.rename("/c", "/c/b/c"), Err(FsError::InvalidPath));
+    fs.create("/f").unwrap();
+    assert_eq!(fs.rename("/c/b", "/f"), Err(FsError::NotADirectory));
+    assert_eq!(fs.rename("/c/b/file", "/a"), Err(FsError::IsADirectory));
+    assert_eq!(fs.rename("/a", "/c"), Err(FsError::NotEmpty));
+
+    // a file replaces a file
+    fs.create("/other").unwrap();
+    fs.rename("/other", "/c/b/file").unwrap();
+    assert_ne!(fs.lookup("/c/b/file", true), Ok(file));
+    assert!(fs.get(file).is_none());
+
+    assert_eq!(fs.rmdir("/c"), Err(FsError::NotEmpty));
+    assert_eq!(fs.unlink("/c"), Err(FsError::IsADirectory));
+    fs.unlink("/c/b/file").unwrap();
+    fs.unlink("/f").unwrap();
+    fs.rmdir("/c/b").unwrap();
+    fs.rmdir("/c").unwrap();
+    fs.rmdir("/a").unwrap();
+    assert_eq!(fs.list("/").unwrap(), [".", ".."]);
+    assert_eq!(fs.len(), 1);
+
+    // slots get reused
+    let again = fs.create("/again").unwrap();
+    assert!(again < 6);
+}
+
+#[test]
+fn test_rootfs_load_flush() {
+    use super::{
+        block::RamDisk,
+        neutronfs::{mkfs, OpenFlags, Timestamp},
+    };
+
+    let mut disk = RamDisk::new(256);
+    mkfs(&mut disk, 256, "rootfs").unwrap();
+    let mut fs = NeFS::mount(disk).unwrap();
+    fs.set_clock(|| Timestamp::new(1_000, 0));
+    fs.mkdir("/etc").unwrap();
+    for (path, contents) in [("/etc/hosts", &b"localhost"[..]), ("/etc/old", b"old")] {
+        fs.create(path).unwrap();
+        fs.open(path, OpenFlags::NONE)
+            .unwrap()
+            .set_contents(contents)
+            .unwrap();
+    }
+    fs.mkdir("/home").unwrap();
+    fs.mkdir("/home/user").unwrap();
+    fs.mkdir("/tmp").unwrap();
+    fs.create("/tmp/keep").unwrap();
+    fs.symlink("/etc/hosts", "/hosts").unwrap();
+    fs.commit().unwrap();
+
+    let mut ram = RootFS::load_from(&mut fs).unwrap();
+    assert!(!ram.is_dirty());
+    let hosts = ram.lookup("/hosts", true).unwrap();
+    assert_eq!(ram.file(hosts).unwrap().data(), b"localhost");
+    assert_eq!(ram.list("/etc").unwrap(), [".", "..", "hosts", "old"]);
+
+    // a batch of changes, none of it on disk until the flush
+    let notes = ram.create("/home/user/notes").unwrap();
+    ram.file_mut(notes)
+        .unwrap()
+        .data_mut()
+        .extend_from_slice(b"notes");
+    ram.rename("/home/user", "/home/admin").unwrap();
+    ram.unlink("/etc/old").unwrap();
+    ram.rename("/tmp/keep", "/keep").unwrap();
+    ram.rmdir("/tmp").unwrap();
+    ram.mkdir("/var").unwrap();
+    ram.symlink("/var", "/v").unwrap();
+    assert!(ram.get(hosts).is_some_and(|n| !n.is_dirty()));
+    assert!(fs.resolve("/home/user", true).is_ok());
+
+    fs.set_clock(|| Timestamp::new(2_000, 0));
+    ram.flush_to(&mut fs).unwrap();
+    assert!(!ram.is_dirty());
+
+    let mut fs = NeFS::mount(fs.unmount()).unwrap();
+    let read = |fs: &mut NeFS<RamDisk>, path| {
+        let mut inode = fs.open(path, OpenFlags::NONE).unwrap();
+        let mut buf = alloc::vec![0; inode.size() as usize];
+        inode.read_bytes(&mut buf, 0).unwrap();
+        buf
+    };
+    assert_eq!(read(&mut fs, "/home/admin/notes"), b"notes");
+    assert_eq!(fs.resolve("/home/user", true), Err(FsError::NotFound));
+    assert_eq!(fs.resolve("/etc/old", true), Err(FsError::NotFound));
+    assert_eq!(fs.resolve("/tmp", true), Err(FsError::NotFound));
+    assert_eq!(
+        fs.resolve("/keep", true),
+        ram.get(ram.lookup("/keep", true).unwrap())
+            .unwrap()
+            .inode()
+            .ok_or(FsError::NotFound)
+    );
+    assert_eq!(fs.readlink("/v").unwrap(), "/var");
+    assert_eq!(fs.stat("/").unwrap().record.n_links, 5);
+    assert_eq!(
+        fs.stat("/home/admin/..").unwrap().inode,
+        fs.resolve("/home", true).unwrap()
+    );
+
+    // only what changed was written
+    assert_eq!(
+        fs.stat("/etc/hosts").unwrap().record.changed,
+        Timestamp::new(1_000, 0)
+    );
+    assert_eq!(
+        fs.stat("/etc").unwrap().record.changed,
+        Timestamp::new(2_000, 0)
+    );
+
+    let again = RootFS::load_from(&mut fs).unwrap();
+    assert_eq!(again.list("/").unwrap(), ram.list("/").unwrap());
+    assert_eq!(again.len(), ram.len());
+}
+
+#[test]
+fn test_rootfs_flush_fails() {
+    use super::{
+        block::RamDisk,
+        neutronfs::{mkfs, OpenFlags, PAGE_SIZE},
+    };
+
+    let mut disk = RamDisk::new(64);
+    mkfs(&mut disk, 64, "rootfs").unwrap();
+    let mut fs = NeFS::mount(disk).unwrap();
+    fs.set_flush_policy(FlushPolicy::IMMEDIATE);
+    let mut ram = RootFS::load_from(&mut fs).unwrap();
+    let generation = fs.superblock().generation();
+
+    // the small files go in before the big one runs out of room, none of it is committed
+    ram.mkdir("/etc").unwrap();
+    let hosts = ram.create("/etc/hosts").unwrap();
+    ram.file_mut(hosts)
+        .unwrap()
+        .data_mut()
+        .extend_from_slice(b"localhost");
+    let big = ram.create("/big").unwrap();
+    ram.file_mut(big)
+        .unwrap()
+        .data_mut()
+        .resize(64 * PAGE_SIZE as usize, 1);
+    assert_eq!(ram.flush_to(&mut fs), Err(FsError::NoSpace));
+    assert_eq!(fs.superblock().generation(), generation);
+    assert_eq!(fs.flush_policy(), FlushPolicy::IMMEDIATE);
+    assert!(ram.is_dirty());
+
+    // once it fits, flushing again finishes it
+    ram.file_mut(big).unwrap().data_mut().truncate(10);
+    ram.flush_to(&mut fs).unwrap();
+    assert!(!ram.is_dirty());
+    let mut fs = NeFS::mount(fs.unmount()).unwrap();
+    let mut buf = [0; 9];
+    fs.open("/etc/hosts", OpenFlags::NONE)
+        .unwrap()
+        .read_bytes(&mut buf, 0)
+        .unwrap();
+    assert_eq!(&buf, b"localhost");
+    assert_eq!(fs.stat("/big").unwrap().record.size, 10);
+}
@@ -0,0 +1,193 @@
+// -----------------
+// IMPORTS
+// -----------------
+
+use log::error;
+use neutron_fs::driver::block::{
+    make_block, Block, BlockDriver, Completion, Completions, IoError, RequestId,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+const CLUSTER_SIZE: u64 = 4096;
+
+// -----------------
+// FILE DRIVER
+// -----------------
+
+/// A raw disk image in a file. Cluster n lives at byte n * 4096
+pub struct FileBlockDriver {
+    file: File,
+    n_clusters: u64,
+    // fsync on flush, otherwise flush only leaves it to the OS
+    fsync: bool,
+    completions: Completions,
+}
+
+impl FileBlockDriver {
+    /// Open an existing image. A trailing partial cluster is ignored
+    pub fn open(path: impl AsRef<Path>, fsync: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let n_clusters = file.metadata()?.len() / CLUSTER_SIZE;
+
+        Ok(Self::new(file, n_clusters, fsync))
+    }
+
+    /// Make a zeroed image of `n_clusters`, replacing whatever was at `path`
+    pub fn create(path: impl AsRef<Path>, n_clusters: u64, fsync: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(n_clusters * CLUSTER_SIZE)?;
+
+        Ok(Self::new(file, n_clusters, fsync))
+    }
+
+    pub fn new(file: File, n_clusters: u64, fsync: bool) -> Self {
+        Self {
+            file,
+            n_clusters,
+            fsync,
+            completions: Completions::new(),
+        }
+    }
+
+    pub fn n_clusters(&self) -> u64 {
+        self.n_clusters
+    }
+
+    fn read_cluster(&mut self, cluster_number: u64) -> io::Result<Block> {
+        let mut block = make_block();
+        self.file
+            .seek(SeekFrom::Start(cluster_number * CLUSTER_SIZE))?;
+        self.file.read_exact(&mut block)?;
+
+        Ok(block)
+    }
+
+    fn write_cluster(&mut self, cluster_number: u64, block: &Block) -> io::Result<()> {
+        self.file
+            .seek(SeekFrom::Start(cluster_number * CLUSTER_SIZE))?;
+        self.file.write_all(block)
+    }
+}
+
+/// Log the io error, the fs only gets to know the device failed
+fn device_error(e: io::Error) -> IoError {
+    error!("disk image: {}", e);
+    IoError::Device
+}
+
+impl BlockDriver for FileBlockDriver {
+    // reads and writes go straight to the file, so every request is done by the time it has an id
+    fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+        if cluster_number >= self.n_clusters {
+            let res = Err(IoError::OutOfRange(cluster_number));
+            return Ok(self.completions.complete_now(Completion::Read(res)));
+        }
+
+        let res = self
+            .read_cluster(cluster_number)
+            .map(Box::new)
+            .map_err(device_error);
+        Ok(self.completions.complete_now(Completion::Read(res)))
+    }
+
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<RequestId, IoError> {
+        if cluster_number >= self.n_clusters {
+            let res = Err(IoError::OutOfRange(cluster_number));
+            return Ok(self.completions.complete_now(Completion::Write(res)));
+        }
+
+        let res = self
+            .write_cluster(cluster_number, &block)
+            .map_err(device_error);
+        Ok(self.completions.complete_now(Completion::Write(res)))
+    }
+
+    fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+        self.completions.take(id)
+    }
+
+    fn is_pending(&self, id: RequestId) -> bool {
+        self.completions.is_pending(id)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.file.flush().map_err(device_error)?;
+        if self.fsync {
+            self.file.sync_data().map_err(device_error)?;
+        }
+
+        Ok(())
+    }
+
+    fn device_clusters(&self) -> Option<u64> {
+        Some(self.n_clusters)
+    }
+}
+
+// -----------------
+// TESTS
+// -----------------
+
+#[cfg(test)]
+fn test_image_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("{}-{}.nefs", name, std::process::id()))
+}
+
+#[test]
+fn test_file_block_read_write() {
+    use neutron_fs::driver::block::{read_block, write_block};
+
+    let path = test_image_path("file-block-rw");
+    let mut driver = FileBlockDriver::create(&path, 8, true).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 8 * 4096);
+
+    write_block(&mut driver, 5, [9; 4096]).unwrap();
+    driver.flush().unwrap();
+    assert_eq!(read_block(&mut driver, 5), Ok([9; 4096]));
+    assert_eq!(read_block(&mut driver, 4), Ok(make_block()));
+    assert_eq!(read_block(&mut driver, 8), Err(IoError::OutOfRange(8)));
+    let id = driver.push_write_request(8, [1; 4096]).unwrap();
+    assert_eq!(
+        driver.wait(id),
+        Completion::Write(Err(IoError::OutOfRange(8)))
+    );
+
+    // lands at the right offset
+    let bytes = std::fs::read(&path).unwrap();
+    assert!(bytes[5 * 4096..6 * 4096].iter().all(|b| *b == 9));
+    assert!(bytes[..5 * 4096].iter().all(|b| *b == 0));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_mkfs_file_image_persists() {
+    use neutron_fs::driver::neutronfs::{mkfs, NeFS};
+
+    let path = test_image_path("file-block-mkfs");
+    let mut driver = FileBlockDriver::create(&path, 64, false).unwrap();
+    let superblock = mkfs(&mut driver, 64, "image").unwrap();
+    let driver = NeFS::mount(driver).unwrap().unmount();
+    drop(driver);
+
+    // another run picks the image back up
+    let driver = FileBlockDriver::open(&path, false).unwrap();
+    assert_eq!(driver.n_clusters(), 64);
+    let fs = NeFS::mount(driver).unwrap();
+    assert_eq!(fs.superblock().fs_uuid(), superblock.fs_uuid());
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -18,6 +18,7 @@ use tokio::task::JoinHandle;
 pub mod block_tokio;
 pub mod cli;
 pub mod client_server;
+pub mod file_block;
 pub mod simple_block;
 
 #[tokio::main]
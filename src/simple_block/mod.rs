@@ -1,13 +1,230 @@
-use neutron_fs::driver::block::{Block, BlockDriver};
+use neutron_fs::driver::block::{
+    make_block, read_block, write_block, Block, BlockDriver, Completion, Completions, IoError,
+    RequestId,
+};
+use neutron_fs::driver::neutronfs::generate_uuid;
 
-/// A simple block driver that blocks on requests until done. No multithreading
-pub struct SimpleBlockDriver {
-    clusters: [Block; 1000],
-    curr_gpt_entries: usize,
+// -------------
+// GPT
+// -------------
+
+// LBAs are 4K, same as a cluster
+// LBA 0 is a protective MBR, LBA 1 the GPT header, the entry array from LBA 2
+// The backup header is on the last LBA with its entry array just before it
+
+pub const LBA_SIZE: usize = 4096;
+pub const GPT_HEADER_LBA: u64 = 1;
+pub const GPT_ENTRIES_LBA: u64 = 2;
+pub const N_GPT_ENTRIES: usize = 128;
+pub const GPT_ENTRY_SIZE: usize = 128;
+/// LBAs the entry array takes up
+pub const GPT_ENTRIES_LBAS: u64 = ((N_GPT_ENTRIES * GPT_ENTRY_SIZE) / LBA_SIZE) as u64;
+/// Partitions start at 34 like on a 512 byte sector disk, the same gap is left before the backup
+pub const FIRST_USABLE_LBA: u64 = 34;
+/// Smallest disk a GPT fits: both tables and one usable LBA
+pub const MIN_GPT_LBAS: u64 = FIRST_USABLE_LBA + 1 + (FIRST_USABLE_LBA - GPT_HEADER_LBA);
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_SIZE: u32 = 92;
+
+/// GUIDs as u128, in the order the bytes go on disk. The first 3 groups are little endian
+const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> u128 {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    let bytes = [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6],
+        d[7],
+    ];
+
+    u128::from_le_bytes(bytes)
+}
+
+/// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+pub const EFI_SYSTEM_PARTITION_GUID: u128 = guid(
+    0xC12A7328,
+    0xF81F,
+    0x11D2,
+    [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+);
+
+/// 4E654653-0000-4E75-8074-726F6E465300
+pub const NEFS_PARTITION_GUID: u128 = guid(
+    0x4E654653,
+    0x0000,
+    0x4E75,
+    [0x80, 0x74, 0x72, 0x6F, 0x6E, 0x46, 0x53, 0x00],
+);
+
+const fn make_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = make_crc32_table();
+
+/// CRC32 (IEEE), what GPT checksums with
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc = CRC32_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GptError {
+    Io(IoError),
+    /// No valid header in either place
+    NoTable,
+    /// Header is fine but the entry array doesnt match its checksum
+    BadEntries,
+    TableFull,
+    NoSpace,
+    /// Fewer than MIN_GPT_LBAS
+    DiskTooSmall,
+}
+
+impl From<IoError> for GptError {
+    fn from(e: IoError) -> Self {
+        GptError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GPTHeader {
+    pub my_lba: u64,
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: u128,
+    pub entries_lba: u64,
+    pub n_entries: u32,
+    pub entry_size: u32,
+    pub entries_crc32: u32,
+}
+
+impl GPTHeader {
+    /// Primary and backup headers for a disk of `n_lbas`
+    pub fn new_pair(
+        n_lbas: u64,
+        disk_guid: u128,
+        entries_crc32: u32,
+    ) -> Result<(Self, Self), GptError> {
+        if n_lbas < MIN_GPT_LBAS {
+            return Err(GptError::DiskTooSmall);
+        }
+
+        let last_lba = n_lbas - 1;
+        let primary = Self {
+            my_lba: GPT_HEADER_LBA,
+            alternate_lba: last_lba,
+            first_usable_lba: FIRST_USABLE_LBA,
+            last_usable_lba: last_lba - (FIRST_USABLE_LBA - GPT_HEADER_LBA),
+            disk_guid,
+            entries_lba: GPT_ENTRIES_LBA,
+            n_entries: N_GPT_ENTRIES as u32,
+            entry_size: GPT_ENTRY_SIZE as u32,
+            entries_crc32,
+        };
+        let backup = Self {
+            my_lba: last_lba,
+            alternate_lba: GPT_HEADER_LBA,
+            entries_lba: last_lba - GPT_ENTRIES_LBAS,
+            ..primary
+        };
+
+        Ok((primary, backup))
+    }
+
+    fn encode(&self, header_crc32: u32) -> [u8; GPT_HEADER_SIZE as usize] {
+        let mut bytes = [0; GPT_HEADER_SIZE as usize];
+        bytes[0..8].copy_from_slice(GPT_SIGNATURE);
+        bytes[8..12].copy_from_slice(&GPT_REVISION.to_le_bytes());
+        bytes[12..16].copy_from_slice(&GPT_HEADER_SIZE.to_le_bytes());
+        bytes[16..20].copy_from_slice(&header_crc32.to_le_bytes());
+        // 20..24 reserved
+        bytes[24..32].copy_from_slice(&self.my_lba.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.alternate_lba.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.first_usable_lba.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.last_usable_lba.to_le_bytes());
+        bytes[56..72].copy_from_slice(&self.disk_guid.to_le_bytes());
+        bytes[72..80].copy_from_slice(&self.entries_lba.to_le_bytes());
+        bytes[80..84].copy_from_slice(&self.n_entries.to_le_bytes());
+        bytes[84..88].copy_from_slice(&self.entry_size.to_le_bytes());
+        bytes[88..92].copy_from_slice(&self.entries_crc32.to_le_bytes());
+
+        bytes
+    }
+
+    /// The header's LBA, with its CRC32 computed over the header with the crc field zeroed
+    pub fn to_block(&self) -> Block {
+        let crc = crc32(&self.encode(0));
+        let mut block = make_block();
+        block[..GPT_HEADER_SIZE as usize].copy_from_slice(&self.encode(crc));
+
+        block
+    }
+
+    /// None if the signature, size or CRC32 is off, or the entry array isnt all on a disk of `n_lbas`
+    pub fn from_block(block: &Block, n_lbas: u64) -> Option<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes(block[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(block[i..i + 8].try_into().unwrap());
+
+        if &block[0..8] != GPT_SIGNATURE || u32_at(12) != GPT_HEADER_SIZE {
+            return None;
+        }
+
+        let header = Self {
+            my_lba: u64_at(24),
+            alternate_lba: u64_at(32),
+            first_usable_lba: u64_at(40),
+            last_usable_lba: u64_at(48),
+            disk_guid: u128::from_le_bytes(block[56..72].try_into().unwrap()),
+            entries_lba: u64_at(72),
+            n_entries: u32_at(80),
+            entry_size: u32_at(84),
+            entries_crc32: u32_at(88),
+        };
+        if header.entry_size as usize != GPT_ENTRY_SIZE
+            || header.n_entries as usize > N_GPT_ENTRIES
+            || crc32(&header.encode(0)) != u32_at(16)
+        {
+            return None;
+        }
+
+        let entries_lbas = (header.n_entries as usize * GPT_ENTRY_SIZE).div_ceil(LBA_SIZE) as u64;
+        if header.entries_lba < GPT_ENTRIES_LBA
+            || entries_lbas > n_lbas.saturating_sub(header.entries_lba)
+        {
+            return None;
+        }
+
+        Some(header)
+    }
 }
 
 /// Note: GPT name is in UTF-16
 #[repr(C, packed)]
+#[derive(Clone, Copy)]
 pub struct GPTEntry {
     partition_type: u128,
     guid: u128,
@@ -36,15 +253,214 @@ impl GPTEntry {
         }
     }
 
-    /// MAKE SURE NAME IS EXACTLY 36 CHARACTERS
+    /// Longer than 36 UTF-16 units gets cut off
     pub fn set_name(&mut self, new_name: String) {
-        let name: Vec<u16> = new_name.encode_utf16().collect();
-        self.name.copy_from_slice(&name);
+        // cant borrow fields of a packed struct, copy out and back
+        let mut buf = [0; 36];
+        for (dst, src) in buf.iter_mut().zip(new_name.encode_utf16()) {
+            *dst = src;
+        }
+        self.name = buf;
     }
 
     pub fn get_name(&self) -> String {
-        String::from_utf16(&self.name).unwrap()
+        let name = self.name;
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        String::from_utf16(&name[..len]).unwrap()
+    }
+
+    pub fn partition_type(&self) -> u128 {
+        self.partition_type
+    }
+
+    pub fn guid(&self) -> u128 {
+        self.guid
+    }
+
+    pub fn first_lba(&self) -> u64 {
+        self.first_lba
+    }
+
+    /// Inclusive
+    pub fn last_lba(&self) -> u64 {
+        self.last_lba
     }
+
+    pub fn n_lbas(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+
+    /// Unused slots in the array are all zero
+    pub fn is_unused(&self) -> bool {
+        self.partition_type() == 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; GPT_ENTRY_SIZE] {
+        let mut bytes = [0; GPT_ENTRY_SIZE];
+        bytes[0..16].copy_from_slice(&self.partition_type().to_le_bytes());
+        bytes[16..32].copy_from_slice(&self.guid().to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.first_lba().to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.last_lba().to_le_bytes());
+        let flags = self.flags;
+        bytes[48..56].copy_from_slice(&flags.to_le_bytes());
+        let name = self.name;
+        for (i, c) in name.iter().enumerate() {
+            bytes[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; GPT_ENTRY_SIZE]) -> Self {
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let mut name = [0; 36];
+        for (i, c) in name.iter_mut().enumerate() {
+            *c = u16::from_le_bytes([bytes[56 + i * 2], bytes[57 + i * 2]]);
+        }
+
+        Self::new(
+            u128::from_le_bytes(bytes[0..16].try_into().unwrap()),
+            u128::from_le_bytes(bytes[16..32].try_into().unwrap()),
+            u64_at(32),
+            u64_at(40),
+            u64_at(48),
+            name,
+        )
+    }
+}
+
+impl core::fmt::Debug for GPTEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("GPTEntry")
+            .field("partition_type", &self.partition_type())
+            .field("first_lba", &self.first_lba())
+            .field("last_lba", &self.last_lba())
+            .field("name", &self.get_name())
+            .finish()
+    }
+}
+
+/// The whole entry array, unused slots zeroed
+fn encode_entries(entries: &[GPTEntry]) -> Vec<u8> {
+    let mut bytes = vec![0; N_GPT_ENTRIES * GPT_ENTRY_SIZE];
+    for (chunk, entry) in bytes.chunks_mut(GPT_ENTRY_SIZE).zip(entries) {
+        chunk.copy_from_slice(&entry.to_bytes());
+    }
+
+    bytes
+}
+
+/// MBR with one 0xEE partition covering the disk, so MBR only tools leave it alone
+pub fn write_protective_mbr(driver: &mut impl BlockDriver, n_lbas: u64) -> Result<(), GptError> {
+    if n_lbas < MIN_GPT_LBAS {
+        return Err(GptError::DiskTooSmall);
+    }
+
+    let mut mbr = make_block();
+    let record = &mut mbr[446..462];
+    // status 0, CHS of LBA 1
+    record[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    record[4] = 0xEE;
+    record[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+    record[8..12].copy_from_slice(&1u32.to_le_bytes());
+    let size = core::cmp::min(n_lbas - 1, u32::MAX as u64) as u32;
+    record[12..16].copy_from_slice(&size.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+
+    Ok(write_block(driver, 0, mbr)?)
+}
+
+/// Write both copies of the GPT. `entries` go in order from the start of the array
+pub fn write_gpt(
+    driver: &mut impl BlockDriver,
+    n_lbas: u64,
+    disk_guid: u128,
+    entries: &[GPTEntry],
+) -> Result<(), GptError> {
+    if entries.len() > N_GPT_ENTRIES {
+        return Err(GptError::TableFull);
+    }
+
+    let array = encode_entries(entries);
+    let (primary, backup) = GPTHeader::new_pair(n_lbas, disk_guid, crc32(&array))?;
+
+    // arrays before headers, a header never points at an array that isnt there yet
+    for header in [primary, backup] {
+        for (i, chunk) in array.chunks(LBA_SIZE).enumerate() {
+            let mut block = make_block();
+            block.copy_from_slice(chunk);
+            write_block(driver, header.entries_lba + i as u64, block)?;
+        }
+    }
+    write_block(driver, primary.my_lba, primary.to_block())?;
+    write_block(driver, backup.my_lba, backup.to_block())?;
+
+    Ok(())
+}
+
+fn read_entries(
+    driver: &mut impl BlockDriver,
+    header: &GPTHeader,
+) -> Result<Vec<GPTEntry>, GptError> {
+    let len = header.n_entries as usize * GPT_ENTRY_SIZE;
+    let mut array = Vec::with_capacity(len);
+    let mut lba = header.entries_lba;
+    while array.len() < len {
+        array.extend_from_slice(&read_block(driver, lba)?);
+        lba += 1;
+    }
+    array.truncate(len);
+
+    if crc32(&array) != header.entries_crc32 {
+        return Err(GptError::BadEntries);
+    }
+
+    Ok(array
+        .chunks(GPT_ENTRY_SIZE)
+        .map(|c| GPTEntry::from_bytes(c.try_into().unwrap()))
+        .filter(|e| !e.is_unused())
+        .collect())
+}
+
+/// The header and used entries. Falls back to the backup at the end of the disk if the primary is broken
+/// A header that says it lives somewhere else, e.g. left behind by a disk that was resized, doesnt count
+pub fn read_gpt(
+    driver: &mut impl BlockDriver,
+    n_lbas: u64,
+) -> Result<(GPTHeader, Vec<GPTEntry>), GptError> {
+    if n_lbas < MIN_GPT_LBAS {
+        return Err(GptError::DiskTooSmall);
+    }
+
+    // an entry array that wont read, BadEntries or Io, still leaves the other copy to try
+    let mut err = GptError::NoTable;
+    for lba in [GPT_HEADER_LBA, n_lbas - 1] {
+        let header = match GPTHeader::from_block(&read_block(driver, lba)?, n_lbas) {
+            Some(h) if h.my_lba == lba => h,
+            _ => continue,
+        };
+        match read_entries(driver, &header) {
+            Ok(entries) => return Ok((header, entries)),
+            Err(e) => {
+                err = e;
+                continue;
+            }
+        }
+    }
+
+    Err(err)
+}
+
+// -------------
+// SIMPLE DRIVER
+// -------------
+
+/// A simple block driver that blocks on requests until done. No multithreading
+pub struct SimpleBlockDriver {
+    clusters: [Block; 1000],
+    curr_gpt_entries: usize,
+    completions: Completions,
 }
 
 impl SimpleBlockDriver {
@@ -52,39 +468,359 @@ impl SimpleBlockDriver {
         Self {
             clusters,
             curr_gpt_entries,
+            completions: Completions::new(),
         }
     }
 
-    pub fn create_efi_partition(&mut self) {
-        // create a protective MBR on cluster 0
+    pub fn n_clusters(&self) -> u64 {
+        self.clusters.len() as u64
+    }
+
+    /// Protective MBR and an empty GPT, wiping whatever partitions were there
+    pub fn create_efi_partition(&mut self) -> Result<(), GptError> {
+        let n_lbas = self.n_clusters();
+        let disk_guid = u128::from_le_bytes(generate_uuid("disk", n_lbas));
+
+        write_protective_mbr(self, n_lbas)?;
+        write_gpt(self, n_lbas, disk_guid, &[])?;
+        self.curr_gpt_entries = 0;
 
-        // create a GPT on cluster 1
+        Ok(())
     }
 
-    // RN, can only create 1 partition
-    pub fn create_nefs_partition(&mut self, size_bytes: usize) {
-        // create an entry from cluster 2 + curr_entries offset
-        // self.clusters[2] =
+    /// Add a NeFS partition of at least `size_bytes` after the last one. Needs create_efi_partition first
+    pub fn create_nefs_partition(&mut self, size_bytes: usize) -> Result<GPTEntry, GptError> {
+        let n_lbas = self.n_clusters();
+        let (header, mut entries) = read_gpt(self, n_lbas)?;
+        if entries.len() >= N_GPT_ENTRIES {
+            return Err(GptError::TableFull);
+        }
+
+        let start_lba = entries
+            .iter()
+            .map(|e| e.last_lba() + 1)
+            .max()
+            .unwrap_or(header.first_usable_lba);
+        let n_partition_lbas = core::cmp::max(
+            1,
+            SimpleBlockDriver::ceil_addr_to_cluster_number(size_bytes as u64),
+        );
+        let end_lba = start_lba + n_partition_lbas - 1;
+        if end_lba > header.last_usable_lba {
+            return Err(GptError::NoSpace);
+        }
 
-        // create an NeFS partition at cluster 34->size
-        let start_addr = 34 * 4096;
-        let end_cluster =
-            SimpleBlockDriver::ceil_addr_to_cluster_number(start_addr + size_bytes as u64);
+        let guid = u128::from_le_bytes(generate_uuid("partition", start_lba));
+        let mut entry = GPTEntry::new(NEFS_PARTITION_GUID, guid, start_lba, end_lba, 0, [0; 36]);
+        entry.set_name("NeutronFS".to_string());
 
-        // self.clusters[end_cluster] =
+        entries.push(entry);
+        write_gpt(self, n_lbas, header.disk_guid, &entries)?;
+        self.curr_gpt_entries = entries.len();
+
+        Ok(entry)
+    }
+
+    /// Every partition in the GPT
+    pub fn partitions(&mut self) -> Result<Vec<GPTEntry>, GptError> {
+        let n_lbas = self.n_clusters();
+        let (_, entries) = read_gpt(self, n_lbas)?;
+        self.curr_gpt_entries = entries.len();
+
+        Ok(entries)
     }
 
     pub fn ceil_addr_to_cluster_number(addr: u64) -> u64 {
-        addr / 4096
+        addr.div_ceil(LBA_SIZE as u64)
     }
 }
 
 impl BlockDriver for SimpleBlockDriver {
-    fn push_read_request(&mut self, buf: &mut [u8], cluster_number: u64) {
-        buf.copy_from_slice(&self.clusters[cluster_number as usize])
+    // blocks until done, so every request is complete by the time it has an id
+    fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+        let res = self
+            .clusters
+            .get(cluster_number as usize)
+            .map(|block| Box::new(*block))
+            .ok_or(IoError::OutOfRange(cluster_number));
+
+        Ok(self.completions.complete_now(Completion::Read(res)))
+    }
+
+    fn push_write_request(
+        &mut self,
+        cluster_number: u64,
+        block: Block,
+    ) -> Result<RequestId, IoError> {
+        let res = match self.clusters.get_mut(cluster_number as usize) {
+            Some(cluster) => {
+                *cluster = block;
+                Ok(())
+            }
+            None => Err(IoError::OutOfRange(cluster_number)),
+        };
+
+        Ok(self.completions.complete_now(Completion::Write(res)))
+    }
+
+    fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+        self.completions.take(id)
+    }
+
+    fn is_pending(&self, id: RequestId) -> bool {
+        self.completions.is_pending(id)
+    }
+
+    fn device_clusters(&self) -> Option<u64> {
+        Some(self.n_clusters())
+    }
+}
+
+// -------------
+// TESTS
+// -------------
+
+#[test]
+fn test_mkfs_simple_block() {
+    use neutron_fs::driver::block::make_block;
+    use neutron_fs::driver::neutronfs::{mkfs, NeFS};
+
+    // 1000 clusters is 4MB, more than a test thread's stack
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(|| {
+            let mut driver = SimpleBlockDriver::new([make_block(); 1000], 0);
+            let superblock = mkfs(&mut driver, 1000, "simple").unwrap();
+            assert_eq!(superblock.label(), "simple");
+
+            let fs = NeFS::mount(driver).unwrap();
+            assert_eq!(fs.superblock().fs_uuid(), superblock.fs_uuid());
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn test_gpt_create_and_read() {
+    use neutron_fs::driver::block::make_block;
+
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(|| {
+            let mut driver = SimpleBlockDriver::new([make_block(); 1000], 0);
+            assert_eq!(driver.partitions().unwrap_err(), GptError::NoTable);
+            assert_eq!(
+                driver.create_nefs_partition(4096).unwrap_err(),
+                GptError::NoTable
+            );
+
+            driver.create_efi_partition().unwrap();
+            assert!(driver.partitions().unwrap().is_empty());
+
+            // protective MBR
+            let mbr = read_block(&mut driver, 0).unwrap();
+            assert_eq!(mbr[446 + 4], 0xEE);
+            assert_eq!(&mbr[510..512], &[0x55, 0xAA]);
+
+            let a = driver.create_nefs_partition(100 * 4096).unwrap();
+            assert_eq!((a.first_lba(), a.last_lba()), (34, 133));
+            // rounds up to whole LBAs
+            let b = driver.create_nefs_partition(4097).unwrap();
+            assert_eq!((b.first_lba(), b.last_lba()), (134, 135));
+
+            let partitions = driver.partitions().unwrap();
+            assert_eq!(partitions.len(), 2);
+            assert_eq!(partitions[0].partition_type(), NEFS_PARTITION_GUID);
+            assert_eq!(partitions[0].get_name(), "NeutronFS");
+            assert_eq!(partitions[1].first_lba(), 134);
+
+            // last usable LBA is 1000 - 34
+            assert_eq!(
+                driver.create_nefs_partition(900 * 4096).unwrap_err(),
+                GptError::NoSpace
+            );
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn test_gpt_falls_back_to_backup() {
+    use neutron_fs::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(256);
+    write_protective_mbr(&mut disk, 256).unwrap();
+    let mut entry = GPTEntry::new(NEFS_PARTITION_GUID, 1, 34, 99, 0, [0; 36]);
+    entry.set_name("root".to_string());
+    write_gpt(&mut disk, 256, 42, &[entry]).unwrap();
+
+    let (header, entries) = read_gpt(&mut disk, 256).unwrap();
+    assert_eq!(header.my_lba, GPT_HEADER_LBA);
+    assert_eq!(header.alternate_lba, 255);
+    assert_eq!(header.disk_guid, 42);
+
+    // break the primary header, then the primary entries
+    let mut header_block = read_block(&mut disk, GPT_HEADER_LBA).unwrap();
+    header_block[40] ^= 1;
+    write_block(&mut disk, GPT_HEADER_LBA, header_block).unwrap();
+    let (header, entries_backup) = read_gpt(&mut disk, 256).unwrap();
+    assert_eq!(header.my_lba, 255);
+    assert_eq!(entries_backup[0].get_name(), "root");
+    assert_eq!(entries_backup[0].last_lba(), entries[0].last_lba());
+
+    write_gpt(&mut disk, 256, 42, &[entry]).unwrap();
+    write_block(&mut disk, GPT_ENTRIES_LBA, [1; 4096]).unwrap();
+    let (header, _) = read_gpt(&mut disk, 256).unwrap();
+    assert_eq!(header.my_lba, 255);
+
+    // both gone
+    write_block(&mut disk, 255, make_block()).unwrap();
+    assert_eq!(read_gpt(&mut disk, 256).unwrap_err(), GptError::BadEntries);
+}
+
+#[test]
+fn test_gpt_small_disk() {
+    use neutron_fs::driver::block::RamDisk;
+
+    // too small for both tables, an error and not an underflow
+    for n_lbas in [0, 1, 34, MIN_GPT_LBAS - 1] {
+        let mut disk = RamDisk::new(n_lbas as usize);
+        assert_eq!(
+            write_protective_mbr(&mut disk, n_lbas),
+            Err(GptError::DiskTooSmall)
+        );
+        assert_eq!(
+            write_gpt(&mut disk, n_lbas, 42, &[]),
+            Err(GptError::DiskTooSmall)
+        );
+        assert_eq!(
+            read_gpt(&mut disk, n_lbas).unwrap_err(),
+            GptError::DiskTooSmall
+        );
     }
 
-    fn push_write_request(&mut self, cluster_number: u64, block: Block) {
-        self.clusters[cluster_number as usize] = block
+    // just fits, with one usable LBA
+    let mut disk = RamDisk::new(MIN_GPT_LBAS as usize);
+    write_protective_mbr(&mut disk, MIN_GPT_LBAS).unwrap();
+    write_gpt(&mut disk, MIN_GPT_LBAS, 42, &[]).unwrap();
+    let (header, entries) = read_gpt(&mut disk, MIN_GPT_LBAS).unwrap();
+    assert_eq!(header.first_usable_lba, header.last_usable_lba);
+    assert!(entries.is_empty());
+}
+
+#[test]
+fn test_gpt_header_in_wrong_place() {
+    use neutron_fs::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(256);
+    write_gpt(&mut disk, 256, 42, &[]).unwrap();
+
+    // a copy of the primary at the end of the disk isnt a backup
+    let primary = read_block(&mut disk, GPT_HEADER_LBA).unwrap();
+    write_block(&mut disk, 255, primary).unwrap();
+    write_block(&mut disk, GPT_HEADER_LBA, make_block()).unwrap();
+    assert_eq!(read_gpt(&mut disk, 256).unwrap_err(), GptError::NoTable);
+
+    // and the primary is only taken at LBA 1
+    write_gpt(&mut disk, 256, 42, &[]).unwrap();
+    let backup = read_block(&mut disk, 255).unwrap();
+    write_block(&mut disk, GPT_HEADER_LBA, backup).unwrap();
+    let (header, _) = read_gpt(&mut disk, 256).unwrap();
+    assert_eq!(header.my_lba, 255);
+}
+
+#[test]
+fn test_gpt_entries_out_of_range() {
+    use neutron_fs::driver::block::RamDisk;
+
+    let mut disk = RamDisk::new(200);
+    write_gpt(&mut disk, 200, 42, &[]).unwrap();
+    let backup = GPTHeader::from_block(&read_block(&mut disk, 199).unwrap(), 200).unwrap();
+
+    // a primary whose entries are past the end falls back to the backup
+    let mut header =
+        GPTHeader::from_block(&read_block(&mut disk, GPT_HEADER_LBA).unwrap(), 200).unwrap();
+    for entries_lba in [0, 199, u64::MAX] {
+        header.entries_lba = entries_lba;
+        write_block(&mut disk, GPT_HEADER_LBA, header.to_block()).unwrap();
+        assert_eq!(GPTHeader::from_block(&header.to_block(), 200), None);
+        let (found, _) = read_gpt(&mut disk, 200).unwrap();
+        assert_eq!(found.my_lba, 199);
+    }
+
+    // entries that are on the disk but wont read fall back too
+    struct BadLba(RamDisk, u64);
+
+    impl BlockDriver for BadLba {
+        fn push_read_request(&mut self, cluster_number: u64) -> Result<RequestId, IoError> {
+            if cluster_number == self.1 {
+                return Err(IoError::Device);
+            }
+            self.0.push_read_request(cluster_number)
+        }
+
+        fn push_write_request(
+            &mut self,
+            cluster_number: u64,
+            block: Block,
+        ) -> Result<RequestId, IoError> {
+            self.0.push_write_request(cluster_number, block)
+        }
+
+        fn poll_completion(&mut self, id: RequestId) -> Option<Completion> {
+            self.0.poll_completion(id)
+        }
     }
+
+    write_gpt(&mut disk, 200, 42, &[]).unwrap();
+    let mut disk = BadLba(disk, GPT_ENTRIES_LBA);
+    let (found, _) = read_gpt(&mut disk, 200).unwrap();
+    assert_eq!(found.my_lba, 199);
+
+    // and with both copies unreadable the error is the device's
+    disk.1 = backup.entries_lba;
+    write_block(&mut disk.0, GPT_HEADER_LBA, make_block()).unwrap();
+    assert_eq!(
+        read_gpt(&mut disk, 200).unwrap_err(),
+        GptError::Io(IoError::Device)
+    );
+}
+
+#[test]
+fn test_crc32_known_value() {
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+}
+
+#[test]
+fn test_mkfs_on_gpt_partition() {
+    use neutron_fs::driver::block::{make_block, PartitionView};
+    use neutron_fs::driver::neutronfs::{mkfs, NeFS};
+
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(|| {
+            let mut driver = SimpleBlockDriver::new([make_block(); 1000], 0);
+            driver.create_efi_partition().unwrap();
+            driver.create_nefs_partition(10 * 4096).unwrap();
+            let entry = driver.create_nefs_partition(200 * 4096).unwrap();
+
+            let mut view = PartitionView::new(&mut driver, entry.first_lba(), entry.n_lbas());
+            let superblock = mkfs(&mut view, entry.n_lbas(), "partition").unwrap();
+            assert_eq!(superblock.physical_addr_of_partition(), 44 * 4096);
+
+            // the table is untouched, and the fs is found again through it
+            let partitions = driver.partitions().unwrap();
+            let nefs = partitions
+                .iter()
+                .find(|e| e.partition_type() == NEFS_PARTITION_GUID && e.n_lbas() == 200)
+                .unwrap();
+            let view = PartitionView::new(driver, nefs.first_lba(), nefs.n_lbas());
+            let fs = NeFS::mount(view).unwrap();
+            assert_eq!(fs.superblock().fs_uuid(), superblock.fs_uuid());
+        })
+        .unwrap()
+        .join()
+        .unwrap();
 }